@@ -7,18 +7,21 @@ use std::borrow::Cow;
 use std::ffi::{c_char, c_int, CStr, CString, OsStr};
 use std::fs::Metadata;
 use std::io::{self, IsTerminal, Read, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 use libbz2_rs_sys::{
     BZ2_bzRead, BZ2_bzReadClose, BZ2_bzReadGetUnused, BZ2_bzReadOpen, BZ2_bzWrite,
-    BZ2_bzWriteClose64, BZ2_bzWriteOpen, BZ2_bzlibVersion, BZFILE,
+    BZ2_bzWriteClose64, BZ2_bzWriteOpen, BZ2_bzlibVersion, ParallelCompressor,
+    ParallelDecompressor, BZFILE,
 };
 
 use libc::{
-    fclose, ferror, fflush, fgetc, fileno, fread, rewind, signal, ungetc, FILE, SIGINT, SIGTERM,
+    fclose, ferror, fflush, fgetc, fileno, fread, fwrite, rewind, signal, ungetc, FILE, SIGINT,
+    SIGTERM,
 };
 
 // FIXME remove this
@@ -77,6 +80,7 @@ enum DecompressMode {
 // NOTE: we use Ordering::SeqCst to synchronize with the signal handler
 static delete_output_on_interrupt: AtomicBool = AtomicBool::new(false);
 static exitValue: AtomicI32 = AtomicI32::new(0);
+static skipped_files: Mutex<Vec<SkipReason>> = Mutex::new(Vec::new());
 
 struct Config {
     program_name: PathBuf,
@@ -94,6 +98,7 @@ struct Config {
     keep_input_files: bool,
     op_mode: OperationMode,
     src_mode: SourceMode,
+    threads: NonZeroUsize,
 
     // compress
     blockSize100k: i32,
@@ -101,6 +106,9 @@ struct Config {
 
     // uncompress
     decompress_mode: DecompressMode,
+
+    // list
+    robot: bool,
 }
 
 impl Config {
@@ -108,7 +116,7 @@ impl Config {
         match self.op_mode {
             OperationMode::Zip => self.with_compress_input(name),
             OperationMode::Unzip => self.with_uncompress_input(name),
-            OperationMode::Test => self.with_test_input(name),
+            OperationMode::Test | OperationMode::List => self.with_test_input(name),
         }
 
         const FILE_NAME_LEN: usize = 1034;
@@ -230,6 +238,7 @@ enum OperationMode {
     Zip = 1,
     Unzip = 2,
     Test = 3,
+    List = 4,
 }
 
 /// Strictly for compatibility with the original bzip2 output
@@ -269,13 +278,16 @@ fn compressStream(
     zStream: CFile,
     metadata: Option<&Metadata>,
 ) {
+    if config.threads.get() > 1 {
+        return compressStreamParallel(config, stream, zStream, metadata);
+    }
+
     let mut ibuf: [u8; 5000] = [0; 5000];
     let mut nbytes_in_lo32: u32 = 0;
     let mut nbytes_in_hi32: u32 = 0;
     let mut nbytes_out_lo32: u32 = 0;
     let mut nbytes_out_hi32: u32 = 0;
     let mut bzerr: i32 = 0;
-    let mut ret: i32;
 
     zStream.set_binary_mode(config);
 
@@ -340,45 +352,9 @@ fn compressStream(
             break 'errhandler;
         }
 
-        if zStream.has_error() {
-            // diverges
-            ioError(config)
-        }
-        ret = zStream.flush();
-        if ret == libc::EOF {
-            // diverges
-            ioError(config)
-        }
-
-        if let Some(metadata) = metadata {
-            set_permissions(config, &zStream, metadata);
-            ret = zStream.close();
-            if ret == libc::EOF {
-                // diverges
-                ioError(config)
-            }
-        }
-
-        if config.verbosity >= 1 {
-            if nbytes_in_lo32 == 0 && nbytes_in_hi32 == 0 {
-                eprintln!(" no data compressed.");
-            } else {
-                let bytes_in = ((nbytes_in_hi32 as u64) << 32) | nbytes_in_lo32 as u64;
-                let bytes_out = ((nbytes_out_hi32 as u64) << 32) | nbytes_out_lo32 as u64;
-
-                let nbytes_in_d = bytes_in as f64;
-                let nbytes_out_d = bytes_out as f64;
-
-                eprintln!(
-                    "{:6.3}:1, {:6.3} bits/byte, {:5.2}% saved, {} in, {} out.",
-                    nbytes_in_d / nbytes_out_d,
-                    8.0 * nbytes_out_d / nbytes_in_d,
-                    100.0 * (1.0 - nbytes_out_d / nbytes_in_d),
-                    bytes_in,
-                    bytes_out,
-                );
-            }
-        }
+        let bytes_in = ((nbytes_in_hi32 as u64) << 32) | nbytes_in_lo32 as u64;
+        let bytes_out = ((nbytes_out_hi32 as u64) << 32) | nbytes_out_lo32 as u64;
+        finishCompressedStream(config, zStream, metadata, bytes_in, bytes_out);
 
         return;
     }
@@ -405,12 +381,131 @@ fn compressStream(
     }
 }
 
+/// Compresses like [`compressStream`], but compresses the blocks on `config.threads` threads.
+///
+/// The compressed data is identical, only the block details of `-vv` are not reported.
+fn compressStreamParallel(
+    config: &Config,
+    mut stream: InputStream,
+    mut zStream: CFile,
+    metadata: Option<&Metadata>,
+) {
+    zStream.set_binary_mode(config);
+
+    if zStream.has_error() {
+        // diverges
+        ioError(config)
+    }
+
+    let mut compressor = match ParallelCompressor::new(
+        config.blockSize100k as u32,
+        config.workFactor as u32,
+        config.threads,
+    ) {
+        Ok(compressor) => compressor,
+        Err(error) => compressError(config, error),
+    };
+
+    if config.verbosity >= 2 {
+        eprintln!();
+    }
+
+    let mut ibuf = vec![0u8; 1 << 16];
+    let mut obuf = Vec::new();
+    let mut bytes_out = 0;
+    loop {
+        let nIbuf = match stream.read(&mut ibuf) {
+            Ok(0) => break, // EOF
+            Ok(n) => n,
+            Err(e) => exit_with_io_error(config, e),
+        };
+
+        if let Err(error) = compressor.compress(&ibuf[..nIbuf], &mut obuf) {
+            compressError(config, error) // diverges
+        }
+
+        if let Err(e) = zStream.write_all(&obuf) {
+            exit_with_io_error(config, e) // diverges
+        }
+        bytes_out += obuf.len() as u64;
+        obuf.clear();
+    }
+
+    let bytes_in = compressor.total_in();
+    if let Err(error) = compressor.finish(&mut obuf) {
+        compressError(config, error) // diverges
+    }
+    if let Err(e) = zStream.write_all(&obuf) {
+        exit_with_io_error(config, e) // diverges
+    }
+    bytes_out += obuf.len() as u64;
+
+    finishCompressedStream(config, zStream, metadata, bytes_in, bytes_out);
+}
+
+fn compressError(config: &Config, error: libbz2_rs_sys::Error) -> ! {
+    match error {
+        libbz2_rs_sys::Error::Config => configError(),
+        libbz2_rs_sys::Error::Mem => outOfMemory(config),
+        _ => panic_str(config, "compress:unexpected error"),
+    }
+}
+
+/// Flushes and closes the output of a successful compression, and reports the statistics.
+fn finishCompressedStream(
+    config: &Config,
+    zStream: CFile,
+    metadata: Option<&Metadata>,
+    bytes_in: u64,
+    bytes_out: u64,
+) {
+    if zStream.has_error() {
+        // diverges
+        ioError(config)
+    }
+    if zStream.flush() == libc::EOF {
+        // diverges
+        ioError(config)
+    }
+
+    if let Some(metadata) = metadata {
+        set_permissions(config, &zStream, metadata);
+        if zStream.close() == libc::EOF {
+            // diverges
+            ioError(config)
+        }
+    }
+
+    if config.verbosity >= 1 {
+        if bytes_in == 0 {
+            eprintln!(" no data compressed.");
+        } else {
+            let nbytes_in_d = bytes_in as f64;
+            let nbytes_out_d = bytes_out as f64;
+
+            eprintln!(
+                "{:6.3}:1, {:6.3} bits/byte, {:5.2}% saved, {} in, {} out.",
+                nbytes_in_d / nbytes_out_d,
+                8.0 * nbytes_out_d / nbytes_in_d,
+                100.0 * (1.0 - nbytes_out_d / nbytes_in_d),
+                bytes_in,
+                bytes_out,
+            );
+        }
+    }
+}
+
 fn uncompressStream(
     config: &Config,
     mut zStream: CFile,
     mut stream: OutputStream,
     metadata: Option<&Metadata>,
 ) -> bool {
+    // the small mode is about memory use, and parallel decompression holds a few blocks per thread
+    if config.threads.get() > 1 && config.decompress_mode == DecompressMode::Fast {
+        return uncompressStreamParallel(config, zStream, stream, metadata);
+    }
+
     let mut bzf = std::ptr::null_mut();
     let mut bzerr: i32 = 0;
     let mut bzerr_dummy: i32 = 0;
@@ -598,6 +693,99 @@ fn uncompressStream(
     }
 }
 
+/// Decompresses like [`uncompressStream`], but decodes the blocks on `config.threads` threads.
+///
+/// Block boundaries are only found by scanning the compressed data, so the compressed data of the
+/// next few blocks per thread is read ahead.
+fn uncompressStreamParallel(
+    config: &Config,
+    mut zStream: CFile,
+    mut stream: OutputStream,
+    metadata: Option<&Metadata>,
+) -> bool {
+    zStream.set_binary_mode(config);
+
+    if zStream.has_error() {
+        // diverges
+        ioError(config)
+    }
+
+    let mut input = CountingReader {
+        inner: &mut zStream,
+        count: 0,
+    };
+
+    let decompressor = ParallelDecompressor::new(config.threads);
+    match decompressor.decompress_from(&mut input, &mut stream) {
+        Ok(len) => {
+            // the input is only read past the streams if something follows them
+            if len < input.count && config.noisy {
+                eprintln!(
+                    "\n{}: {}: trailing garbage after EOF ignored",
+                    config.program_name.display(),
+                    config.input.display(),
+                );
+            }
+        }
+        Err(error) => {
+            let inner = error
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<libbz2_rs_sys::Error>());
+            match inner {
+                None => exit_with_io_error(config, error),
+                Some(libbz2_rs_sys::Error::Data) => crcError(config),
+                Some(libbz2_rs_sys::Error::Mem) => outOfMemory(config),
+                Some(libbz2_rs_sys::Error::UnexpectedEof) => compressedStreamEOF(config),
+                Some(libbz2_rs_sys::Error::DataMagic) if config.force_overwrite => {
+                    zStream.rewind();
+                    if let Err(e) = io::copy(&mut zStream, &mut stream) {
+                        exit_with_io_error(config, e) // diverges
+                    }
+                }
+                Some(libbz2_rs_sys::Error::DataMagic) => {
+                    zStream.close();
+                    return false;
+                }
+                Some(_) => panic_str(config, "decompress:unexpected error"),
+            }
+        }
+    }
+
+    if let Some(metadata) = metadata {
+        if let OutputStream::File(file) = &stream {
+            set_permissions_rust(config, file, metadata);
+        }
+    }
+
+    if let libc::EOF = zStream.close() {
+        ioError(config)
+    }
+
+    if let Err(e) = stream.flush() {
+        exit_with_io_error(config, e) // diverges
+    }
+
+    if config.verbosity >= 2 {
+        eprint!("\n    ");
+    }
+
+    true
+}
+
+/// Counts the bytes that are read from `inner`.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
 fn testStream(config: &Config, zStream: CFile) -> bool {
     let mut bzf: *mut BZFILE;
     let mut bzerr: i32 = 0;
@@ -730,6 +918,66 @@ fn setExit(v: i32) {
     exitValue.fetch_max(v, Ordering::SeqCst);
 }
 
+/// Why an input file was passed over, for the summary of `--recursive`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SkipReason {
+    AlreadyCompressed,
+    UnknownSuffix,
+    SymbolicLink,
+    NotNormalFile,
+    OutputExists,
+    HardLinks,
+    InvalidName,
+    UnreadableDirectory,
+}
+
+impl SkipReason {
+    fn describe(self) -> &'static str {
+        match self {
+            SkipReason::AlreadyCompressed => "already compressed",
+            SkipReason::UnknownSuffix => "no bzip2 suffix",
+            SkipReason::SymbolicLink => "is a symbolic link",
+            SkipReason::NotNormalFile => "is not a normal file",
+            SkipReason::OutputExists => "output file already exists",
+            SkipReason::HardLinks => "has other links",
+            SkipReason::InvalidName => "name is not valid UTF-8",
+            SkipReason::UnreadableDirectory => "directory could not be read",
+        }
+    }
+
+    /// The reason for a file that fails `not_a_standard_file`.
+    fn not_standard(path: &Path) -> Self {
+        if path.is_symlink() {
+            SkipReason::SymbolicLink
+        } else {
+            SkipReason::NotNormalFile
+        }
+    }
+}
+
+fn skip(reason: SkipReason) {
+    setExit(1);
+    skipped_files.lock().unwrap().push(reason);
+}
+
+fn print_skip_summary(program_name: &Path) {
+    let mut skipped = skipped_files.lock().unwrap();
+    if skipped.is_empty() {
+        return;
+    }
+    skipped.sort_unstable();
+
+    eprintln!(
+        "{}: skipped {} file{}:",
+        program_name.display(),
+        skipped.len(),
+        if skipped.len() > 1 { "s" } else { "" },
+    );
+    for reason in skipped.chunk_by(|a, b| a == b) {
+        eprintln!("  {:>6}  {}", reason.len(), reason[0].describe());
+    }
+}
+
 fn cadvise(config: &Config) {
     if config.noisy {
         eprint!(concat!(
@@ -1176,6 +1424,26 @@ impl CFile {
     }
 }
 
+impl Write for CFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let nwritten =
+            unsafe { fwrite(buf.as_ptr() as *const libc::c_void, 1, buf.len(), self.file) };
+        if self.has_error() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(nwritten)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if unsafe { fflush(self.file) } == libc::EOF {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl Read for CFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let nread = unsafe {
@@ -1315,7 +1583,7 @@ fn compress(config: &Config) {
                         &bz2_extension[1..],
                     );
                 }
-                setExit(1);
+                skip(SkipReason::AlreadyCompressed);
                 return;
             }
         }
@@ -1343,7 +1611,7 @@ fn compress(config: &Config) {
                 config.input.display(),
             );
         }
-        setExit(1);
+        skip(SkipReason::not_standard(&config.input));
         return;
     }
     if config.src_mode == SourceMode::F2F && config.output.exists() {
@@ -1355,7 +1623,7 @@ fn compress(config: &Config) {
                 config.program_name.display(),
                 config.output.display(),
             );
-            setExit(1);
+            skip(SkipReason::OutputExists);
             return;
         }
     }
@@ -1371,7 +1639,7 @@ fn compress(config: &Config) {
                     n,
                     if n > 1 { "s" } else { "" },
                 );
-                setExit(1);
+                skip(SkipReason::HardLinks);
                 return;
             }
         }
@@ -1567,7 +1835,7 @@ fn uncompress(config: &Config) -> bool {
                 config.input.display(),
             );
         }
-        setExit(1);
+        skip(SkipReason::not_standard(&config.input));
         return true;
     }
 
@@ -1590,7 +1858,7 @@ fn uncompress(config: &Config) -> bool {
                 config.program_name.display(),
                 config.output.display(),
             );
-            setExit(1);
+            skip(SkipReason::OutputExists);
             return true;
         }
     }
@@ -1606,7 +1874,7 @@ fn uncompress(config: &Config) -> bool {
                     n,
                     if n > 1 { "s" } else { "" },
                 );
-                setExit(1);
+                skip(SkipReason::HardLinks);
                 return true;
             }
         }
@@ -1820,6 +2088,226 @@ fn testf(config: &Config) -> bool {
     allOK
 }
 
+/// The statistics that `--list` reports for a single file.
+struct ListInfo {
+    streams: u64,
+    blocks: u64,
+    compressed: u64,
+    uncompressed: u64,
+    /// The distinct block size settings of the streams, in order of appearance.
+    block_sizes: Vec<u8>,
+}
+
+impl ListInfo {
+    /// Gathers the statistics of the compressed data in `reader`, in a single pass that also
+    /// decompresses and verifies the data.
+    fn read<R: Read>(reader: R) -> io::Result<Self> {
+        let mut input = CountingReader {
+            inner: reader,
+            count: 0,
+        };
+        let streams = libbz2_rs_sys::inspect::inspect_verified(&mut input)?;
+
+        // the trailing garbage after the last stream counts towards the compressed size
+        io::copy(&mut input, &mut io::sink())?;
+
+        let mut block_sizes = Vec::new();
+        for stream in &streams {
+            if !block_sizes.contains(&stream.block_size_100k) {
+                block_sizes.push(stream.block_size_100k);
+            }
+        }
+
+        Ok(Self {
+            streams: streams.len() as u64,
+            blocks: streams.iter().map(|s| s.blocks.len() as u64).sum(),
+            compressed: input.count,
+            uncompressed: streams.iter().filter_map(|s| s.uncompressed_len).sum(),
+            block_sizes,
+        })
+    }
+
+    fn print(&self, name: &Path, robot: bool) {
+        let ratio = list_ratio(self.uncompressed, self.compressed);
+        if robot {
+            let block_sizes: Vec<_> = self.block_sizes.iter().map(u8::to_string).collect();
+            println!(
+                "file\t{}\t{}\t{}\t{}\t{:.3}\t{}\t{}",
+                self.streams,
+                self.blocks,
+                self.compressed,
+                self.uncompressed,
+                ratio,
+                block_sizes.join(","),
+                name.display(),
+            );
+        } else {
+            let block_sizes: Vec<_> = self
+                .block_sizes
+                .iter()
+                .map(|size| format!("{}k", u32::from(*size) * 100))
+                .collect();
+            println!(
+                "{:>7} {:>7} {:>14} {:>14} {:>8} {:>10}  {}",
+                self.streams,
+                self.blocks,
+                self.compressed,
+                self.uncompressed,
+                format!("{ratio:.3}"),
+                block_sizes.join(","),
+                name.display(),
+            );
+        }
+    }
+}
+
+/// The sums over all files that were listed successfully.
+#[derive(Default)]
+struct ListTotals {
+    files: u64,
+    streams: u64,
+    blocks: u64,
+    compressed: u64,
+    uncompressed: u64,
+}
+
+impl ListTotals {
+    fn add(&mut self, info: Option<ListInfo>) {
+        if let Some(info) = info {
+            self.files += 1;
+            self.streams += info.streams;
+            self.blocks += info.blocks;
+            self.compressed += info.compressed;
+            self.uncompressed += info.uncompressed;
+        }
+    }
+
+    fn print(&self, robot: bool) {
+        let ratio = list_ratio(self.uncompressed, self.compressed);
+        if robot {
+            println!(
+                "totals\t{}\t{}\t{}\t{}\t{:.3}\t{}",
+                self.streams, self.blocks, self.compressed, self.uncompressed, ratio, self.files,
+            );
+        } else {
+            println!(
+                "{:>7} {:>7} {:>14} {:>14} {:>8} {:>10}  ({} files)",
+                self.streams,
+                self.blocks,
+                self.compressed,
+                self.uncompressed,
+                format!("{ratio:.3}"),
+                "",
+                self.files,
+            );
+        }
+    }
+}
+
+/// The compression ratio as reported by `--list`, i.e. the expansion factor on decompression.
+fn list_ratio(uncompressed: u64, compressed: u64) -> f64 {
+    if compressed == 0 {
+        0.0
+    } else {
+        uncompressed as f64 / compressed as f64
+    }
+}
+
+fn print_list_header() {
+    println!(
+        "{:>7} {:>7} {:>14} {:>14} {:>8} {:>10}  Filename",
+        "Streams", "Blocks", "Compressed", "Uncompressed", "Ratio", "Block size",
+    );
+}
+
+fn list_error_message(error: io::Error) -> String {
+    let inner = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<libbz2_rs_sys::Error>());
+    match inner {
+        Some(libbz2_rs_sys::Error::DataMagic) => {
+            String::from("bad magic number (file not created by bzip2)")
+        }
+        Some(libbz2_rs_sys::Error::Data) => String::from("data integrity (CRC) error in data"),
+        Some(libbz2_rs_sys::Error::UnexpectedEof) => String::from("file ends unexpectedly"),
+        Some(libbz2_rs_sys::Error::Mem) => String::from("couldn't allocate enough memory"),
+        _ => display_os_error(error),
+    }
+}
+
+fn listf(config: &Config) -> Option<ListInfo> {
+    if config.src_mode != SourceMode::I2O && contains_dubious_chars_safe(&config.input) {
+        if config.noisy {
+            eprintln!(
+                "{}: There are no files matching `{}'.",
+                config.program_name.display(),
+                config.input.display(),
+            );
+        }
+        setExit(1);
+        return None;
+    }
+    if config.src_mode != SourceMode::I2O && !config.input.exists() {
+        eprintln!(
+            "{}: Can't open input {}: {}.",
+            config.program_name.display(),
+            config.input.display(),
+            display_last_os_error(),
+        );
+        setExit(1);
+        return None;
+    }
+    if config.src_mode != SourceMode::I2O && config.input.is_dir() {
+        eprintln!(
+            "{}: Input file {} is a directory.",
+            config.program_name.display(),
+            config.input.display(),
+        );
+        setExit(1);
+        return None;
+    }
+
+    let result = match config.src_mode {
+        SourceMode::I2O => {
+            if std::io::stdin().is_terminal() {
+                eprintln!(
+                    "{}: I won't read compressed data from a terminal.",
+                    config.program_name.display(),
+                );
+                eprintln!(
+                    "{}: For help, type: `{} --help'.",
+                    config.program_name.display(),
+                    config.program_name.display(),
+                );
+                setExit(1);
+                return None;
+            }
+
+            ListInfo::read(std::io::stdin().lock())
+        }
+        SourceMode::F2O | SourceMode::F2F => {
+            std::fs::File::open(&config.input).and_then(ListInfo::read)
+        }
+    };
+
+    match result {
+        Ok(info) => {
+            info.print(&config.input, config.robot);
+            Some(info)
+        }
+        Err(error) => {
+            eprintln!(
+                "{}: {}: {}",
+                config.program_name.display(),
+                config.input.display(),
+                list_error_message(error),
+            );
+            setExit(2);
+            None
+        }
+    }
+}
+
 const BZLIB_VERSION: &str = unsafe {
     match CStr::from_ptr(BZ2_bzlibVersion()).to_str() {
         Ok(s) => s,
@@ -1859,8 +2347,10 @@ fn usage(full_program_name: &Path) {
             "   -d --decompress     force decompression\n",
             "   -z --compress       force compression\n",
             "   -k --keep           keep (don't delete) input files\n",
+            "   -r --recursive      operate on the files in directories, recursively\n",
             "   -f --force          overwrite existing output files\n",
             "   -t --test           test compressed file integrity\n",
+            "   -l --list           list information about compressed files\n",
             "   -c --stdout         output to standard out\n",
             "   -q --quiet          suppress noncritical error messages\n",
             "   -v --verbose        be verbose (a 2nd -v gives more)\n",
@@ -1870,6 +2360,8 @@ fn usage(full_program_name: &Path) {
             "   -1 .. -9            set block size to 100k .. 900k\n",
             "   --fast              alias for -1\n",
             "   --best              alias for -9\n",
+            "   -TN --threads=N     use N threads (-T0 uses one per CPU)\n",
+            "   --robot             print the --list output in a machine-readable format\n",
             "\n",
             "   If invoked as `bzip2', default action is to compress.\n",
             "              as `bunzip2',  default action is to decompress.\n",
@@ -1885,6 +2377,151 @@ fn usage(full_program_name: &Path) {
     );
 }
 
+/// Returns the number of file names in `arg_list`, and the length of the longest one.
+fn count_files(arg_list: &[String]) -> (u32, usize) {
+    let mut num_files_total = 0;
+    let mut longest_filename = 7;
+    let mut decode = true;
+
+    for name in arg_list {
+        if name == "--" {
+            decode = false;
+        } else if !(name.starts_with('-') && decode) {
+            num_files_total += 1;
+            longest_filename = Ord::max(longest_filename, name.len());
+        }
+    }
+
+    (num_files_total, longest_filename)
+}
+
+/// Replaces the directories among the file names in `arg_list` by the files below them.
+fn expand_directories(walk: &Walk, arg_list: Vec<String>) -> Vec<String> {
+    let mut expanded = Vec::with_capacity(arg_list.len());
+    let mut decode = true;
+
+    for name in arg_list {
+        if name == "--" {
+            decode = false;
+        } else if !(name.starts_with('-') && decode) {
+            // a symbolic link to a directory is not followed, it is refused like any other
+            if Path::new(&name)
+                .symlink_metadata()
+                .is_ok_and(|m| m.is_dir())
+            {
+                walk.directory(Path::new(&name), &mut expanded);
+                continue;
+            }
+        }
+        expanded.push(name);
+    }
+
+    expanded
+}
+
+/// The settings that decide which files of a directory are operated on.
+struct Walk<'a> {
+    program_name: &'a Path,
+    op_mode: OperationMode,
+    noisy: bool,
+    force_overwrite: bool,
+}
+
+impl Walk<'_> {
+    /// Appends the files below `dir` to `files`, in sorted order.
+    fn directory(&self, dir: &Path, files: &mut Vec<String>) {
+        let entries = std::fs::read_dir(dir).and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()
+        });
+        let mut paths = match entries {
+            Ok(paths) => paths,
+            Err(error) => {
+                eprintln!(
+                    "{}: Can't read directory {}: {}.",
+                    self.program_name.display(),
+                    dir.display(),
+                    display_os_error(error),
+                );
+                skip(SkipReason::UnreadableDirectory);
+                return;
+            }
+        };
+        paths.sort();
+
+        for path in paths {
+            if let Some(reason) = self.skip_reason(&path) {
+                skip(reason);
+            } else if path.is_dir() {
+                self.directory(&path, files);
+            } else {
+                match path.into_os_string().into_string() {
+                    Ok(name) => files.push(name),
+                    Err(name) => {
+                        if self.noisy {
+                            eprintln!(
+                                "{}: Input file {} has a name that is not valid UTF-8.",
+                                self.program_name.display(),
+                                Path::new(&name).display(),
+                            );
+                        }
+                        skip(SkipReason::InvalidName);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decides whether `path` is passed over before it is operated on, and reports why.
+    fn skip_reason(&self, path: &Path) -> Option<SkipReason> {
+        // links to directories are never followed, so that the walk can't loop. Links to files
+        // are refused like they are on the command line, unless forced.
+        if path.is_symlink() && (path.is_dir() || !self.force_overwrite) {
+            if self.noisy {
+                eprintln!(
+                    "{}: Input file {} is not a normal file.",
+                    self.program_name.display(),
+                    path.display(),
+                );
+            }
+            return Some(SkipReason::SymbolicLink);
+        }
+
+        // only files with a known suffix are decompressed, instead of guessing an output name
+        let has_suffix = Z_SUFFIX.iter().any(|suffix| {
+            path.as_os_str()
+                .as_encoded_bytes()
+                .ends_with(suffix.as_bytes())
+        });
+        if self.op_mode != OperationMode::Zip && !has_suffix && !path.is_dir() {
+            if self.noisy {
+                eprintln!(
+                    "{}: Input file {} has no bzip2 suffix -- ignored.",
+                    self.program_name.display(),
+                    path.display(),
+                );
+            }
+            return Some(SkipReason::UnknownSuffix);
+        }
+
+        None
+    }
+}
+
+/// Parses the value of `-T` or `--threads`, where 0 picks a thread per available CPU.
+fn parse_threads(program_name: &Path, flag_name: &str, value: &str) -> NonZeroUsize {
+    match value.parse::<usize>() {
+        Ok(0) => std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+        Ok(n) => NonZeroUsize::new(n).unwrap(),
+        Err(_) => {
+            eprintln!("{}: Bad flag `{}'", program_name.display(), flag_name);
+            usage(program_name);
+            exit(1);
+        }
+    }
+}
+
 fn redundant(program_name: &Path, flag_name: &str) {
     eprintln!(
         "{}: {} is redundant in versions 0.9.5 and above",
@@ -1913,6 +2550,8 @@ fn main() {
     let mut verbosity = 0;
     let mut force_overwrite = false;
     let mut keep_input_files = false;
+    let mut threads = NonZeroUsize::MIN;
+    let mut recursive = false;
 
     // compress config
     let mut blockSize100k = 9;
@@ -1921,6 +2560,9 @@ fn main() {
     // uncompress config
     let mut decompress_mode = DecompressMode::Fast;
 
+    // list config
+    let mut robot = false;
+
     let mut arg_list = Vec::with_capacity(16);
 
     if let Ok(val) = std::env::var("BZIP2") {
@@ -1933,18 +2575,8 @@ fn main() {
 
     arg_list.extend(std::env::args().skip(1));
 
-    let mut longest_filename = 7;
-    let mut num_files_total = 0;
-    let mut decode = true;
-
-    for name in &arg_list {
-        if name == "--" {
-            decode = false;
-        } else if !(name.starts_with('-') && decode) {
-            num_files_total += 1;
-            longest_filename = Ord::max(longest_filename, name.len());
-        }
-    }
+    let (mut num_files_total, mut longest_filename) = count_files(&arg_list);
+    let mut decode;
 
     let mut src_mode = match num_files_total {
         0 => SourceMode::I2O,
@@ -1973,14 +2605,16 @@ fn main() {
 
         // only `-h`, not `--help`
         if flag_name.as_bytes()[0] == b'-' && flag_name.as_bytes()[1] != b'-' {
-            for c in &flag_name.as_bytes()[1..] {
+            for (i, c) in flag_name.as_bytes()[1..].iter().enumerate() {
                 match c {
                     b'c' => src_mode = SourceMode::F2O,
                     b'd' => op_mode = OperationMode::Unzip,
                     b'z' => op_mode = OperationMode::Zip,
                     b'f' => force_overwrite = true,
                     b't' => op_mode = OperationMode::Test,
+                    b'l' => op_mode = OperationMode::List,
                     b'k' => keep_input_files = true,
+                    b'r' => recursive = true,
                     b's' => decompress_mode = DecompressMode::Small,
                     b'q' => noisy = false,
                     b'1' => blockSize100k = 1,
@@ -1997,6 +2631,11 @@ fn main() {
                         exit(0);
                     }
                     b'v' => verbosity += 1,
+                    b'T' => {
+                        // the thread count is the rest of the flag, e.g. `-T4` or `-9T4`
+                        threads = parse_threads(program_name, flag_name, &flag_name[i + 2..]);
+                        break;
+                    }
                     b'h' => {
                         usage(program_name);
                         exit(0);
@@ -2019,7 +2658,10 @@ fn main() {
             "--compress" => op_mode = OperationMode::Zip,
             "--force" => force_overwrite = true,
             "--test" => op_mode = OperationMode::Test,
+            "--list" => op_mode = OperationMode::List,
+            "--robot" => robot = true,
             "--keep" => keep_input_files = true,
+            "--recursive" => recursive = true,
             "--small" => decompress_mode = DecompressMode::Small,
             "--quiet" => noisy = false,
             "--version" | "--license" => {
@@ -2036,6 +2678,9 @@ fn main() {
                 usage(program_name);
                 exit(0);
             }
+            _ if flag_name.starts_with("--threads=") => {
+                threads = parse_threads(program_name, flag_name, &flag_name["--threads=".len()..]);
+            }
             _ => {
                 if flag_name.starts_with("--") {
                     eprintln!("{}: Bad flag `{}'", program_name.display(), flag_name);
@@ -2063,6 +2708,13 @@ fn main() {
         );
         exit(1);
     }
+    if op_mode == OperationMode::List && src_mode == SourceMode::F2O {
+        eprintln!(
+            "{}: -c and -l cannot be used together.",
+            program_name.display(),
+        );
+        exit(1);
+    }
     if src_mode == SourceMode::F2O && num_files_total == 0 {
         src_mode = SourceMode::I2O;
     }
@@ -2070,6 +2722,17 @@ fn main() {
         blockSize100k = 0;
     }
 
+    if recursive && src_mode != SourceMode::I2O {
+        let walk = Walk {
+            program_name,
+            op_mode,
+            noisy,
+            force_overwrite,
+        };
+        arg_list = expand_directories(&walk, arg_list);
+        (num_files_total, longest_filename) = count_files(&arg_list);
+    }
+
     let arg_list = &arg_list;
 
     let config = Arc::new(RwLock::new(Config {
@@ -2088,6 +2751,7 @@ fn main() {
         keep_input_files,
         op_mode,
         src_mode,
+        threads,
 
         // compress
         blockSize100k,
@@ -2095,6 +2759,9 @@ fn main() {
 
         // uncompress
         decompress_mode,
+
+        // list
+        robot,
     }));
 
     if src_mode == SourceMode::F2F {
@@ -2144,7 +2811,6 @@ fn main() {
             }
             if !all_ok {
                 setExit(2);
-                exit(exitValue.load(Ordering::SeqCst));
             }
         }
         OperationMode::Test => {
@@ -2177,9 +2843,40 @@ fn main() {
                     ));
                 }
                 setExit(2);
-                exit(exitValue.load(Ordering::SeqCst));
             }
         }
+        OperationMode::List => {
+            let mut totals = ListTotals::default();
+            if !robot {
+                print_list_header();
+            }
+            if src_mode == SourceMode::I2O {
+                config.write().unwrap().with_input(None);
+                totals.add(listf(&config.read().unwrap()));
+            } else {
+                decode = true;
+                for name in arg_list {
+                    if name == "--" {
+                        decode = false;
+                    } else if !(name.starts_with('-') && decode) {
+                        {
+                            let mut config = config.write().unwrap();
+                            config.num_files_processed += 1;
+                            config.with_input(Some(name.as_str()));
+                        }
+                        totals.add(listf(&config.read().unwrap()));
+                    }
+                }
+            }
+
+            if num_files_total > 1 {
+                totals.print(robot);
+            }
+        }
+    }
+
+    if recursive && noisy {
+        print_skip_summary(program_name);
     }
 
     exit(exitValue.load(Ordering::SeqCst))
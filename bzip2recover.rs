@@ -1,11 +1,13 @@
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libbz2_rs_sys::{DecompressMode, Decompressor, Status};
 
-const BZ_MAX_HANDLED_BLOCKS: usize = 50000;
 const BZ_MAX_FILENAME: usize = 2000;
 
 const BLOCK_HEADER_HI: u32 = 0x00003141u32;
@@ -14,22 +16,27 @@ const BLOCK_HEADER_LO: u32 = 0x59265359u32;
 const BLOCK_ENDMARK_HI: u32 = 0x00001772u32;
 const BLOCK_ENDMARK_LO: u32 = 0x45385090u32;
 
+/// The first bit of the block in a recovered stream, after the stream header and the block magic.
+const BLOCK_OFFSET: u64 = 32 + 48;
+
+/// The two bits flipped by `--repair=2` are at most this far apart.
+const REPAIR_PAIR_DISTANCE: u64 = 16;
+
 enum Error {
     Reading(std::io::Error),
     Writing(std::io::Error),
-    TooManyBlocks(usize),
     Fatal,
 }
 
-struct BitStream {
-    handle: File,
+struct BitStream<T> {
+    handle: T,
     buffer: i32,
     buff_live: i32,
     mode: u8,
 }
 
-impl BitStream {
-    fn open_read_stream(stream: File) -> Self {
+impl<T> BitStream<T> {
+    fn open_read_stream(stream: T) -> Self {
         Self {
             handle: stream,
             buffer: 0,
@@ -38,7 +45,7 @@ impl BitStream {
         }
     }
 
-    fn open_write_stream(stream: File) -> Self {
+    fn open_write_stream(stream: T) -> Self {
         Self {
             handle: stream,
             buffer: 0,
@@ -46,8 +53,11 @@ impl BitStream {
             mode: b'w',
         }
     }
+}
 
-    fn close(mut self) -> Result<(), Error> {
+impl<T: Write> BitStream<T> {
+    /// Pads and flushes the output, and gives back the underlying handle.
+    fn close(mut self) -> Result<T, Error> {
         if self.mode == b'w' {
             while self.buff_live < 8 {
                 self.buff_live += 1;
@@ -59,9 +69,11 @@ impl BitStream {
             self.handle.flush().map_err(Error::Writing)?;
         }
 
-        Ok(())
+        Ok(self.handle)
     }
+}
 
+impl<T: Read> BitStream<T> {
     fn get_bit(&mut self) -> Result<Option<bool>, Error> {
         if self.buff_live > 0 {
             self.buff_live -= 1;
@@ -82,7 +94,9 @@ impl BitStream {
             Ok(Some((self.buffer >> 7) & 0x1 != 0))
         }
     }
+}
 
+impl<T: Write> BitStream<T> {
     fn put_bit(&mut self, bit: i32) -> Result<(), Error> {
         if self.buff_live == 8 {
             self.handle
@@ -113,6 +127,179 @@ impl BitStream {
 
         Ok(())
     }
+
+    /// Copies `len` bits of `src`, starting at bit `start`.
+    fn put_bits(&mut self, src: &[u8], start: u64, len: u64) -> Result<(), Error> {
+        for pos in start..start + len {
+            let byte = src[(pos / 8) as usize];
+            self.put_bit(((byte >> (7 - pos % 8)) & 0x1) as i32)?;
+        }
+
+        Ok(())
+    }
+
+    fn put_stream_header(&mut self) -> Result<(), Error> {
+        // the block size isn't known, so use the largest one
+        self.put_u8(0x42)?;
+        self.put_u8(0x5a)?;
+        self.put_u8(0x68)?;
+        self.put_u8(0x30 + 9)?;
+
+        Ok(())
+    }
+
+    fn put_stream_trailer(&mut self, combined_crc: u32) -> Result<(), Error> {
+        self.put_u8(0x17)?;
+        self.put_u8(0x72)?;
+        self.put_u8(0x45)?;
+        self.put_u8(0x38)?;
+        self.put_u8(0x50)?;
+        self.put_u8(0x90)?;
+        self.put_u32(combined_crc)?;
+
+        Ok(())
+    }
+}
+
+/// Checks that `stream`, a recovered stream of a single block, decodes and matches its CRC.
+///
+/// The header of a recovered stream claims the largest block size, so the decoder is given the
+/// block size of the original stream instead. That keeps its buffers, which are reused from one
+/// call to the next, no larger than needed.
+fn verify_stream(decompressor: &mut Decompressor, stream: &[u8], block_size: u8) -> bool {
+    decompressor.reset();
+
+    let header = [b'B', b'Z', b'h', b'0' + block_size];
+    let mut output = [0u8; 16 * 1024];
+
+    for mut input in [&header[..], &stream[4..]] {
+        loop {
+            let Ok(progress) = decompressor.decompress(input, &mut output) else {
+                return false;
+            };
+
+            if progress.status == Status::StreamEnd {
+                return true;
+            }
+
+            if progress.consumed == 0 && progress.produced == 0 {
+                break;
+            }
+
+            input = &input[progress.consumed..];
+        }
+    }
+
+    // the stream is truncated
+    false
+}
+
+/// The bits, relative to the start of the block, that are flipped by repair candidate `index`.
+///
+/// All single bits come first, followed by the pairs of nearby bits.
+fn candidate_flips(index: u64, block_len: u64) -> Option<Vec<u64>> {
+    if index < block_len {
+        return Some(vec![index]);
+    }
+
+    let index = index - block_len;
+    let first = index / REPAIR_PAIR_DISTANCE;
+    let second = first + 1 + index % REPAIR_PAIR_DISTANCE;
+
+    (second < block_len).then(|| vec![first, second])
+}
+
+/// Flips bits of the block in a recovered stream.
+///
+/// The stream trailer repeats the block CRC, so a flip in the stored CRC is mirrored there.
+fn flip_bits(stream: &mut [u8], block_len: u64, flips: &[u64]) {
+    let mut flip = |pos: u64| stream[(pos / 8) as usize] ^= 0x80 >> (pos % 8);
+
+    for &offset in flips {
+        flip(BLOCK_OFFSET + offset);
+        if offset < 32 {
+            flip(BLOCK_OFFSET + block_len + 48 + offset);
+        }
+    }
+}
+
+/// Searches for one or two flipped bits that make a damaged block decode and match its CRC.
+///
+/// On success the stream is repaired in place, and the flipped bits are returned. When more than
+/// one candidate works, the first one in the order of [`candidate_flips`] is used.
+fn repair_stream(
+    stream: &mut [u8],
+    block_len: u64,
+    block_size: u8,
+    max_flips: u32,
+) -> Option<Vec<u64>> {
+    const CHUNK: u64 = 64;
+
+    let candidates = match max_flips {
+        1 => block_len,
+        _ => block_len * (1 + REPAIR_PAIR_DISTANCE),
+    };
+
+    let next = AtomicU64::new(0);
+    let found = AtomicU64::new(u64::MAX);
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    let damaged: &[u8] = stream;
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                let mut candidate = damaged.to_vec();
+                let Ok(mut decompressor) = Decompressor::new(DecompressMode::Fast) else {
+                    return;
+                };
+
+                // chunks are handed out in order, so every candidate before a success is tried
+                loop {
+                    let start = next.fetch_add(CHUNK, Ordering::Relaxed);
+                    if start >= candidates || start > found.load(Ordering::Relaxed) {
+                        break;
+                    }
+
+                    for index in start..Ord::min(start + CHUNK, candidates) {
+                        let Some(flips) = candidate_flips(index, block_len) else {
+                            continue;
+                        };
+
+                        flip_bits(&mut candidate, block_len, &flips);
+                        let good = verify_stream(&mut decompressor, &candidate, block_size);
+                        flip_bits(&mut candidate, block_len, &flips);
+
+                        if good {
+                            found.fetch_min(index, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let index = found.into_inner();
+    if index == u64::MAX {
+        return None;
+    }
+
+    let flips = candidate_flips(index, block_len)?;
+    flip_bits(stream, block_len, &flips);
+
+    Some(flips)
+}
+
+/// Creates a file for recovered data, which must not exist yet.
+fn create_output_file(path: &Path) -> std::io::Result<File> {
+    let mut options = std::fs::File::options();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_cast)]
+    options.mode(libc::S_IWUSR as u32 | libc::S_IRUSR as u32);
+
+    options.open(path)
 }
 
 struct EmitError<'a> {
@@ -160,38 +347,17 @@ impl core::fmt::Display for EmitError<'_> {
 
                 Ok(())
             }
-            Error::TooManyBlocks(max_handled_blocks) => {
-                let program_name = self.program_name.display();
-
-                writeln!(
-                    f,
-                    "{}: `{}' appears to contain more than {max_handled_blocks} blocks",
-                    program_name,
-                    self.in_filename.display(),
-                )?;
-
-                writeln!(
-                    f,
-                    "{program_name}: and cannot be handled.  To fix, increase"
-                )?;
-                writeln!(
-                    f,
-                    "{program_name}: BZ_MAX_HANDLED_BLOCKS in bzip2recover.rs, and recompile."
-                )?;
-
-                Ok(())
-            }
             Error::Fatal => Ok(()),
         }
     }
 }
 
-fn main_help(program_name: &Path, in_filename: &Path) -> Result<(), Error> {
-    let mut b_start = vec![0u64; BZ_MAX_HANDLED_BLOCKS];
-    let mut b_end = vec![0u64; BZ_MAX_HANDLED_BLOCKS];
-    let mut rb_start = vec![0u64; BZ_MAX_HANDLED_BLOCKS];
-    let mut rb_end = vec![0u64; BZ_MAX_HANDLED_BLOCKS];
-
+fn main_help(
+    program_name: &Path,
+    in_filename: &Path,
+    merge: bool,
+    repair: u32,
+) -> Result<(), Error> {
     let progname = program_name.display();
 
     if in_filename.as_os_str().len() >= BZ_MAX_FILENAME - 20 {
@@ -210,74 +376,82 @@ fn main_help(program_name: &Path, in_filename: &Path) -> Result<(), Error> {
         return Err(Error::Fatal);
     };
 
-    let mut input_bitstream = BitStream::open_read_stream(input_file);
+    let mut input_bitstream = BitStream::open_read_stream(BufReader::new(input_file));
     eprintln!("{progname}: searching for block boundaries ...");
 
     let mut bits_read: u64 = 0;
     let mut buff_lo: u32 = 0;
     let mut buff_hi = buff_lo;
     let mut current_block = 0;
-    b_start[current_block] = 0;
-    let mut rb_ctr = 0;
+    let mut b_start: u64 = 0;
+
+    // the 16 bits before those in `buff_hi`, to find the stream headers
+    let mut buff_header: u32 = 0;
+    // the block size of the current stream, the largest one until its header is found
+    let mut block_size: u8 = 9;
+
+    // the first and last bit of every identified block, and the block size of its stream. there
+    // is no limit on their number
+    let mut blocks: Vec<(u64, u64, u8)> = Vec::new();
 
     loop {
         let b = input_bitstream.get_bit()?;
         bits_read = bits_read.wrapping_add(1);
         match b {
             None => {
-                if bits_read >= b_start[current_block]
-                    && bits_read.wrapping_sub(b_start[current_block]) >= 40
-                {
-                    b_end[current_block] = bits_read.wrapping_sub(1);
+                if bits_read >= b_start && bits_read.wrapping_sub(b_start) >= 40 {
+                    let b_end = bits_read.wrapping_sub(1);
                     if current_block > 0 {
                         eprintln!(
                             "   block {} runs from {} to {} (incomplete)",
-                            current_block, b_start[current_block], b_end[current_block],
+                            current_block, b_start, b_end,
                         );
                     }
                 }
                 break;
             }
             Some(b) => {
+                buff_header = (buff_header << 1) | (buff_hi >> 31);
                 buff_hi = (buff_hi << 1) | (buff_lo >> 31);
                 buff_lo = (buff_lo << 1) | b as u32;
-                if (buff_hi & 0xffff) == BLOCK_HEADER_HI && buff_lo == BLOCK_HEADER_LO
-                    || (buff_hi & 0xffff) == BLOCK_ENDMARK_HI && buff_lo == BLOCK_ENDMARK_LO
-                {
-                    b_end[current_block] = if bits_read > 49 {
+                let is_block = (buff_hi & 0xffff) == BLOCK_HEADER_HI && buff_lo == BLOCK_HEADER_LO;
+                let is_endmark =
+                    (buff_hi & 0xffff) == BLOCK_ENDMARK_HI && buff_lo == BLOCK_ENDMARK_LO;
+                if is_block || is_endmark {
+                    let b_end = if bits_read > 49 {
                         bits_read.wrapping_sub(49)
                     } else {
                         0
                     };
 
-                    if current_block > 0
-                        && (b_end[current_block]).wrapping_sub(b_start[current_block]) >= 130
-                    {
+                    if current_block > 0 && b_end.wrapping_sub(b_start) >= 130 {
                         eprintln!(
                             "   block {} runs from {} to {}",
-                            rb_ctr + 1,
-                            b_start[current_block],
-                            b_end[current_block],
+                            blocks.len() + 1,
+                            b_start,
+                            b_end,
                         );
-                        rb_start[rb_ctr] = b_start[current_block];
-                        rb_end[rb_ctr] = b_end[current_block];
-                        rb_ctr += 1;
-                    }
-                    if current_block >= BZ_MAX_HANDLED_BLOCKS {
-                        return Err(Error::TooManyBlocks(BZ_MAX_HANDLED_BLOCKS));
+                        blocks.push((b_start, b_end, block_size));
                     }
                     current_block += 1;
-                    b_start[current_block] = bits_read;
+                    b_start = bits_read;
+
+                    // a stream header is byte-aligned, and directly followed by the first block
+                    let header = ((buff_header & 0xffff) << 16) | (buff_hi >> 16);
+                    if is_endmark {
+                        block_size = 9;
+                    } else if bits_read >= 80
+                        && (bits_read - 80) % 8 == 0
+                        && (0x425a6831..=0x425a6839).contains(&header)
+                    {
+                        block_size = (header & 0xf) as u8;
+                    }
                 }
             }
         }
     }
 
-    input_bitstream.close()?;
-
-    /*-- identified blocks run from 1 to rbCtr inclusive. --*/
-
-    if rb_ctr < 1 {
+    if blocks.is_empty() {
         eprintln!("{progname}: sorry, I couldn't find any block boundaries.");
 
         return Err(Error::Fatal);
@@ -290,11 +464,47 @@ fn main_help(program_name: &Path, in_filename: &Path) -> Result<(), Error> {
 
         return Err(Error::Fatal);
     };
-    input_bitstream = BitStream::open_read_stream(input_file);
+    input_bitstream = BitStream::open_read_stream(BufReader::new(input_file));
+
+    // the stream of the current block is collected in memory, so that it can be verified
+    let Ok(mut decompressor) = Decompressor::new(DecompressMode::Fast) else {
+        eprintln!("{progname}: couldn't allocate enough memory");
 
+        return Err(Error::Fatal);
+    };
     let mut block_crc: u32 = 0;
-    let mut output_bitstream: Option<BitStream> = None;
+    let mut output: Option<(BitStream<Vec<u8>>, File)> = None;
     let mut wr_block = 0;
+    let mut good_blocks = 0;
+    let mut damaged_blocks = 0;
+    let mut repaired_blocks = 0;
+
+    let mut merged = match merge {
+        false => None,
+        true => {
+            // we've been able to open this file, so there must be a file name
+            let filename = in_filename.file_name().unwrap();
+            let filename = format!("recovered_{}", filename.to_string_lossy());
+            let out_filename = in_filename.with_file_name(&filename).with_extension("bz2");
+
+            eprintln!(
+                "{progname}: merging the good blocks into `{}'",
+                out_filename.display()
+            );
+
+            let Ok(output_file) = create_output_file(&out_filename) else {
+                eprintln!("{}: can't write `{}'", progname, out_filename.display());
+
+                return Err(Error::Fatal);
+            };
+            let mut merged = BitStream::open_write_stream(BufWriter::new(output_file));
+            merged.put_stream_header()?;
+            Some((merged, 0u32))
+        }
+    };
+
+    // the recNNNNN names grow a digit beyond 99999 blocks, so that they still sort correctly
+    let width = Ord::max(5, blocks.len().to_string().len());
 
     bits_read = 0;
 
@@ -302,75 +512,124 @@ fn main_help(program_name: &Path, in_filename: &Path) -> Result<(), Error> {
         buff_hi = (buff_hi << 1) | (buff_lo >> 31);
         buff_lo = (buff_lo << 1) | b as u32;
 
-        if bits_read == 47u64.wrapping_add(rb_start[wr_block]) {
+        let (rb_start, rb_end, block_size) = blocks[wr_block];
+
+        if bits_read == 47u64.wrapping_add(rb_start) {
             block_crc = (buff_hi << 16) | (buff_lo >> 16);
         }
-        if bits_read >= rb_start[wr_block] && bits_read <= rb_end[wr_block] {
-            if let Some(output_bitstream) = output_bitstream.as_mut() {
+        if bits_read >= rb_start && bits_read <= rb_end {
+            if let Some((output_bitstream, _)) = output.as_mut() {
                 output_bitstream.put_bit(b as i32)?;
             }
         }
         bits_read = bits_read.wrapping_add(1);
-        if bits_read == (rb_end[wr_block]).wrapping_add(1) {
-            if let Some(mut output_bitstream) = output_bitstream.take() {
-                output_bitstream.put_u8(0x17)?;
-                output_bitstream.put_u8(0x72)?;
-                output_bitstream.put_u8(0x45)?;
-                output_bitstream.put_u8(0x38)?;
-                output_bitstream.put_u8(0x50)?;
-                output_bitstream.put_u8(0x90)?;
-                output_bitstream.put_u32(block_crc)?;
-                output_bitstream.close()?;
+        if bits_read == (rb_end).wrapping_add(1) {
+            if let Some((mut output_bitstream, mut output_file)) = output.take() {
+                output_bitstream.put_stream_trailer(block_crc)?;
+                let mut stream = output_bitstream.close()?;
+
+                let block_len = rb_end - rb_start + 1;
+                let flips = match verify_stream(&mut decompressor, &stream, block_size) {
+                    true => Some(Vec::new()),
+                    false if repair > 0 => {
+                        damaged_blocks += 1;
+                        repair_stream(&mut stream, block_len, block_size, repair)
+                    }
+                    false => None,
+                };
+
+                output_file.write_all(&stream).map_err(|error| {
+                    eprintln!();
+                    Error::Writing(error)
+                })?;
+
+                if let Some(flips) = flips {
+                    if flips.is_empty() {
+                        eprintln!(" ok");
+                    } else {
+                        let mut changed = Vec::new();
+                        for offset in flips {
+                            // a flip in the stored CRC changes the CRC of the block
+                            if offset < 32 {
+                                block_crc ^= 1 << (31 - offset);
+                            }
+
+                            let bit = rb_start + offset;
+                            let mask = 0x80 >> (bit % 8);
+                            changed
+                                .push(format!("bit {bit} (byte {}, mask 0x{mask:02x})", bit / 8));
+                        }
+                        eprintln!(" repaired by flipping {}", changed.join(" and "));
+                        repaired_blocks += 1;
+                    }
+                    good_blocks += 1;
+
+                    if let Some((merged, combined_crc)) = merged.as_mut() {
+                        // copy the block magic and the block, skipping the stream header
+                        let block_bits = 48 + rb_end - rb_start + 1;
+                        merged.put_bits(&stream, 32, block_bits)?;
+                        *combined_crc = combined_crc.rotate_left(1) ^ block_crc;
+                    }
+                } else if repair > 0 {
+                    eprintln!(" damaged, no repair found");
+                } else {
+                    eprintln!(" damaged");
+                }
             }
 
-            if wr_block >= rb_ctr {
+            wr_block += 1;
+            if wr_block >= blocks.len() {
                 break;
             }
-            wr_block += 1;
-        } else if bits_read == rb_start[wr_block] {
+        } else if bits_read == rb_start {
             // we've been able to open this file, so there must be a file name
             let filename = in_filename.file_name().unwrap();
 
-            let filename = format!("rec{:05}{}", wr_block + 1, filename.to_string_lossy());
+            let filename = format!("rec{:0width$}{}", wr_block + 1, filename.to_string_lossy());
 
             let out_filename = in_filename.with_file_name(&filename).with_extension("bz2");
 
-            eprintln!(
+            eprint!(
                 "   writing block {} to `{}' ...",
                 wr_block + 1,
                 out_filename.display(),
             );
 
-            let mut options = std::fs::File::options();
-            options.write(true).create_new(true);
-
-            #[cfg(unix)]
-            #[allow(clippy::unnecessary_cast)]
-            options.mode(libc::S_IWUSR as u32 | libc::S_IRUSR as u32);
-
-            let Ok(output_file) = options.open(&out_filename) else {
+            let Ok(output_file) = create_output_file(&out_filename) else {
+                // the error goes on a line of its own
+                eprintln!();
                 eprintln!("{}: can't write `{}'", progname, out_filename.display());
 
                 return Err(Error::Fatal);
             };
 
-            output_bitstream = {
-                let mut output_bitstream = BitStream::open_write_stream(output_file);
-                output_bitstream.put_u8(0x42)?;
-                output_bitstream.put_u8(0x5a)?;
-                output_bitstream.put_u8(0x68)?;
-                output_bitstream.put_u8(0x30 + 9)?;
-                output_bitstream.put_u8(0x31)?;
-                output_bitstream.put_u8(0x41)?;
-                output_bitstream.put_u8(0x59)?;
-                output_bitstream.put_u8(0x26)?;
-                output_bitstream.put_u8(0x53)?;
-                output_bitstream.put_u8(0x59)?;
-                Some(output_bitstream)
-            }
+            // the block magic is not part of the identified bits of the block
+            let mut output_bitstream = BitStream::open_write_stream(Vec::new());
+            output_bitstream.put_stream_header()?;
+            output_bitstream.put_u8(0x31)?;
+            output_bitstream.put_u8(0x41)?;
+            output_bitstream.put_u8(0x59)?;
+            output_bitstream.put_u8(0x26)?;
+            output_bitstream.put_u8(0x53)?;
+            output_bitstream.put_u8(0x59)?;
+            output = Some((output_bitstream, output_file));
         }
     }
 
+    if let Some((mut merged, combined_crc)) = merged {
+        merged.put_stream_trailer(combined_crc)?;
+        merged.close()?;
+    }
+
+    eprintln!(
+        "{progname}: {good_blocks} of {} blocks decode correctly",
+        blocks.len()
+    );
+
+    if repair > 0 {
+        eprintln!("{progname}: {repaired_blocks} of {damaged_blocks} damaged blocks repaired");
+    }
+
     eprintln!("{progname}: finished");
 
     Ok(())
@@ -380,22 +639,40 @@ fn main() -> ExitCode {
     let mut it = ::std::env::args_os();
 
     let program_name = PathBuf::from(it.next().unwrap());
-    let opt_in_filename = it.next().map(PathBuf::from);
+    let mut merge = false;
+    let mut repair = 0;
+    let mut opt_in_filename = it.next().map(PathBuf::from);
+    while let Some(flag) = opt_in_filename.as_deref() {
+        if flag == Path::new("-m") || flag == Path::new("--merge") {
+            merge = true;
+        } else if flag == Path::new("-r") || flag == Path::new("--repair") {
+            repair = 1;
+        } else if flag == Path::new("--repair=2") {
+            repair = 2;
+        } else {
+            break;
+        }
+        opt_in_filename = it.next().map(PathBuf::from);
+    }
 
     eprintln!("bzip2recover 1.0.6: extracts blocks from damaged .bz2 files.");
 
     let Some(in_filename) = opt_in_filename else {
         eprintln!(
-            "{program_name}: usage is `{program_name} damaged_file_name'.",
+            "{program_name}: usage is `{program_name} [-m] [-r] damaged_file_name'.",
             program_name = program_name.display()
         );
 
+        eprintln!("\t-m --merge: also write the good blocks to a single valid file");
+        eprintln!("\t-r --repair: try to fix damaged blocks by flipping a single bit");
+        eprintln!("\t--repair=2: also try flipping two bits close together");
+
         eprintln!("\trestrictions on size of recovered file: None");
 
         return ExitCode::FAILURE;
     };
 
-    match main_help(&program_name, &in_filename) {
+    match main_help(&program_name, &in_filename, merge, repair) {
         Ok(()) => ExitCode::SUCCESS,
         Err(error) => {
             let emit_error = EmitError {
@@ -484,30 +761,4 @@ mod test {
             }
         );
     }
-
-    #[test]
-    fn too_many_blocks() {
-        use std::fmt::Write;
-
-        let program_name = Path::new("/foo/bar/bzip2recover");
-        let in_filename = Path::new("$garbage");
-
-        let emit_error = EmitError {
-            program_name,
-            in_filename,
-            error: Error::TooManyBlocks(42),
-        };
-
-        let mut buf = String::new();
-        write!(&mut buf, "{emit_error}").unwrap();
-
-        assert_eq!(
-            buf,
-            concat!(
-                "/foo/bar/bzip2recover: `$garbage' appears to contain more than 42 blocks\n",
-                "/foo/bar/bzip2recover: and cannot be handled.  To fix, increase\n",
-                "/foo/bar/bzip2recover: BZ_MAX_HANDLED_BLOCKS in bzip2recover.rs, and recompile.\n"
-            )
-        );
-    }
 }
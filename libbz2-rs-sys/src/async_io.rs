@@ -0,0 +1,441 @@
+//! Adapters for the async io traits of `tokio` and `futures-io`.
+//!
+//! The compression and decompression state machines suspend on empty input or full output, so
+//! the adapters only need to turn a pending read or write of the underlying stream into
+//! [`Poll::Pending`]. Each adapter implements the traits of whichever of the `tokio` and
+//! `futures-io` features are enabled.
+
+use core::pin::Pin;
+use core::task::{ready, Context, Poll};
+use std::io;
+#[cfg(feature = "tokio")]
+use std::vec::Vec;
+
+use crate::bufread::StreamDecoder;
+use crate::bzlib::BZ_MAX_UNUSED_U32;
+use crate::{bz_decompress_limits, Action, Compressor, DecompressMode, Error, Status};
+
+/// Decompresses bzip2 data read from an underlying async buffered reader.
+///
+/// By default a single stream is decoded, and the decoder stops exactly at its end like
+/// [`crate::bufread::BzDecoder`]. With [`AsyncBzDecoder::multiple_streams`], a concatenation of
+/// streams is decoded like [`crate::bufread::MultiBzDecoder`] does.
+pub struct AsyncBzDecoder<R> {
+    reader: R,
+    state: StreamDecoder,
+    /// Decompressed output for a `tokio` read, so that its buffer needs no initialization.
+    #[cfg(feature = "tokio")]
+    scratch: Vec<u8>,
+}
+
+impl<R> AsyncBzDecoder<R> {
+    /// Creates a decoder that reads compressed data from `reader`, using [`DecompressMode::Fast`].
+    pub fn new(reader: R) -> Self {
+        Self::with_mode(reader, DecompressMode::Fast)
+    }
+
+    /// Creates a decoder that reads compressed data from `reader`, using the given `mode`.
+    pub fn with_mode(reader: R, mode: DecompressMode) -> Self {
+        Self::with_limits(reader, mode, bz_decompress_limits::default())
+    }
+
+    /// Creates a decoder that fails with [`Error::LimitExceeded`] when decompression would cross
+    /// one of the `limits`.
+    pub fn with_limits(reader: R, mode: DecompressMode, limits: bz_decompress_limits) -> Self {
+        Self {
+            reader,
+            state: StreamDecoder::new(mode, limits, false),
+            #[cfg(feature = "tokio")]
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Creates a decoder that reads a concatenation of streams from `reader`.
+    pub fn multiple_streams(reader: R, mode: DecompressMode) -> Self {
+        Self::multiple_streams_with_limits(reader, mode, bz_decompress_limits::default())
+    }
+
+    /// Creates a decoder that reads a concatenation of streams from `reader`, and fails with
+    /// [`Error::LimitExceeded`] when decompression would cross one of the `limits`.
+    ///
+    /// The limits apply to all of the streams together.
+    pub fn multiple_streams_with_limits(
+        reader: R,
+        mode: DecompressMode,
+        limits: bz_decompress_limits,
+    ) -> Self {
+        Self {
+            reader,
+            state: StreamDecoder::new(mode, limits, true),
+            #[cfg(feature = "tokio")]
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Reading from the underlying reader before the end of the stream will corrupt the
+    /// decompressed output.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Unwraps this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+type PollFillBuf<R> = for<'a> fn(Pin<&'a mut R>, &mut Context<'_>) -> Poll<io::Result<&'a [u8]>>;
+
+impl<R: Unpin> AsyncBzDecoder<R> {
+    fn poll_decode(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+        fill_buf: PollFillBuf<R>,
+        consume: fn(Pin<&mut R>, usize),
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if self.state.is_done() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let input = ready!(fill_buf(Pin::new(&mut self.reader), cx))?;
+            let (consumed, produced) = self.state.decode(input, out)?;
+            consume(Pin::new(&mut self.reader), consumed);
+
+            if let Some(produced) = produced {
+                return Poll::Ready(Ok(produced));
+            }
+        }
+    }
+}
+
+/// The most output decompressed by a single `tokio` read.
+#[cfg(feature = "tokio")]
+const SCRATCH_LEN: usize = 32 * 1024;
+
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncBufRead + Unpin> tokio::io::AsyncRead for AsyncBzDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut scratch = core::mem::take(&mut this.scratch);
+        // zeroed only once, on the first read
+        scratch.resize(SCRATCH_LEN, 0);
+        let len = buf.remaining().min(SCRATCH_LEN);
+
+        let result = this.poll_decode(cx, &mut scratch[..len], R::poll_fill_buf, R::consume);
+        if let Poll::Ready(Ok(n)) = result {
+            buf.put_slice(&scratch[..n]);
+        }
+        this.scratch = scratch;
+
+        result.map_ok(|_| ())
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<R: futures_io::AsyncBufRead + Unpin> futures_io::AsyncRead for AsyncBzDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_decode(cx, buf, R::poll_fill_buf, R::consume)
+    }
+}
+
+/// Compresses data written to it, and writes the compressed data to an underlying async writer.
+///
+/// Shutting down (`tokio`) or closing (`futures-io`) the encoder completes the compressed stream,
+/// and then shuts down or closes the underlying writer.
+pub struct AsyncBzEncoder<W> {
+    writer: W,
+    compressor: Compressor,
+    buf: [u8; BZ_MAX_UNUSED_U32 as usize],
+    /// The range of `buf` that still needs to be written.
+    pos: usize,
+    end: usize,
+    finished: bool,
+}
+
+impl<W> AsyncBzEncoder<W> {
+    /// Creates an encoder that writes compressed data to `writer`.
+    ///
+    /// For the meaning of `block_size_100k` and `work_factor`, see [`Compressor::new`], which
+    /// also lists the possible errors.
+    pub fn new(writer: W, block_size_100k: u32, work_factor: u32) -> Result<Self, Error> {
+        Ok(Self {
+            writer,
+            compressor: Compressor::new(block_size_100k, work_factor)?,
+            buf: [0; BZ_MAX_UNUSED_U32 as usize],
+            pos: 0,
+            end: 0,
+            finished: false,
+        })
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Writing to the underlying writer directly will corrupt the compressed output.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Unwraps this encoder, returning the underlying writer.
+    ///
+    /// The compressed stream is only complete if the encoder was shut down or closed first.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// The total number of uncompressed bytes written to this encoder.
+    pub fn total_in(&self) -> u64 {
+        self.compressor.total_in()
+    }
+
+    /// The total number of compressed bytes produced by this encoder.
+    pub fn total_out(&self) -> u64 {
+        self.compressor.total_out()
+    }
+}
+
+type PollWrite<W> = fn(Pin<&mut W>, &mut Context<'_>, &[u8]) -> Poll<io::Result<usize>>;
+
+impl<W: Unpin> AsyncBzEncoder<W> {
+    /// Writes the pending output to the underlying writer.
+    fn poll_dump(&mut self, cx: &mut Context<'_>, write: PollWrite<W>) -> Poll<io::Result<()>> {
+        while self.pos < self.end {
+            let n = ready!(write(
+                Pin::new(&mut self.writer),
+                cx,
+                &self.buf[self.pos..self.end]
+            ))?;
+
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.pos += n;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_encode(
+        &mut self,
+        cx: &mut Context<'_>,
+        input: &[u8],
+        write: PollWrite<W>,
+    ) -> Poll<io::Result<usize>> {
+        if self.finished {
+            return Poll::Ready(Err(Error::Sequence.into()));
+        }
+
+        loop {
+            ready!(self.poll_dump(cx, write))?;
+
+            if input.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let progress = self
+                .compressor
+                .compress(input, &mut self.buf, Action::Run)?;
+            self.pos = 0;
+            self.end = progress.produced;
+
+            if progress.consumed > 0 {
+                return Poll::Ready(Ok(progress.consumed));
+            }
+        }
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>, write: PollWrite<W>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_dump(cx, write))?;
+
+            if self.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            let progress = self
+                .compressor
+                .compress(&[], &mut self.buf, Action::Finish)?;
+            self.pos = 0;
+            self.end = progress.produced;
+            self.finished = progress.status == Status::StreamEnd;
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for AsyncBzEncoder<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_encode(cx, buf, W::poll_write)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_dump(cx, W::poll_write))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_finish(cx, W::poll_write))?;
+        Pin::new(&mut this.writer).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<W: futures_io::AsyncWrite + Unpin> futures_io::AsyncWrite for AsyncBzEncoder<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_encode(cx, buf, W::poll_write)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_dump(cx, W::poll_write))?;
+        Pin::new(&mut this.writer).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_finish(cx, W::poll_write))?;
+        Pin::new(&mut this.writer).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use crate::test_data::SAMPLE2_REF;
+    #[cfg(feature = "tokio")]
+    use crate::test_data::{SAMPLE1_BZ2, SAMPLE1_REF, SAMPLE2_BZ2};
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_round_trip() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+        // a small pipe, so both sides regularly have to wait for each other
+        let (client, server) = tokio::io::duplex(64);
+
+        let writer = async move {
+            let mut encoder = AsyncBzEncoder::new(client, 1, 30).unwrap();
+            for chunk in SAMPLE1_REF.chunks(1000) {
+                encoder.write_all(chunk).await.unwrap();
+            }
+            encoder.shutdown().await.unwrap();
+            assert_eq!(encoder.total_in(), SAMPLE1_REF.len() as u64);
+        };
+
+        let reader = async move {
+            let mut decoder = AsyncBzDecoder::new(BufReader::new(server));
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).await.unwrap();
+            output
+        };
+
+        let ((), output) = tokio::join!(writer, reader);
+        assert_eq!(output, SAMPLE1_REF);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_multiple_streams() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let (mut client, server) = tokio::io::duplex(100);
+
+        let writer = async move {
+            client.write_all(SAMPLE1_BZ2).await.unwrap();
+            client.write_all(SAMPLE2_BZ2).await.unwrap();
+        };
+
+        let reader = async move {
+            let reader = BufReader::new(server);
+            let mut decoder = AsyncBzDecoder::multiple_streams(reader, DecompressMode::Small);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).await.unwrap();
+            output
+        };
+
+        let ((), output) = tokio::join!(writer, reader);
+        assert_eq!(output, [SAMPLE1_REF, SAMPLE2_REF].concat());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_truncated() {
+        use tokio::io::AsyncReadExt;
+
+        let truncated = &SAMPLE1_BZ2[..SAMPLE1_BZ2.len() - 10];
+
+        let mut output = Vec::new();
+        let error = AsyncBzDecoder::new(truncated)
+            .read_to_end(&mut output)
+            .await
+            .unwrap_err();
+        assert_eq!(Error::from(error), Error::UnexpectedEof);
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[test]
+    fn futures_round_trip() {
+        use futures::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let (client, server) = tokio::io::duplex(64);
+
+        let writer = async move {
+            let mut encoder = AsyncBzEncoder::new(client.compat(), 9, 0).unwrap();
+            encoder.write_all(SAMPLE2_REF).await.unwrap();
+            encoder.close().await.unwrap();
+        };
+
+        let reader = async move {
+            let mut decoder = AsyncBzDecoder::new(BufReader::new(server.compat()));
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).await.unwrap();
+
+            // the decoder stops at the end of the stream
+            let mut rest = Vec::new();
+            decoder.into_inner().read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+
+            output
+        };
+
+        let ((), output) = futures::executor::block_on(futures::future::join(writer, reader));
+        assert_eq!(output, SAMPLE2_REF);
+    }
+}
@@ -0,0 +1,144 @@
+//! Bit-level handling of the blocks of a stream.
+//!
+//! Blocks are not byte-aligned, so copying a block out of a stream means shifting all of its
+//! bits. A copied block can be decoded on its own by wrapping it in a stream header and trailer.
+
+use std::vec::Vec;
+
+use crate::decompress::END_OF_STREAM_MAGIC;
+use crate::{DecompressMode, Decompressor, Error, Status};
+
+/// Writes a big-endian bit stream.
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    pub(crate) bytes: Vec<u8>,
+    buff: u32,
+    live: u32,
+}
+
+impl BitWriter {
+    /// Writes the `n <= 24` low bits of `v`.
+    pub(crate) fn write(&mut self, n: u32, v: u32) {
+        debug_assert!(n <= 32 - 8);
+        self.buff = (self.buff << n) | (v & ((1 << n) - 1));
+        self.live += n;
+
+        while self.live >= 8 {
+            self.live -= 8;
+            self.bytes.push((self.buff >> self.live) as u8);
+        }
+    }
+
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.live == 0 {
+            self.bytes.extend_from_slice(bytes);
+        } else {
+            for &byte in bytes {
+                self.write(8, u32::from(byte));
+            }
+        }
+    }
+
+    /// Writes `len` bits of `src`, starting at bit offset `start`.
+    pub(crate) fn write_bits(&mut self, src: &[u8], start: u64, len: u64) {
+        let end = start + len;
+        let mut pos = start;
+
+        // first align the source to a whole byte
+        let lead = Ord::min((8 - pos % 8) % 8, len) as u32;
+        if lead > 0 {
+            self.write(lead, read_bits(src, pos, lead));
+            pos += u64::from(lead);
+        }
+
+        let whole = ((end - pos) / 8) as usize;
+        self.write_bytes(&src[(pos / 8) as usize..][..whole]);
+        pos += whole as u64 * 8;
+
+        let rest = (end - pos) as u32;
+        if rest > 0 {
+            self.write(rest, read_bits(src, pos, rest));
+        }
+    }
+
+    /// Pads the last byte with zero bits.
+    pub(crate) fn finish(&mut self) {
+        if self.live > 0 {
+            self.write(8 - self.live, 0);
+        }
+    }
+}
+
+/// The largest number of bits that a block with the given block size takes up.
+///
+/// A block holds at most one symbol per byte of the block, plus the end-of-block symbol, and a
+/// symbol takes at most 20 bits. The tables and selectors that precede the symbols take up less
+/// than 64 KiB, unless an encoder pads the delta coding of the code lengths, which the format
+/// allows but no encoder does.
+pub(crate) fn max_block_bits(block_size_100k: u8) -> u64 {
+    let block_size = u64::from(block_size_100k) * 100_000;
+    (block_size + 1) * 20 + 64 * 1024 * 8
+}
+
+/// The largest number of bytes that a block with the given block size decompresses to.
+///
+/// The run-length encoding that is undone last turns every 5 bytes of the block into at most 259
+/// bytes: 4 equal bytes and a count of up to 255 more.
+pub(crate) fn max_block_output(block_size_100k: u8) -> u64 {
+    u64::from(block_size_100k) * 100_000 / 5 * 259
+}
+
+/// Reads the `n <= 32` bits of `src` that start at bit offset `pos`.
+pub(crate) fn read_bits(src: &[u8], pos: u64, n: u32) -> u32 {
+    let mut v = 0u64;
+    for i in 0..n as u64 {
+        let bit = pos + i;
+        v = (v << 1) | u64::from(src[(bit / 8) as usize] >> (7 - bit % 8) & 1);
+    }
+    v as u32
+}
+
+/// Decodes the bits `start..end` of `input` as a single block, and returns the decompressed data
+/// and the block CRC.
+///
+/// The block is wrapped in a stream of its own. That only decodes correctly when `end` is exactly
+/// the end of the block, and the block CRC matches.
+pub(crate) fn decode_block(
+    input: &[u8],
+    level: u8,
+    start: u64,
+    end: u64,
+) -> Result<(Vec<u8>, u32), Error> {
+    if end - start < 48 + 32 {
+        return Err(Error::Data);
+    }
+    let crc = read_bits(input, start + 48, 32);
+
+    let mut writer = BitWriter::default();
+    writer.write_bytes(&[b'B', b'Z', b'h', level]);
+    writer.write_bits(input, start, end - start);
+    writer.write_bytes(&END_OF_STREAM_MAGIC.to_be_bytes()[2..]);
+    writer.write_bytes(&crc.to_be_bytes());
+    writer.finish();
+
+    let mut decompressor = Decompressor::new(DecompressMode::Fast)?;
+    let mut stream = writer.bytes.as_slice();
+    let mut block = Vec::new();
+    loop {
+        if block.len() == block.capacity() {
+            block.reserve(64 * 1024);
+        }
+
+        let len = block.len();
+        block.resize(block.capacity(), 0);
+        let progress = decompressor.decompress(stream, &mut block[len..])?;
+        block.truncate(len + progress.produced);
+        stream = &stream[progress.consumed..];
+
+        match progress.status {
+            Status::StreamEnd => return Ok((block, crc)),
+            _ if stream.is_empty() && progress.produced == 0 => return Err(Error::UnexpectedEof),
+            _ => {}
+        }
+    }
+}
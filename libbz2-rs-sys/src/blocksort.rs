@@ -2,11 +2,13 @@
 
 use core::cmp::Ordering;
 
+use crate::logging::{LogEvent, LogSink};
+use crate::sais;
+use crate::InternalError;
 use crate::{
     assert_h,
-    bzlib::{Arr2, EState, BZ_N_OVERSHOOT, BZ_N_QSORT, BZ_N_RADIX, FTAB_LEN},
+    bzlib::{Arr2, BlockSorter, EState, BZ_N_OVERSHOOT, BZ_N_QSORT, BZ_N_RADIX, FTAB_LEN},
 };
-use crate::{debug_log, debug_logln};
 
 /// Fallback O(N log(N)^2) sorting algorithm, for repetitive blocks
 #[inline]
@@ -47,7 +49,12 @@ fn fallbackSimpleSort(fmap: &mut [u32], eclass: &[u32], lo: i32, hi: i32) {
 const FALLBACK_QSORT_SMALL_THRESH: i32 = 10;
 const FALLBACK_QSORT_STACK_SIZE: usize = 100;
 
-fn fallbackQSort3(fmap: &mut [u32], eclass: &[u32], loSt: i32, hiSt: i32) {
+fn fallbackQSort3(
+    fmap: &mut [u32],
+    eclass: &[u32],
+    loSt: i32,
+    hiSt: i32,
+) -> Result<(), InternalError> {
     let mut unLo: i32;
     let mut unHi: i32;
     let mut ltLo: i32;
@@ -184,6 +191,8 @@ fn fallbackQSort3(fmap: &mut [u32], eclass: &[u32], loSt: i32, hiSt: i32) {
             fpush!(lo, n);
         }
     }
+
+    Ok(())
 }
 
 fn fallbackSort(
@@ -192,7 +201,8 @@ fn fallbackSort(
     bhtab: &mut [u32; FTAB_LEN],
     nblock: usize,
     verb: i32,
-) {
+    log: LogSink,
+) -> Result<(), InternalError> {
     macro_rules! SET_BH {
         ($zz:expr) => {
             bhtab[$zz as usize >> 5] |= 1 << ($zz & 31);
@@ -231,7 +241,7 @@ fn fallbackSort(
        initial fmap and initial BH bits.
     --*/
     if verb >= 4 {
-        debug_logln!("        bucket sorting ...");
+        log.emit(LogEvent::FallbackBucketSort);
     }
 
     {
@@ -279,9 +289,6 @@ fn fallbackSort(
     let mut k: i32;
     let mut l: i32;
     loop {
-        if verb >= 4 {
-            debug_log!("        depth {:>6} has ", H);
-        }
         let mut j = 0;
         for (i, x) in fmap[..nblock as usize].iter().enumerate() {
             if ISSET_BH!(i) {
@@ -333,7 +340,7 @@ fn fallbackSort(
             /*-- now [l, r] bracket current bucket --*/
             if r > l {
                 nNotDone += r - l + 1;
-                fallbackQSort3(fmap, arr2.eclass(), l, r);
+                fallbackQSort3(fmap, arr2.eclass(), l, r)?;
 
                 /*-- scan bucket and generate header bits-- */
                 let mut cc = -1;
@@ -347,7 +354,10 @@ fn fallbackSort(
             }
         }
         if verb >= 4 {
-            debug_logln!("{:>6} unresolved strings", nNotDone);
+            log.emit(LogEvent::FallbackDepth {
+                depth: H as u32,
+                unresolved: nNotDone as u32,
+            });
         }
         H *= 2;
         if H > nblock || nNotDone == 0 {
@@ -356,7 +366,7 @@ fn fallbackSort(
     }
 
     if verb >= 4 {
-        debug_logln!("        reconstructing block ...");
+        log.emit(LogEvent::FallbackReconstruct);
     }
 
     {
@@ -373,6 +383,8 @@ fn fallbackSort(
 
         assert_h!(j < 256, 1005);
     }
+
+    Ok(())
 }
 
 #[inline]
@@ -511,7 +523,7 @@ fn mainQSort3(
     hiSt: i32,
     dSt: u32,
     budget: &mut i32,
-) {
+) -> Result<(), InternalError> {
     let mut unLo: i32;
     let mut unHi: i32;
     let mut ltLo: i32;
@@ -533,7 +545,7 @@ fn mainQSort3(
         if hi - lo < MAIN_QSORT_SMALL_THRESH || d > MAIN_QSORT_DEPTH_THRESH {
             mainSimpleSort(ptr, block, quadrant, nblock, lo, hi, d, budget);
             if *budget < 0 {
-                return;
+                return Ok(());
             }
         } else {
             let med = median_of_3(
@@ -611,6 +623,8 @@ fn mainQSort3(
             }
         }
     }
+
+    Ok(())
 }
 fn mainSort(
     ptr: &mut [u32],
@@ -619,8 +633,9 @@ fn mainSort(
     ftab: &mut [u32; FTAB_LEN],
     nblock: usize,
     verb: i32,
+    log: LogSink,
     budget: &mut i32,
-) {
+) -> Result<(), InternalError> {
     let mut j: i32;
     let mut k: usize;
     let mut ss: i32;
@@ -631,7 +646,7 @@ fn mainSort(
     let mut c1: u8;
     let mut s: u16;
     if verb >= 4 {
-        debug_logln!("        main sort initialise ...");
+        log.emit(LogEvent::MainSortInit);
     }
 
     /*-- set up the 2-byte frequency table --*/
@@ -648,7 +663,7 @@ fn mainSort(
     }
 
     if verb >= 4 {
-        debug_logln!("        bucket sorting ...");
+        log.emit(LogEvent::MainBucketSort);
     }
 
     /*-- Complete the initial radix sort --*/
@@ -738,18 +753,17 @@ fn mainSort(
 
                     if hi > lo {
                         if verb >= 4 {
-                            debug_logln!(
-                                "        qsort [{:#x}, {:#x}]   done {}   this {}",
-                                ss,
-                                j,
-                                numQSorted,
-                                hi - lo + 1,
-                            );
+                            log.emit(LogEvent::QuickSort {
+                                big: ss as u32,
+                                small: j as u32,
+                                sorted: numQSorted as u32,
+                                size: (hi - lo + 1) as u32,
+                            });
                         }
-                        mainQSort3(ptr, block, quadrant, nblock, lo, hi, 2, budget);
+                        mainQSort3(ptr, block, quadrant, nblock, lo, hi, 2, budget)?;
                         numQSorted += hi - lo + 1;
                         if *budget < 0 {
-                            return;
+                            return Ok(());
                         }
                     }
                 }
@@ -880,13 +894,14 @@ fn mainSort(
         }
     }
     if verb >= 4 {
-        debug_logln!(
-            "        {} pointers, {} sorted, {} scanned",
-            nblock,
-            numQSorted,
-            nblock - numQSorted as usize,
-        );
+        log.emit(LogEvent::MainSortDone {
+            pointers: nblock as u32,
+            sorted: numQSorted as u32,
+            scanned: (nblock - numQSorted as usize) as u32,
+        });
     }
+
+    Ok(())
 }
 
 /// Pre:
@@ -900,13 +915,31 @@ fn mainSort(
 ///    All other areas of block destroyed
 ///    ftab [ 0 .. 65536 ] destroyed
 ///    arr1 [0 .. nblock-1] holds sorted order
-pub(crate) fn block_sort(s: &mut EState) {
+pub(crate) fn block_sort(s: &mut EState) -> Result<(), InternalError> {
     let nblock = usize::try_from(s.nblock).unwrap();
 
     let ptr = s.arr1.ptr();
     let ftab = s.ftab.ftab();
 
-    BZ2_blockSortHelp(ptr, &mut s.arr2, ftab, nblock, s.workFactor, s.verbosity);
+    let sorted = match s.blockSorter {
+        BlockSorter::Reference => false,
+        BlockSorter::SuffixArray => {
+            let (block, workspace) = s.arr2.block_and_workspace(nblock);
+            sais::sort_rotations(ptr, block, workspace, ftab)
+        }
+    };
+
+    if !sorted {
+        BZ2_blockSortHelp(
+            ptr,
+            &mut s.arr2,
+            ftab,
+            nblock,
+            s.workFactor,
+            s.verbosity,
+            s.logSink,
+        )?;
+    }
 
     s.origPtr = -1;
     for i in 0..s.nblock {
@@ -917,6 +950,8 @@ pub(crate) fn block_sort(s: &mut EState) {
     }
 
     assert_h!(s.origPtr != -1, 1003);
+
+    Ok(())
 }
 
 fn BZ2_blockSortHelp(
@@ -926,9 +961,10 @@ fn BZ2_blockSortHelp(
     nblock: usize,
     workFactor: i32,
     verbosity: i32,
-) {
+    log: LogSink,
+) -> Result<(), InternalError> {
     if nblock < 10000 {
-        fallbackSort(ptr, arr2, ftab, nblock, verbosity);
+        fallbackSort(ptr, arr2, ftab, nblock, verbosity, log)?;
     } else {
         let (block, quadrant) = arr2.block_and_quadrant(nblock);
 
@@ -943,23 +979,32 @@ fn BZ2_blockSortHelp(
         let budgetInit = nblock as i32 * ((wfact - 1) / 3);
         let mut budget = budgetInit;
 
-        mainSort(ptr, block, quadrant, ftab, nblock, verbosity, &mut budget);
+        mainSort(
+            ptr,
+            block,
+            quadrant,
+            ftab,
+            nblock,
+            verbosity,
+            log,
+            &mut budget,
+        )?;
 
         if verbosity >= 3 {
-            debug_logln!(
-                "      {} work, {} block, ratio {:5.2}",
-                budgetInit - budget,
-                nblock,
-                (budgetInit - budget) as f64 / (if nblock == 0 { 1 } else { nblock }) as f64
-            );
+            log.emit(LogEvent::SortWork {
+                work: (budgetInit - budget) as u32,
+                block_size: nblock as u32,
+            });
         }
 
         if budget < 0 {
             if verbosity >= 2 {
-                debug_logln!("    too repetitive; using fallback sorting algorithm");
+                log.emit(LogEvent::SortFallback);
             }
 
-            fallbackSort(ptr, arr2, ftab, nblock, verbosity);
+            fallbackSort(ptr, arr2, ftab, nblock, verbosity, log)?;
         }
     }
+
+    Ok(())
 }
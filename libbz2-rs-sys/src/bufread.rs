@@ -0,0 +1,341 @@
+//! Adapters that decompress data pulled from a [`BufRead`] implementation.
+//!
+//! Unlike the adapters in [`crate::read`], these only consume the bytes of the underlying reader
+//! that are part of the compressed data. After the end of the stream, any remaining data can be
+//! read from the underlying reader directly.
+
+use std::io::{self, BufRead, Read};
+
+use crate::{bz_decompress_limits, DecompressMode, Decompressor, Error, Status};
+
+/// The state shared by the decoders that read from a buffered source.
+///
+/// The caller fills the buffer, passes its contents to [`StreamDecoder::decode`], and consumes
+/// the reported number of bytes. Nothing is consumed after the end of the last stream.
+pub(crate) struct StreamDecoder {
+    mode: DecompressMode,
+    limits: bz_decompress_limits,
+    /// The decompressor, which is kept from one stream to the next so that the limits apply to
+    /// all of them together.
+    decompressor: Option<Decompressor>,
+    /// Whether a stream was started and has not ended yet.
+    in_stream: bool,
+    /// Whether another stream may follow after the end of a stream.
+    multi: bool,
+    /// The number of streams that were started so far.
+    streams: u64,
+    done: bool,
+}
+
+impl StreamDecoder {
+    pub(crate) fn new(mode: DecompressMode, limits: bz_decompress_limits, multi: bool) -> Self {
+        Self {
+            mode,
+            limits,
+            decompressor: None,
+            in_stream: false,
+            multi,
+            streams: 0,
+            done: false,
+        }
+    }
+
+    /// Whether the decoder is finished, without looking at more input.
+    pub(crate) fn is_done(&mut self) -> bool {
+        if !self.in_stream && self.streams > 0 && !self.multi {
+            self.finish();
+        }
+
+        self.done
+    }
+
+    /// Stops decoding, and frees the decompressor.
+    fn finish(&mut self) {
+        self.in_stream = false;
+        self.decompressor = None;
+        self.done = true;
+    }
+
+    /// Decompresses from `input`, which is empty only at the end of the input, into `out`.
+    ///
+    /// Returns the number of bytes of `input` to consume, and the number of bytes written to
+    /// `out` if that should be returned to the caller. `None` means the decoder made progress
+    /// without producing output, and should be called again with fresh input.
+    pub(crate) fn decode(
+        &mut self,
+        input: &[u8],
+        out: &mut [u8],
+    ) -> io::Result<(usize, Option<usize>)> {
+        if self.done || out.is_empty() {
+            return Ok((0, Some(0)));
+        }
+
+        if !self.in_stream {
+            // the previous stream ended, the same check as in `uncompressStream`
+            if self.streams > 0 && input.is_empty() {
+                self.finish();
+                return Ok((0, Some(0)));
+            }
+
+            if let Some(decompressor) = &mut self.decompressor {
+                decompressor.next_stream();
+            }
+
+            self.streams += 1;
+            self.in_stream = true;
+        }
+
+        let decompressor = match &mut self.decompressor {
+            Some(decompressor) => decompressor,
+            None => {
+                let decompressor = Decompressor::with_limits(self.mode, self.limits)?;
+                self.decompressor.insert(decompressor)
+            }
+        };
+
+        match decompressor.decompress(input, out) {
+            Ok(progress) => {
+                if progress.status == Status::StreamEnd {
+                    self.in_stream = false;
+                } else if progress.produced == 0 && input.is_empty() {
+                    return Err(Error::UnexpectedEof.into());
+                }
+
+                let produced = (progress.produced > 0).then_some(progress.produced);
+                Ok((progress.consumed, produced))
+            }
+            Err(Error::DataMagic) if self.streams > 1 => {
+                // trailing garbage after the last stream is ignored
+                self.finish();
+                Ok((0, Some(0)))
+            }
+            Err(error) => {
+                self.finish();
+                Err(error.into())
+            }
+        }
+    }
+}
+
+/// Decompresses a single bzip2 stream read from an underlying [`BufRead`] implementation.
+///
+/// The decoder stops exactly at the end of the stream: the bytes after it are left in the
+/// underlying reader, and can be retrieved with [`BzDecoder::get_mut`] or [`BzDecoder::into_inner`].
+pub struct BzDecoder<R> {
+    reader: R,
+    state: StreamDecoder,
+}
+
+impl<R: BufRead> BzDecoder<R> {
+    /// Creates a decoder that reads compressed data from `reader`, using [`DecompressMode::Fast`].
+    pub fn new(reader: R) -> Self {
+        Self::with_mode(reader, DecompressMode::Fast)
+    }
+
+    /// Creates a decoder that reads compressed data from `reader`, using the given `mode`.
+    pub fn with_mode(reader: R, mode: DecompressMode) -> Self {
+        Self::with_limits(reader, mode, bz_decompress_limits::default())
+    }
+
+    /// Creates a decoder that fails with [`Error::LimitExceeded`] when decompression would cross
+    /// one of the `limits`.
+    ///
+    /// See [`Decompressor::with_limits`].
+    pub fn with_limits(reader: R, mode: DecompressMode, limits: bz_decompress_limits) -> Self {
+        Self {
+            reader,
+            state: StreamDecoder::new(mode, limits, false),
+        }
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Reading from the underlying reader before the end of the stream will corrupt the
+    /// decompressed output.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Unwraps this decoder, returning the underlying reader.
+    ///
+    /// After the end of the stream, the reader is positioned at the first byte after it.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: BufRead> Read for BzDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.state.is_done() {
+                return Ok(0);
+            }
+
+            let input = self.reader.fill_buf()?;
+            let (consumed, produced) = self.state.decode(input, out)?;
+            self.reader.consume(consumed);
+
+            if let Some(produced) = produced {
+                return Ok(produced);
+            }
+        }
+    }
+}
+
+/// Decompresses a concatenation of bzip2 streams read from an underlying [`BufRead`]
+/// implementation.
+///
+/// This yields the concatenation of the decompressed streams, like the `bzip2` binary does. Data
+/// after the last stream that does not begin with the bzip2 magic bytes is ignored, but only once
+/// at least one stream has been decoded successfully.
+pub struct MultiBzDecoder<R>(BzDecoder<R>);
+
+impl<R: BufRead> MultiBzDecoder<R> {
+    /// Creates a decoder that reads compressed data from `reader`, using [`DecompressMode::Fast`].
+    pub fn new(reader: R) -> Self {
+        Self::with_mode(reader, DecompressMode::Fast)
+    }
+
+    /// Creates a decoder that reads compressed data from `reader`, using the given `mode`.
+    pub fn with_mode(reader: R, mode: DecompressMode) -> Self {
+        Self::with_limits(reader, mode, bz_decompress_limits::default())
+    }
+
+    /// Creates a decoder that fails with [`Error::LimitExceeded`] when decompression would cross
+    /// one of the `limits`.
+    ///
+    /// The limits apply to all of the streams together, so a concatenation of many streams that
+    /// each stay under the limits is still stopped.
+    pub fn with_limits(reader: R, mode: DecompressMode, limits: bz_decompress_limits) -> Self {
+        Self(BzDecoder {
+            reader,
+            state: StreamDecoder::new(mode, limits, true),
+        })
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        self.0.get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Reading from the underlying reader directly will corrupt the decompressed output.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.0.get_mut()
+    }
+
+    /// Unwraps this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.0.into_inner()
+    }
+}
+
+impl<R: BufRead> Read for MultiBzDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.0.read(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::BufReader;
+    use std::vec::Vec;
+
+    use crate::test_data::{SAMPLE1_BZ2, SAMPLE1_REF, SAMPLE2_BZ2, SAMPLE2_REF};
+
+    #[test]
+    fn stops_at_end_of_stream() {
+        let input = [SAMPLE1_BZ2, SAMPLE2_BZ2, b"rest of the container"].concat();
+
+        let mut decoder = BzDecoder::new(input.as_slice());
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, SAMPLE1_REF);
+
+        let rest = decoder.into_inner();
+        assert_eq!(rest, [SAMPLE2_BZ2, b"rest of the container"].concat());
+
+        let mut decoder = BzDecoder::new(rest);
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, SAMPLE2_REF);
+        assert_eq!(decoder.into_inner(), b"rest of the container");
+    }
+
+    #[test]
+    fn small_buffer() {
+        let input = [SAMPLE1_BZ2, b"rest of the container"].concat();
+
+        let reader = BufReader::with_capacity(17, input.as_slice());
+        let mut decoder = BzDecoder::with_mode(reader, DecompressMode::Small);
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, SAMPLE1_REF);
+
+        let mut rest = Vec::new();
+        decoder.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"rest of the container");
+    }
+
+    #[test]
+    fn multiple_streams() {
+        let input = [SAMPLE1_BZ2, SAMPLE2_BZ2, b"trailing garbage"].concat();
+
+        let mut output = Vec::new();
+        MultiBzDecoder::new(input.as_slice())
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, [SAMPLE1_REF, SAMPLE2_REF].concat());
+    }
+
+    #[test]
+    fn concatenated_bomb() {
+        let mut compressor = crate::Compressor::new(9, 0).unwrap();
+        let mut stream = std::vec![0u8; 4096];
+        let progress = compressor
+            .compress(&std::vec![0u8; 100_000], &mut stream, crate::Action::Finish)
+            .unwrap();
+        stream.truncate(progress.produced);
+        let input = stream.repeat(50);
+
+        let limits = bz_decompress_limits {
+            max_output: 1_000_000,
+            max_blocks: 20,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let error = MultiBzDecoder::with_limits(input.as_slice(), DecompressMode::Fast, limits)
+            .read_to_end(&mut output)
+            .unwrap_err();
+        assert_eq!(Error::from(error), Error::LimitExceeded);
+        assert!(output.len() <= 1_000_000);
+
+        // the same limits let a short concatenation through
+        let input = stream.repeat(5);
+        let mut output = Vec::new();
+        MultiBzDecoder::with_limits(input.as_slice(), DecompressMode::Fast, limits)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output.len(), 500_000);
+    }
+
+    #[test]
+    fn truncated() {
+        let truncated = &SAMPLE1_BZ2[..SAMPLE1_BZ2.len() - 10];
+
+        let mut output = Vec::new();
+        let error = BzDecoder::new(truncated)
+            .read_to_end(&mut output)
+            .unwrap_err();
+        assert_eq!(Error::from(error), Error::UnexpectedEof);
+    }
+}
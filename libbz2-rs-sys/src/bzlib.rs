@@ -5,10 +5,12 @@ use core::{mem, ptr};
 use crate::allocator::Allocator;
 use crate::compress::compress_block;
 use crate::crctable::BZ2_CRC32TABLE;
-use crate::debug_log;
 use crate::decompress::{self, decompress};
+use crate::decompressor::Damage;
 #[cfg(feature = "stdio")]
 use crate::libbz2_rs_sys_version;
+use crate::logging::{bz_log_callback, LogEvent, LogSink};
+use crate::{handle_assert_failure, InternalError};
 
 #[cfg(feature = "stdio")]
 pub use crate::high_level::*;
@@ -34,8 +36,9 @@ pub(crate) const BZ_MAX_UNUSED_U32: u32 = 5000;
 #[cfg(doc)]
 use crate::{
     BZ_CONFIG_ERROR, BZ_DATA_ERROR, BZ_DATA_ERROR_MAGIC, BZ_FINISH, BZ_FINISH_OK, BZ_FLUSH,
-    BZ_FLUSH_OK, BZ_IO_ERROR, BZ_MEM_ERROR, BZ_OK, BZ_OUTBUFF_FULL, BZ_PARAM_ERROR, BZ_RUN,
-    BZ_RUN_OK, BZ_SEQUENCE_ERROR, BZ_STREAM_END, BZ_UNEXPECTED_EOF,
+    BZ_FLUSH_OK, BZ_INTERNAL_ERROR, BZ_IO_ERROR, BZ_LIMIT_EXCEEDED, BZ_MEM_ERROR, BZ_OK,
+    BZ_OUTBUFF_FULL, BZ_PARAM_ERROR, BZ_RUN, BZ_RUN_OK, BZ_SEQUENCE_ERROR, BZ_STREAM_END,
+    BZ_UNEXPECTED_EOF,
 };
 
 #[cfg(feature = "custom-prefix")]
@@ -159,6 +162,122 @@ pub struct bz_stream {
     pub opaque: *mut c_void,
 }
 
+/// Limits on the resources that decompressing a single stream may use.
+///
+/// Highly repetitive data compresses extremely well, so a small compressed stream can decompress
+/// to an enormous amount of data. When decompressing untrusted data, these limits stop
+/// decompression with [`BZ_LIMIT_EXCEEDED`] before the output gets out of hand. The memory limit
+/// is checked when the stream header is read, before the buffers for its block size are allocated.
+///
+/// A value of zero means that there is no limit. The limits count from initialization, or from
+/// [`BZ2_bzDecompressReset`]. A concatenation of streams can be limited as a whole by continuing
+/// with [`BZ2_bzDecompressNextStream`] after each [`BZ_STREAM_END`]: the output, the expansion
+/// ratio and the number of blocks then add up over all of the streams.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct bz_decompress_limits {
+    /// The maximum number of bytes that the streams may decompress to.
+    pub max_output: u64,
+    /// The maximum ratio between the number of bytes produced and the number of bytes consumed.
+    pub max_ratio: c_uint,
+    /// The maximum number of blocks in the streams.
+    pub max_blocks: c_uint,
+    /// The maximum number of bytes that the state of the stream may allocate, as computed by
+    /// [`BZ2_bzDecompressMemoryUsage`] for the block size in the stream header.
+    pub max_memory: u64,
+    /// When nonzero, a stream that needs more than `max_memory` in the fast mode is decompressed in
+    /// the small mode instead, if that fits. Otherwise such a stream fails.
+    pub fallback_to_small: c_uint,
+}
+
+impl bz_decompress_limits {
+    /// The number of bytes that may still be produced, or `None` when the output is not limited.
+    fn output_budget(&self, total_in: u64, total_out: u64) -> Option<u64> {
+        let max_output = match self.max_output {
+            0 => u64::MAX,
+            max_output => max_output,
+        };
+        let max_expanded = match self.max_ratio {
+            0 => u64::MAX,
+            max_ratio => total_in.saturating_mul(u64::from(max_ratio)),
+        };
+
+        match Ord::min(max_output, max_expanded) {
+            u64::MAX => None,
+            max => Some(max.saturating_sub(total_out)),
+        }
+    }
+}
+
+/// Progress information about a completed block, passed to a [`bz_block_callback`].
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct bz_block_info {
+    /// The number of the block within its stream, starting at 1.
+    pub block: c_uint,
+    /// The number of uncompressed bytes in the block.
+    pub uncompressed: u64,
+    /// The number of bits of the compressed block, starting at its block header.
+    pub compressed_bits: u64,
+    /// The CRC of the uncompressed data of the block.
+    pub crc: c_uint,
+    /// The time spent sorting the block, in nanoseconds.
+    ///
+    /// This is always zero during decompression, and when the `std` feature is disabled.
+    pub sort_nanos: u64,
+}
+
+/// A function that is called with the `opaque` pointer it was registered with, after every block
+/// that is completed.
+///
+/// See [`BZ2_bzCompressSetBlockCallback`] and [`BZ2_bzDecompressSetBlockCallback`]. The `info`
+/// pointer is only valid for the duration of the call. The callback must not call back into the
+/// library with the same stream.
+#[allow(non_camel_case_types)]
+pub type bz_block_callback = unsafe extern "C" fn(opaque: *mut c_void, info: *const bz_block_info);
+
+/// A registered [`bz_block_callback`], and the `opaque` pointer that it is called with.
+#[derive(Clone, Copy)]
+pub(crate) struct BlockCallback {
+    callback: Option<bz_block_callback>,
+    opaque: *mut c_void,
+}
+
+impl BlockCallback {
+    const NONE: Self = Self {
+        callback: None,
+        opaque: ptr::null_mut(),
+    };
+
+    fn notify(&self, info: bz_block_info) {
+        if let Some(callback) = self.callback {
+            // SAFETY: the caller that registered the callback guarantees that it can be called
+            // with its opaque pointer
+            unsafe { callback(self.opaque, &info) };
+        }
+    }
+}
+
+/// Wraps a rust closure, so that it can be registered as a [`bz_block_callback`].
+#[cfg(feature = "std")]
+pub(crate) type BoxedBlockCallback = std::boxed::Box<dyn FnMut(&bz_block_info) + Send>;
+
+/// Calls the [`BoxedBlockCallback`] that `opaque` points to.
+///
+/// A panic in the closure can't unwind through the C interface, and aborts the process.
+#[cfg(feature = "std")]
+pub(crate) unsafe extern "C" fn call_boxed_block_callback(
+    opaque: *mut c_void,
+    info: *const bz_block_info,
+) {
+    // SAFETY: the opaque pointer was registered together with this function, and points to a
+    // boxed closure that lives as long as the stream
+    let callback = unsafe { &mut *opaque.cast::<BoxedBlockCallback>() };
+    callback(unsafe { &*info });
+}
+
 pub(crate) use stream::*;
 mod stream {
     use super::*;
@@ -425,6 +544,36 @@ pub(crate) enum ReturnCode {
     BZ_UNEXPECTED_EOF = -7,
     BZ_OUTBUFF_FULL = -8,
     BZ_CONFIG_ERROR = -9,
+    BZ_LIMIT_EXCEEDED = -10,
+    BZ_INTERNAL_ERROR = -11,
+}
+
+/// The non-error outcomes of compression and decompression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Corresponds to [`BZ_OK`].
+    Ok,
+    /// Corresponds to [`BZ_RUN_OK`].
+    RunOk,
+    /// Corresponds to [`BZ_FLUSH_OK`].
+    FlushOk,
+    /// Corresponds to [`BZ_FINISH_OK`].
+    FinishOk,
+    /// Corresponds to [`BZ_STREAM_END`].
+    StreamEnd,
+}
+
+impl Status {
+    pub(crate) fn from_return_code(return_code: ReturnCode) -> Option<Self> {
+        match return_code {
+            ReturnCode::BZ_OK => Some(Self::Ok),
+            ReturnCode::BZ_RUN_OK => Some(Self::RunOk),
+            ReturnCode::BZ_FLUSH_OK => Some(Self::FlushOk),
+            ReturnCode::BZ_FINISH_OK => Some(Self::FinishOk),
+            ReturnCode::BZ_STREAM_END => Some(Self::StreamEnd),
+            _ => None,
+        }
+    }
 }
 
 #[repr(u8)]
@@ -482,6 +631,15 @@ pub(crate) struct EState {
     pub code: [[u32; 258]; 6],
     pub rfreq: [[i32; 258]; 6],
     pub len_pack: [[u32; 4]; 258],
+    pub blockCallback: BlockCallback,
+    /// The number of input bytes that went into the blocks before the current one.
+    pub blockStartIn: u64,
+    pub logSink: LogSink,
+    /// Whether a failed internal check poisons the stream, instead of terminating the process.
+    pub recoverable: bool,
+    /// The failed internal check that poisoned the stream.
+    pub internalError: Option<InternalError>,
+    pub blockSorter: BlockSorter,
 }
 
 /// Creates a new pointer that is dangling, but well-aligned.
@@ -582,6 +740,18 @@ impl Arr2 {
 
         (block, quadrant)
     }
+
+    /// The block, and the whole words after it as scratch space.
+    pub(crate) fn block_and_workspace(&mut self, nblock: usize) -> (&mut [u8], &mut [u32]) {
+        let words = nblock.div_ceil(4);
+        assert!(words <= self.len);
+
+        let block = unsafe { core::slice::from_raw_parts_mut(self.ptr.cast(), nblock) };
+        let workspace =
+            unsafe { core::slice::from_raw_parts_mut(self.ptr.add(words), self.len - words) };
+
+        (block, workspace)
+    }
 }
 
 pub(crate) struct Ftab {
@@ -625,7 +795,10 @@ pub(crate) struct DState {
     pub bsLive: i32,
     pub rNToGo: u16,
     pub rTPos: u16,
+    /// The mode of the current stream, which `limits.fallback_to_small` can switch to small.
     pub smallDecompress: DecompressMode,
+    /// The mode that the stream was initialized with.
+    pub initMode: DecompressMode,
     pub currBlockNo: i32,
     pub verbosity: i32,
     pub origPtr: i32,
@@ -655,6 +828,25 @@ pub(crate) struct DState {
     pub perm: [[u16; 258]; 6],
     pub minLens: [u8; 6],
     pub save: SaveArea,
+    pub blockPause: decompress::BlockPause,
+    pub limits: bz_decompress_limits,
+    /// The input, output and blocks of the earlier streams that count towards the limits.
+    pub limitedIn: u64,
+    pub limitedOut: u64,
+    pub limitedBlocks: u32,
+    pub skipOutput: bool,
+    pub lenient: bool,
+    pub damage: Option<Damage>,
+    pub damagedBlocks: u32,
+    pub resyncBuff: u64,
+    pub blockCallback: BlockCallback,
+    /// The bit position of the header of the current block.
+    pub blockStartBit: u64,
+    /// The number of bits of the current block, known once its output starts.
+    pub blockBits: u64,
+    /// The number of bytes that were produced before the output of the current block.
+    pub blockStartOut: u64,
+    pub logSink: LogSink,
 }
 
 #[derive(Default)]
@@ -707,6 +899,33 @@ impl<T> DSlice<T> {
         }
     }
 
+    /// Replaces the slice with a zeroed one of `len` elements, unless it already has that length.
+    ///
+    /// Returns `false` when the allocation fails, leaving the slice empty.
+    ///
+    /// # Safety
+    ///
+    /// The current slice must be empty, or have been allocated with `allocator`.
+    pub(crate) unsafe fn ensure_len(&mut self, allocator: &Allocator, len: usize) -> bool {
+        if self.len == len {
+            return true;
+        }
+
+        // SAFETY: the caller guarantees that the slice was allocated with `allocator`
+        unsafe { self.dealloc(allocator) };
+        if len == 0 {
+            return true;
+        }
+
+        match Self::alloc(allocator, len) {
+            Some(slice) => {
+                *self = slice;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub(crate) fn as_slice(&self) -> &[T] {
         unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
     }
@@ -716,6 +935,35 @@ impl<T> DSlice<T> {
     }
 }
 
+impl DState {
+    /// Sizes the buffers of the inverse BWT for the block size and mode of the current stream.
+    ///
+    /// A stream that was reset keeps the buffers of the previous stream, which are reused when the
+    /// mode and block size are the same. Returns `false` when an allocation fails.
+    ///
+    /// `allocator` is the allocator of the stream that owns this state.
+    pub(crate) fn ensure_block_buffers(&mut self, allocator: &Allocator) -> bool {
+        let len = usize::from(self.blockSize100k) * 100000;
+
+        // SAFETY: the buffers start out empty, and are only allocated here and freed in
+        // `BZ2_bzDecompressEnd`, both times with the allocator of the stream
+        unsafe {
+            match self.smallDecompress {
+                DecompressMode::Small => {
+                    self.tt.ensure_len(allocator, 0)
+                        && self.ll16.ensure_len(allocator, len)
+                        && self.ll4.ensure_len(allocator, (1 + len) >> 1)
+                }
+                DecompressMode::Fast => {
+                    self.ll16.ensure_len(allocator, 0)
+                        && self.ll4.ensure_len(allocator, 0)
+                        && self.tt.ensure_len(allocator, len)
+                }
+            }
+        }
+    }
+}
+
 const _C_INT_SIZE: () = assert!(core::mem::size_of::<core::ffi::c_int>() == 4);
 const _C_SHORT_SIZE: () = assert!(core::mem::size_of::<core::ffi::c_short>() == 2);
 const _C_CHAR_SIZE: () = assert!(core::mem::size_of::<core::ffi::c_char>() == 1);
@@ -799,12 +1047,9 @@ pub(crate) fn BZ2_bzCompressInitHelp(
     // that a given state belongs to a given strm.
     unsafe { (*s).strm_addr = strm as *const _ as usize }; // FIXME use .addr() once stable
 
-    let n = 100000 * blockSize100k;
+    let (arr1_len, arr2_len) = compress_buffer_lens(blockSize100k as usize);
 
-    let arr1_len = n as usize;
     let arr1 = Arr1::alloc(&allocator, arr1_len);
-
-    let arr2_len = n as usize + (2 + 12 + 18 + 2);
     let arr2 = Arr2::alloc(&allocator, arr2_len);
 
     let ftab = Ftab::alloc(&allocator);
@@ -834,24 +1079,68 @@ pub(crate) fn BZ2_bzCompressInitHelp(
         }
     };
 
+    unsafe {
+        (*s).blockCallback = BlockCallback::NONE;
+        (*s).logSink = LogSink::Default;
+        (*s).recoverable = false;
+        (*s).internalError = None;
+        (*s).blockSorter = BlockSorter::Reference;
+    }
+
     strm.state = s;
 
     // safety: the EState has now been sufficiently initialized; the allocator zeroes the memory,
-    // and the only fields where zero is not a valid value are the arrays that were just set
+    // and the fields where zero is not known to be a valid value were written above
     //
     // note in particular that if the discriminant of the first variant of an enum is unspecified,
-    // then it is set to zero.
+    // then it is set to zero, so a zeroed `Mode` or `State` is valid.
     let s = unsafe { &mut *s };
 
-    s.blockNo = 0;
-    s.state = State::Output;
-    s.mode = Mode::Running;
-    s.combinedCRC = 0;
     s.blockSize100k = blockSize100k;
     s.nblockMAX = 100000 * blockSize100k - 19;
     s.verbosity = verbosity;
     s.workFactor = workFactor;
 
+    start_compress_stream(strm, s);
+
+    ReturnCode::BZ_OK
+}
+
+/// The number of `u32` elements of `arr1` and `arr2` for a block size.
+fn compress_buffer_lens(blockSize100k: usize) -> (usize, usize) {
+    let n = 100000 * blockSize100k;
+    (n, n + (2 + 12 + 18 + 2))
+}
+
+/// Gives the number of bytes that [`BZ2_bzCompressInit`] allocates for a block size.
+///
+/// This is the sum of the sizes that are requested from the allocator: the state of the stream and
+/// the buffers for block sorting. Nothing else is allocated while compressing.
+///
+/// # Returns
+///
+/// - 0 if `!(1..=9).contains(&blockSize100k)`
+/// - the number of bytes otherwise
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressMemoryUsage))]
+pub extern "C" fn BZ2_bzCompressMemoryUsage(blockSize100k: c_int) -> usize {
+    if !(1..=9).contains(&blockSize100k) {
+        return 0;
+    }
+
+    let (arr1_len, arr2_len) = compress_buffer_lens(blockSize100k as usize);
+    mem::size_of::<EState>() + (arr1_len + arr2_len + FTAB_LEN) * mem::size_of::<u32>()
+}
+
+/// Puts the stream at the start of a new compressed stream, keeping its buffers and settings.
+fn start_compress_stream(strm: &mut BzStream<EState>, s: &mut EState) {
+    s.blockNo = 0;
+    s.state = State::Output;
+    s.mode = Mode::Running;
+    s.avail_in_expect = 0;
+    s.combinedCRC = 0;
+    s.blockStartIn = 0;
+    s.internalError = None;
+
     strm.total_in_lo32 = 0;
     strm.total_in_hi32 = 0;
     strm.total_out_lo32 = 0;
@@ -859,6 +1148,49 @@ pub(crate) fn BZ2_bzCompressInitHelp(
 
     init_rl(s);
     prepare_new_block(s);
+}
+
+/// Makes the stream ready to compress a new stream, without freeing and reallocating its memory.
+///
+/// This is equivalent to [`BZ2_bzCompressEnd`] followed by [`BZ2_bzCompressInit`] with the same
+/// parameters, but keeps the block sorting buffers, which take about 8 times `blockSize100k`
+/// times 100000 bytes. Any data that was given to the stream but not finished is discarded.
+///
+/// The settings made with [`BZ2_bzCompressSetBlockCallback`], [`BZ2_bzCompressSetLogCallback`],
+/// [`BZ2_bzCompressSetRecoverable`] and [`BZ2_bzCompressSetBlockSorter`] are kept. A stream that
+/// was poisoned by a failed internal check can be used again after a reset.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzCompressInit`]
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressReset))]
+pub unsafe extern "C" fn BZ2_bzCompressReset(strm: *mut bz_stream) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    BZ2_bzCompressResetHelp(strm) as c_int
+}
+
+pub(crate) fn BZ2_bzCompressResetHelp(strm: &mut BzStream<EState>) -> ReturnCode {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
+    }
+
+    start_compress_stream(strm, s);
 
     ReturnCode::BZ_OK
 }
@@ -991,7 +1323,33 @@ fn copy_output_until_stop(strm: &mut BzStream<EState>, s: &mut EState) -> bool {
     progress_out
 }
 
-fn handle_compress(strm: &mut BzStream<EState>, s: &mut EState) -> bool {
+/// Compresses the current block, and reports it to the block callback.
+fn complete_block(
+    strm: &BzStream<EState>,
+    s: &mut EState,
+    is_last_block: bool,
+) -> Result<(), InternalError> {
+    let total_in = (u64::from(strm.total_in_hi32) << 32) | u64::from(strm.total_in_lo32);
+
+    // the bytes of a run that is still being counted go into the next block
+    let pending = match isempty_rl(s) {
+        true => 0,
+        false => s.state_in_len as u64,
+    };
+    let uncompressed = total_in - pending - s.blockStartIn;
+    s.blockStartIn += uncompressed;
+
+    if let Some(info) = compress_block(s, is_last_block)? {
+        s.blockCallback.notify(bz_block_info {
+            uncompressed,
+            ..info
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_compress(strm: &mut BzStream<EState>, s: &mut EState) -> Result<bool, InternalError> {
     let mut progress_in = false;
     let mut progress_out = false;
 
@@ -1017,22 +1375,29 @@ fn handle_compress(strm: &mut BzStream<EState>, s: &mut EState) -> bool {
         if !matches!(s.mode, Mode::Running) && s.avail_in_expect == 0 {
             flush_rl(s);
             let is_last_block = matches!(s.mode, Mode::Finishing);
-            compress_block(s, is_last_block);
+            complete_block(strm, s, is_last_block)?;
             s.state = State::Input;
         } else if s.nblock >= s.nblockMAX {
-            compress_block(s, false);
+            complete_block(strm, s, false)?;
             s.state = State::Input;
         } else if strm.avail_in == 0 {
             break;
         }
     }
 
-    progress_in || progress_out
+    Ok(progress_in || progress_out)
 }
 
-pub(crate) enum Action {
+/// The action to perform on a compression stream.
+///
+/// The numeric values correspond to [`BZ_RUN`], [`BZ_FLUSH`] and [`BZ_FINISH`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Compress as much input as possible.
     Run = 0,
+    /// Compress all input supplied so far, and end the current block.
     Flush = 1,
+    /// Compress all input supplied so far, and write the end-of-stream marker.
     Finish = 2,
 }
 
@@ -1065,6 +1430,8 @@ impl TryFrom<i32> for Action {
 /// - [`BZ_FINISH_OK`] if all input has been read but not all output has been written to the output
 ///   buffer yet
 /// - [`BZ_STREAM_END`] if all input has been read all output has been written to the output buffer
+/// - [`BZ_INTERNAL_ERROR`] if an internal check failed now or in an earlier call, and the stream
+///   was made recoverable with [`BZ2_bzCompressSetRecoverable`]
 ///
 /// # Safety
 ///
@@ -1096,21 +1463,36 @@ pub(crate) fn BZ2_bzCompressHelp(strm: &mut BzStream<EState>, action: i32) -> Re
         return ReturnCode::BZ_PARAM_ERROR;
     }
 
-    compress_loop(strm, s, action)
+    if s.internalError.is_some() {
+        return ReturnCode::BZ_INTERNAL_ERROR;
+    }
+
+    match compress_loop(strm, s, action) {
+        Ok(ret) => ret,
+        Err(error) if s.recoverable => {
+            s.internalError = Some(error);
+            ReturnCode::BZ_INTERNAL_ERROR
+        }
+        Err(error) => handle_assert_failure(error),
+    }
 }
 
-fn compress_loop(strm: &mut BzStream<EState>, s: &mut EState, action: i32) -> ReturnCode {
+fn compress_loop(
+    strm: &mut BzStream<EState>,
+    s: &mut EState,
+    action: i32,
+) -> Result<ReturnCode, InternalError> {
     loop {
         match s.mode {
-            Mode::Idle => return ReturnCode::BZ_SEQUENCE_ERROR,
+            Mode::Idle => return Ok(ReturnCode::BZ_SEQUENCE_ERROR),
             Mode::Running => match Action::try_from(action) {
                 Ok(Action::Run) => {
-                    let progress = handle_compress(strm, s);
-                    return if progress {
+                    let progress = handle_compress(strm, s)?;
+                    return Ok(if progress {
                         ReturnCode::BZ_RUN_OK
                     } else {
                         ReturnCode::BZ_PARAM_ERROR
-                    };
+                    });
                 }
                 Ok(Action::Flush) => {
                     s.avail_in_expect = strm.avail_in;
@@ -1121,60 +1503,62 @@ fn compress_loop(strm: &mut BzStream<EState>, s: &mut EState, action: i32) -> Re
                     s.mode = Mode::Finishing;
                 }
                 Err(()) => {
-                    return ReturnCode::BZ_PARAM_ERROR;
+                    return Ok(ReturnCode::BZ_PARAM_ERROR);
                 }
             },
             Mode::Flushing => {
                 let Ok(Action::Flush) = Action::try_from(action) else {
-                    return ReturnCode::BZ_SEQUENCE_ERROR;
+                    return Ok(ReturnCode::BZ_SEQUENCE_ERROR);
                 };
                 if s.avail_in_expect != strm.avail_in {
-                    return ReturnCode::BZ_SEQUENCE_ERROR;
+                    return Ok(ReturnCode::BZ_SEQUENCE_ERROR);
                 }
-                handle_compress(strm, s);
+                handle_compress(strm, s)?;
                 if s.avail_in_expect > 0
                     || !isempty_rl(s)
                     || s.state_out_pos < s.writer.num_z as i32
                 {
-                    return ReturnCode::BZ_FLUSH_OK;
+                    return Ok(ReturnCode::BZ_FLUSH_OK);
                 }
                 s.mode = Mode::Running;
-                return ReturnCode::BZ_RUN_OK;
+                return Ok(ReturnCode::BZ_RUN_OK);
             }
             Mode::Finishing => {
                 let Ok(Action::Finish) = Action::try_from(action) else {
                     // unreachable in practice
-                    return ReturnCode::BZ_SEQUENCE_ERROR;
+                    return Ok(ReturnCode::BZ_SEQUENCE_ERROR);
                 };
                 if s.avail_in_expect != strm.avail_in {
                     // unreachable in practice
-                    return ReturnCode::BZ_SEQUENCE_ERROR;
+                    return Ok(ReturnCode::BZ_SEQUENCE_ERROR);
                 }
-                let progress = handle_compress(strm, s);
+                let progress = handle_compress(strm, s)?;
                 if !progress {
-                    return ReturnCode::BZ_SEQUENCE_ERROR;
+                    return Ok(ReturnCode::BZ_SEQUENCE_ERROR);
                 }
                 if s.avail_in_expect > 0
                     || !isempty_rl(s)
                     || s.state_out_pos < s.writer.num_z as i32
                 {
-                    return ReturnCode::BZ_FINISH_OK;
+                    return Ok(ReturnCode::BZ_FINISH_OK);
                 }
                 s.mode = Mode::Idle;
-                return ReturnCode::BZ_STREAM_END;
+                return Ok(ReturnCode::BZ_STREAM_END);
             }
         }
     }
 }
 
-/// Deallocates all dynamically allocated data structures for this stream.
+/// Registers a `callback` that is called after each block is compressed, replacing any earlier one.
+///
+/// The callback receives `opaque` and a [`bz_block_info`] describing the block. A `NULL` callback
+/// removes the registered one.
 ///
 /// # Returns
 ///
 /// - [`BZ_PARAM_ERROR`] if any of
 ///     - `strm.is_null()`
 ///     - `strm.s.is_null()`
-///     - no [valid allocator](bz_stream#custom-allocators) could be configured
 /// - [`BZ_OK`] otherwise
 ///
 /// # Safety
@@ -1182,136 +1566,645 @@ fn compress_loop(strm: &mut BzStream<EState>, s: &mut EState, action: i32) -> Re
 /// * Either
 ///     - `strm` is `NULL`
 ///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzCompressInit`]
-#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressEnd))]
-pub unsafe extern "C" fn BZ2_bzCompressEnd(strm: *mut bz_stream) -> c_int {
+/// * `callback` can be called with `opaque` for as long as it is registered
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressSetBlockCallback))]
+pub unsafe extern "C" fn BZ2_bzCompressSetBlockCallback(
+    strm: *mut bz_stream,
+    callback: Option<bz_block_callback>,
+    opaque: *mut c_void,
+) -> c_int {
     let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
         return ReturnCode::BZ_PARAM_ERROR as c_int;
     };
-    BZ2_bzCompressEndHelp(strm)
+    BZ2_bzCompressSetBlockCallbackHelp(strm, callback, opaque) as c_int
 }
 
-fn BZ2_bzCompressEndHelp(strm: &mut BzStream<EState>) -> c_int {
+pub(crate) fn BZ2_bzCompressSetBlockCallbackHelp(
+    strm: &mut BzStream<EState>,
+    callback: Option<bz_block_callback>,
+    opaque: *mut c_void,
+) -> ReturnCode {
     let Some(s) = (unsafe { strm.state.as_mut() }) else {
-        return ReturnCode::BZ_PARAM_ERROR as c_int;
+        return ReturnCode::BZ_PARAM_ERROR;
     };
 
     // FIXME use .addr() once stable
     if s.strm_addr != strm as *mut _ as usize {
-        return ReturnCode::BZ_PARAM_ERROR as c_int;
+        return ReturnCode::BZ_PARAM_ERROR;
     }
 
-    let Some(allocator) = strm.allocator() else {
+    s.blockCallback = BlockCallback { callback, opaque };
+
+    ReturnCode::BZ_OK
+}
+
+/// Registers a `callback` that receives the diagnostic events of the stream, replacing any earlier
+/// one.
+///
+/// Events are only emitted when the stream was initialized with a `verbosity` of 2 or more. A
+/// `NULL` callback restores the default, which writes the events to stderr.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzCompressInit`]
+/// * `callback` can be called with `opaque` for as long as it is registered
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressSetLogCallback))]
+pub unsafe extern "C" fn BZ2_bzCompressSetLogCallback(
+    strm: *mut bz_stream,
+    callback: Option<bz_log_callback>,
+    opaque: *mut c_void,
+) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
         return ReturnCode::BZ_PARAM_ERROR as c_int;
     };
+    let sink = match callback {
+        Some(callback) => LogSink::Callback { callback, opaque },
+        None => LogSink::Default,
+    };
+    BZ2_bzCompressSetLogSinkHelp(strm, sink) as c_int
+}
 
-    unsafe {
-        s.arr1.dealloc(&allocator);
-        s.arr2.dealloc(&allocator);
-        s.ftab.dealloc(&allocator);
-    }
+pub(crate) fn BZ2_bzCompressSetLogSinkHelp(
+    strm: &mut BzStream<EState>,
+    sink: LogSink,
+) -> ReturnCode {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
 
-    unsafe {
-        allocator.deallocate(strm.state.cast::<EState>(), 1);
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
     }
-    strm.state = ptr::null_mut::<EState>();
 
-    ReturnCode::BZ_OK as c_int
-}
+    s.logSink = sink;
 
-pub(crate) enum DecompressMode {
-    Small,
-    Fast,
+    ReturnCode::BZ_OK
 }
 
-/// Prepares the stream for decompression.
+/// Makes a failed internal consistency check poison the stream, instead of terminating the process.
+///
+/// By default such a failure prints a report to stderr and exits the process with code 3, or
+/// panics without the `std` feature, like the reference implementation. A recoverable stream
+/// instead returns [`BZ_INTERNAL_ERROR`] from this and every later call to [`BZ2_bzCompress`],
+/// and [`BZ2_bzCompressGetInternalError`] gives the report. The stream must still be freed with
+/// [`BZ2_bzCompressEnd`].
+///
+/// Decompression has no such checks, so [`BZ2_bzDecompress`] never returns [`BZ_INTERNAL_ERROR`].
 ///
 /// # Returns
 ///
 /// - [`BZ_PARAM_ERROR`] if any of
 ///     - `strm.is_null()`
-///     - `!(0..=1).contains(&small)`
-///     - `!(0..=4).contains(&verbosity)`
-///     - no [valid allocator](bz_stream#custom-allocators) could be configured
-/// - [`BZ_MEM_ERROR`] if insufficient memory is available
+///     - `strm.s.is_null()`
 /// - [`BZ_OK`] otherwise
 ///
 /// # Safety
 ///
-/// The caller must guarantee that
-///
 /// * Either
 ///     - `strm` is `NULL`
-///     - `strm` satisfies the requirements of `&mut *strm`
-/// * The `bzalloc`, `bzfree` and `opaque` fields form a [valid allocator](bz_stream#custom-allocators).
-#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressInit))]
-pub unsafe extern "C" fn BZ2_bzDecompressInit(
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzCompressInit`]
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressSetRecoverable))]
+pub unsafe extern "C" fn BZ2_bzCompressSetRecoverable(
     strm: *mut bz_stream,
-    verbosity: c_int,
-    small: c_int,
+    recoverable: c_int,
 ) -> c_int {
     let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
         return ReturnCode::BZ_PARAM_ERROR as c_int;
     };
-    BZ2_bzDecompressInitHelp(strm, verbosity, small) as c_int
+    BZ2_bzCompressSetRecoverableHelp(strm, recoverable != 0) as c_int
 }
 
-pub(crate) fn BZ2_bzDecompressInitHelp(
-    strm: &mut BzStream<DState>,
-    verbosity: c_int,
-    small: c_int,
+pub(crate) fn BZ2_bzCompressSetRecoverableHelp(
+    strm: &mut BzStream<EState>,
+    recoverable: bool,
 ) -> ReturnCode {
-    let decompress_mode = match small {
-        0 => DecompressMode::Fast,
-        1 => DecompressMode::Small,
-        _ => return ReturnCode::BZ_PARAM_ERROR,
-    };
-    if !(0..=4).contains(&verbosity) {
-        return ReturnCode::BZ_PARAM_ERROR;
-    }
-
-    // return a param error when no [valid allocator](bz_stream#custom-allocators) could be configured
-    let Some(allocator) = configure_allocator(strm) else {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
         return ReturnCode::BZ_PARAM_ERROR;
     };
 
-    let Some(s) = allocator.allocate_zeroed::<DState>(1) else {
-        return ReturnCode::BZ_MEM_ERROR;
-    };
-
-    // this `s.strm` pointer should _NEVER_ be used! it exists just as a consistency check to ensure
-    // that a given state belongs to a given strm.
-    unsafe { (*s).strm_addr = strm as *const _ as usize }; // FIXME use .addr() once stable
-
-    unsafe {
-        (*s).state = decompress::State::BZ_X_MAGIC_1;
-        (*s).bsLive = 0;
-        (*s).bsBuff = 0;
-        (*s).calculatedCombinedCRC = 0;
-    }
-
-    unsafe {
-        (*s).smallDecompress = decompress_mode;
-        (*s).ll4 = DSlice::new();
-        (*s).ll16 = DSlice::new();
-        (*s).tt = DSlice::new();
-        (*s).currBlockNo = 0;
-        (*s).verbosity = verbosity;
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
     }
 
-    strm.state = s;
-
-    strm.total_in_lo32 = 0;
-    strm.total_in_hi32 = 0;
-    strm.total_out_lo32 = 0;
-    strm.total_out_hi32 = 0;
+    s.recoverable = recoverable;
 
     ReturnCode::BZ_OK
 }
 
-macro_rules! BZ_RAND_MASK {
-    ($s:expr) => {
-        ($s.rNToGo == 1) as u8
-    };
+/// Gives the number of the internal check that poisoned a recoverable stream, or 0.
+///
+/// When `buf` is not `NULL`, the report of the failure is written to it as a nul-terminated string,
+/// truncated to `len` bytes. See [`BZ2_bzCompressSetRecoverable`].
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+/// - 0 if the stream is not poisoned, in which case `buf` is not modified
+/// - the number of the failed check otherwise, e.g. 1007
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzCompressInit`]
+/// * Either
+///     - `buf` is `NULL`
+///     - `buf` is writable for `len` bytes
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressGetInternalError))]
+pub unsafe extern "C" fn BZ2_bzCompressGetInternalError(
+    strm: *mut bz_stream,
+    buf: *mut c_char,
+    len: c_uint,
+) -> c_int {
+    let Some(strm) = (unsafe { BzStream::<EState>::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    }
+
+    let Some(error) = s.internalError else {
+        return 0;
+    };
+
+    if !buf.is_null() && len > 0 {
+        // SAFETY: the caller guarantees that `buf` is writable for `len` bytes
+        let buf = unsafe { core::slice::from_raw_parts_mut(buf.cast::<u8>(), len as usize) };
+        let _ = core::fmt::write(&mut TruncatingWriter::new(buf), format_args!("{error}"));
+    }
+
+    error.code()
+}
+
+/// The algorithm used to sort the rotations of a block during compression.
+///
+/// The choice only affects speed: both produce exactly the same compressed output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockSorter {
+    /// Use the sorts of the reference implementation, which fall back to a slower sort on
+    /// repetitive blocks.
+    Reference,
+    /// Use a linear time suffix array construction (SA-IS).
+    ///
+    /// This is several times faster on highly repetitive blocks, but can be slower on blocks that
+    /// are not repetitive at all, like already compressed data. A block that consists of a shorter
+    /// string repeated over and over still uses the reference sorts, because those decide how its
+    /// identical rotations are ordered.
+    SuffixArray,
+}
+
+/// Selects the algorithm that sorts the blocks of this stream.
+///
+/// `sorter` is 0 for the [reference sorts](BlockSorter::Reference) (the default), or 1 for the
+/// [suffix array sort](BlockSorter::SuffixArray). The compressed output is the same either way,
+/// but the suffix array sort is much faster on highly repetitive input. It can be changed at any
+/// time, and applies from the next block that is sorted.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+///     - `!(0..=1).contains(&sorter)`
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzCompressInit`]
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressSetBlockSorter))]
+pub unsafe extern "C" fn BZ2_bzCompressSetBlockSorter(
+    strm: *mut bz_stream,
+    sorter: c_int,
+) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    let sorter = match sorter {
+        0 => BlockSorter::Reference,
+        1 => BlockSorter::SuffixArray,
+        _ => return ReturnCode::BZ_PARAM_ERROR as c_int,
+    };
+    BZ2_bzCompressSetBlockSorterHelp(strm, sorter) as c_int
+}
+
+pub(crate) fn BZ2_bzCompressSetBlockSorterHelp(
+    strm: &mut BzStream<EState>,
+    sorter: BlockSorter,
+) -> ReturnCode {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
+    }
+
+    s.blockSorter = sorter;
+
+    ReturnCode::BZ_OK
+}
+
+/// Writes a nul-terminated string into a buffer, truncating what doesn't fit.
+pub(crate) struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> TruncatingWriter<'a> {
+    /// Starts with an empty string. The buffer must not be empty.
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        buf[0] = 0;
+        Self { buf, len: 0 }
+    }
+}
+
+impl core::fmt::Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // keep the last byte for the nul terminator
+        let n = Ord::min(s.len(), self.buf.len() - 1 - self.len);
+        self.buf[self.len..][..n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        self.buf[self.len] = 0;
+        Ok(())
+    }
+}
+
+/// Deallocates all dynamically allocated data structures for this stream.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+///     - no [valid allocator](bz_stream#custom-allocators) could be configured
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzCompressInit`]
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzCompressEnd))]
+pub unsafe extern "C" fn BZ2_bzCompressEnd(strm: *mut bz_stream) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    BZ2_bzCompressEndHelp(strm)
+}
+
+pub(crate) fn BZ2_bzCompressEndHelp(strm: &mut BzStream<EState>) -> c_int {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    }
+
+    let Some(allocator) = strm.allocator() else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+
+    unsafe {
+        s.arr1.dealloc(&allocator);
+        s.arr2.dealloc(&allocator);
+        s.ftab.dealloc(&allocator);
+    }
+
+    unsafe {
+        allocator.deallocate(strm.state.cast::<EState>(), 1);
+    }
+    strm.state = ptr::null_mut::<EState>();
+
+    ReturnCode::BZ_OK as c_int
+}
+
+/// The algorithm used for decompression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressMode {
+    /// Use an alternative algorithm that needs about half the memory, at the cost of speed.
+    Small,
+    /// Use the default algorithm.
+    Fast,
+}
+
+/// The number of bytes that decompressing a stream with this block size allocates in `mode`.
+pub(crate) fn decompress_memory_usage(mode: DecompressMode, blockSize100k: u8) -> usize {
+    let n = usize::from(blockSize100k) * 100000;
+    let buffers = match mode {
+        DecompressMode::Small => n * mem::size_of::<u16>() + ((1 + n) >> 1),
+        DecompressMode::Fast => n * mem::size_of::<u32>(),
+    };
+
+    mem::size_of::<DState>() + buffers
+}
+
+/// Gives the number of bytes that decompressing a stream allocates, for the block size in its
+/// header, and `small` as passed to [`BZ2_bzDecompressInit`].
+///
+/// This is the sum of the sizes that are requested from the allocator: the state of the stream,
+/// allocated by [`BZ2_bzDecompressInit`], and the buffers for the inverse transform, allocated by
+/// [`BZ2_bzDecompress`] once it has read the header. The block size is the digit after `BZh` in
+/// the header. [`bz_decompress_limits::max_memory`] can cap this amount.
+///
+/// # Returns
+///
+/// - 0 if any of
+///     - `!(1..=9).contains(&blockSize100k)`
+///     - `!(0..=1).contains(&small)`
+/// - the number of bytes otherwise
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressMemoryUsage))]
+pub extern "C" fn BZ2_bzDecompressMemoryUsage(blockSize100k: c_int, small: c_int) -> usize {
+    let mode = match small {
+        0 => DecompressMode::Fast,
+        1 => DecompressMode::Small,
+        _ => return 0,
+    };
+    if !(1..=9).contains(&blockSize100k) {
+        return 0;
+    }
+
+    decompress_memory_usage(mode, blockSize100k as u8)
+}
+
+/// Prepares the stream for decompression.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `!(0..=1).contains(&small)`
+///     - `!(0..=4).contains(&verbosity)`
+///     - no [valid allocator](bz_stream#custom-allocators) could be configured
+/// - [`BZ_MEM_ERROR`] if insufficient memory is available
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// The caller must guarantee that
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm`
+/// * The `bzalloc`, `bzfree` and `opaque` fields form a [valid allocator](bz_stream#custom-allocators).
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressInit))]
+pub unsafe extern "C" fn BZ2_bzDecompressInit(
+    strm: *mut bz_stream,
+    verbosity: c_int,
+    small: c_int,
+) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    BZ2_bzDecompressInitHelp(strm, verbosity, small, bz_decompress_limits::default()) as c_int
+}
+
+/// Prepares the stream for decompression, like [`BZ2_bzDecompressInit`], and limits the resources
+/// that decompression may use.
+///
+/// When decompression would cross one of the `limits`, [`BZ2_bzDecompress`] returns
+/// [`BZ_LIMIT_EXCEEDED`] instead. All output up to the limit has been written at that point. A
+/// `NULL` value for `limits` means that there are no limits.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `!(0..=1).contains(&small)`
+///     - `!(0..=4).contains(&verbosity)`
+///     - no [valid allocator](bz_stream#custom-allocators) could be configured
+/// - [`BZ_MEM_ERROR`] if insufficient memory is available
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// The caller must guarantee that
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm`
+/// * The `bzalloc`, `bzfree` and `opaque` fields form a [valid allocator](bz_stream#custom-allocators).
+/// * Either
+///     - `limits` is `NULL`
+///     - `limits` satisfies the requirements of `&*limits`
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressInitLimits))]
+pub unsafe extern "C" fn BZ2_bzDecompressInitLimits(
+    strm: *mut bz_stream,
+    verbosity: c_int,
+    small: c_int,
+    limits: *const bz_decompress_limits,
+) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    let limits = unsafe { limits.as_ref() }.copied().unwrap_or_default();
+    BZ2_bzDecompressInitHelp(strm, verbosity, small, limits) as c_int
+}
+
+pub(crate) fn BZ2_bzDecompressInitHelp(
+    strm: &mut BzStream<DState>,
+    verbosity: c_int,
+    small: c_int,
+    limits: bz_decompress_limits,
+) -> ReturnCode {
+    let decompress_mode = match small {
+        0 => DecompressMode::Fast,
+        1 => DecompressMode::Small,
+        _ => return ReturnCode::BZ_PARAM_ERROR,
+    };
+    if !(0..=4).contains(&verbosity) {
+        return ReturnCode::BZ_PARAM_ERROR;
+    }
+
+    // return a param error when no [valid allocator](bz_stream#custom-allocators) could be configured
+    let Some(allocator) = configure_allocator(strm) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
+
+    let Some(s) = allocator.allocate_zeroed::<DState>(1) else {
+        return ReturnCode::BZ_MEM_ERROR;
+    };
+
+    // this `s.strm` pointer should _NEVER_ be used! it exists just as a consistency check to ensure
+    // that a given state belongs to a given strm.
+    unsafe { (*s).strm_addr = strm as *const _ as usize }; // FIXME use .addr() once stable
+
+    unsafe {
+        (*s).state = decompress::State::BZ_X_MAGIC_1;
+        (*s).smallDecompress = decompress_mode;
+        (*s).initMode = decompress_mode;
+        (*s).ll4 = DSlice::new();
+        (*s).ll16 = DSlice::new();
+        (*s).tt = DSlice::new();
+        (*s).verbosity = verbosity;
+        (*s).blockPause = decompress::BlockPause::Off;
+        (*s).limits = limits;
+        (*s).skipOutput = false;
+        (*s).lenient = false;
+        (*s).damage = None;
+        (*s).blockCallback = BlockCallback::NONE;
+        (*s).logSink = LogSink::Default;
+    }
+
+    strm.state = s;
+
+    // safety: the DState has now been sufficiently initialized
+    start_decompress_stream(strm, unsafe { &mut *s });
+
+    ReturnCode::BZ_OK
+}
+
+/// Puts the stream at the start of a new compressed stream, keeping its buffers and settings.
+fn start_decompress_stream(strm: &mut BzStream<DState>, s: &mut DState) {
+    s.state = decompress::State::BZ_X_MAGIC_1;
+    s.bsLive = 0;
+    s.bsBuff = 0;
+    s.calculatedCombinedCRC = 0;
+    s.state_out_len = 0;
+    s.currBlockNo = 0;
+    if s.blockPause != decompress::BlockPause::Off {
+        s.blockPause = decompress::BlockPause::Running;
+    }
+    s.damage = None;
+    s.damagedBlocks = 0;
+    s.resyncBuff = 0;
+    s.blockStartBit = 0;
+    s.blockBits = 0;
+    s.blockStartOut = 0;
+    s.limitedIn = 0;
+    s.limitedOut = 0;
+    s.limitedBlocks = 0;
+
+    strm.total_in_lo32 = 0;
+    strm.total_in_hi32 = 0;
+    strm.total_out_lo32 = 0;
+    strm.total_out_hi32 = 0;
+}
+
+/// Makes the stream ready to decompress a new stream, without freeing and reallocating its memory.
+///
+/// This is equivalent to [`BZ2_bzDecompressEnd`] followed by [`BZ2_bzDecompressInit`] with the
+/// same parameters. The buffers for the inverse transform are kept, and reused when the next
+/// stream has the same block size. Any input of the current stream that was not decompressed yet
+/// is discarded.
+///
+/// The limits and the settings made with [`BZ2_bzDecompressSetBlockCallback`] and
+/// [`BZ2_bzDecompressSetLogCallback`] are kept.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzDecompressInit`]
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressReset))]
+pub unsafe extern "C" fn BZ2_bzDecompressReset(strm: *mut bz_stream) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    BZ2_bzDecompressResetHelp(strm) as c_int
+}
+
+pub(crate) fn BZ2_bzDecompressResetHelp(strm: &mut BzStream<DState>) -> ReturnCode {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
+    }
+
+    start_decompress_stream(strm, s);
+
+    ReturnCode::BZ_OK
+}
+
+/// Makes the stream ready to decompress the next of a concatenation of streams.
+///
+/// This is [`BZ2_bzDecompressReset`], except that the limits keep counting: the output, the
+/// expansion ratio and the number of blocks are checked against the totals of this stream and all
+/// streams since initialization or the last reset. Without that, a concatenation of streams that
+/// each stay under the limits could decompress to any amount of data.
+///
+/// The totals of the stream itself, `total_in_lo32` etc., start over at zero.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzDecompressInit`]
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressNextStream))]
+pub unsafe extern "C" fn BZ2_bzDecompressNextStream(strm: *mut bz_stream) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    BZ2_bzDecompressNextStreamHelp(strm) as c_int
+}
+
+pub(crate) fn BZ2_bzDecompressNextStreamHelp(strm: &mut BzStream<DState>) -> ReturnCode {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
+    }
+
+    let total_in = (u64::from(strm.total_in_hi32) << 32) | u64::from(strm.total_in_lo32);
+    let limitedIn = s.limitedIn.saturating_add(total_in);
+    let limitedOut = s.limitedOut.saturating_add(bytes_produced(strm));
+    let limitedBlocks = s.limitedBlocks.saturating_add(s.currBlockNo as u32);
+
+    start_decompress_stream(strm, s);
+
+    s.limitedIn = limitedIn;
+    s.limitedOut = limitedOut;
+    s.limitedBlocks = limitedBlocks;
+
+    ReturnCode::BZ_OK
+}
+
+macro_rules! BZ_RAND_MASK {
+    ($s:expr) => {
+        ($s.rNToGo == 1) as u8
+    };
 }
 
 macro_rules! BZ_RAND_UPD_MASK {
@@ -1760,6 +2653,109 @@ fn un_rle_obuf_to_output_small(strm: &mut BzStream<DState>, s: &mut DState) -> b
     }
 }
 
+/// Registers a `callback` that is called after each block is decompressed and has passed its CRC
+/// check, replacing any earlier one.
+///
+/// The callback receives `opaque` and a [`bz_block_info`] describing the block. It is called once
+/// all output of the block has been written. A `NULL` callback removes the registered one.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzDecompressInit`]
+/// * `callback` can be called with `opaque` for as long as it is registered
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressSetBlockCallback))]
+pub unsafe extern "C" fn BZ2_bzDecompressSetBlockCallback(
+    strm: *mut bz_stream,
+    callback: Option<bz_block_callback>,
+    opaque: *mut c_void,
+) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    BZ2_bzDecompressSetBlockCallbackHelp(strm, callback, opaque) as c_int
+}
+
+pub(crate) fn BZ2_bzDecompressSetBlockCallbackHelp(
+    strm: &mut BzStream<DState>,
+    callback: Option<bz_block_callback>,
+    opaque: *mut c_void,
+) -> ReturnCode {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
+    }
+
+    s.blockCallback = BlockCallback { callback, opaque };
+
+    ReturnCode::BZ_OK
+}
+
+/// Registers a `callback` that receives the diagnostic events of the stream, replacing any earlier
+/// one.
+///
+/// Events are only emitted when the stream was initialized with a `verbosity` of 2 or more. A
+/// `NULL` callback restores the default, which writes the events to stderr.
+///
+/// # Returns
+///
+/// - [`BZ_PARAM_ERROR`] if any of
+///     - `strm.is_null()`
+///     - `strm.s.is_null()`
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// * Either
+///     - `strm` is `NULL`
+///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzDecompressInit`]
+/// * `callback` can be called with `opaque` for as long as it is registered
+#[cfg_attr(feature = "export-symbols", export_name = prefix!(BZ2_bzDecompressSetLogCallback))]
+pub unsafe extern "C" fn BZ2_bzDecompressSetLogCallback(
+    strm: *mut bz_stream,
+    callback: Option<bz_log_callback>,
+    opaque: *mut c_void,
+) -> c_int {
+    let Some(strm) = (unsafe { BzStream::from_ptr(strm) }) else {
+        return ReturnCode::BZ_PARAM_ERROR as c_int;
+    };
+    let sink = match callback {
+        Some(callback) => LogSink::Callback { callback, opaque },
+        None => LogSink::Default,
+    };
+    BZ2_bzDecompressSetLogSinkHelp(strm, sink) as c_int
+}
+
+pub(crate) fn BZ2_bzDecompressSetLogSinkHelp(
+    strm: &mut BzStream<DState>,
+    sink: LogSink,
+) -> ReturnCode {
+    let Some(s) = (unsafe { strm.state.as_mut() }) else {
+        return ReturnCode::BZ_PARAM_ERROR;
+    };
+
+    // FIXME use .addr() once stable
+    if s.strm_addr != strm as *mut _ as usize {
+        return ReturnCode::BZ_PARAM_ERROR;
+    }
+
+    s.logSink = sink;
+
+    ReturnCode::BZ_OK
+}
+
 /// Decompresses as much data as possible, and stops when the input buffer becomes empty or the output buffer becomes full.
 ///
 /// # Returns
@@ -1771,6 +2767,8 @@ fn un_rle_obuf_to_output_small(strm: &mut BzStream<DState>, s: &mut DState) -> b
 /// - [`BZ_DATA_ERROR`] if a data integrity error is detected in the compressed stream
 /// - [`BZ_DATA_ERROR_MAGIC`] if the compressed stream doesn't begin with the right magic bytes
 /// - [`BZ_MEM_ERROR`] if there wasn't enough memory available
+/// - [`BZ_LIMIT_EXCEEDED`] if decompression would cross one of the limits given to
+///   [`BZ2_bzDecompressInitLimits`]
 /// - [`BZ_STREAM_END`] if the logical end of the data stream was detected and all output has been
 ///   written to the output buffer
 /// - [`BZ_OK`] otherwise
@@ -1780,6 +2778,7 @@ fn un_rle_obuf_to_output_small(strm: &mut BzStream<DState>, s: &mut DState) -> b
 /// * Either
 ///     - `strm` is `NULL`
 ///     - `strm` satisfies the requirements of `&mut *strm` and was initialized with [`BZ2_bzDecompressInit`]
+///       or [`BZ2_bzDecompressInitLimits`]
 /// * Either
 ///     - `strm.next_in` is `NULL` and `strm.avail_in` is 0
 ///     - `strm.next_in` is readable for `strm.avail_in` bytes
@@ -1795,6 +2794,33 @@ pub unsafe extern "C" fn BZ2_bzDecompress(strm: *mut bz_stream) -> c_int {
     BZ2_bzDecompressHelp(strm) as c_int
 }
 
+impl DState {
+    /// Records a damaged block that a lenient decoder passes over.
+    fn report_damage(&mut self, damage: Damage) {
+        self.damage = Some(damage);
+        self.damagedBlocks += 1;
+    }
+
+    /// Whether the current block is one more than [`bz_decompress_limits::max_blocks`] allows.
+    pub(crate) fn exceeds_max_blocks(&self) -> bool {
+        let blocks = self.limitedBlocks.saturating_add(self.currBlockNo as u32);
+        self.limits.max_blocks != 0 && blocks > self.limits.max_blocks
+    }
+}
+
+/// The number of bytes that the stream produced.
+fn bytes_produced(strm: &BzStream<DState>) -> u64 {
+    (u64::from(strm.total_out_hi32) << 32) | u64::from(strm.total_out_lo32)
+}
+
+/// The number of bits of the stream that have been decoded.
+pub(crate) fn bit_position(strm: &BzStream<DState>, s: &DState) -> u64 {
+    let total_in = (u64::from(strm.total_in_hi32) << 32) | u64::from(strm.total_in_lo32);
+
+    // the bits in the bit buffer were read from the input, but not consumed yet
+    total_in * 8 - s.bsLive as u64
+}
+
 pub(crate) fn BZ2_bzDecompressHelp(strm: &mut BzStream<DState>) -> ReturnCode {
     let Some(s) = (unsafe { strm.state.as_mut() }) else {
         return ReturnCode::BZ_PARAM_ERROR;
@@ -1815,35 +2841,96 @@ pub(crate) fn BZ2_bzDecompressHelp(strm: &mut BzStream<DState>) -> ReturnCode {
                 return ReturnCode::BZ_SEQUENCE_ERROR;
             }
             decompress::State::BZ_X_OUTPUT => {
+                let total_in =
+                    (u64::from(strm.total_in_hi32) << 32) | u64::from(strm.total_in_lo32);
+                let total_out =
+                    (u64::from(strm.total_out_hi32) << 32) | u64::from(strm.total_out_lo32);
+                let budget = s.limits.output_budget(
+                    s.limitedIn.saturating_add(total_in),
+                    s.limitedOut.saturating_add(total_out),
+                );
+
+                // hide the output space beyond the limit
+                let avail_out = strm.avail_out;
+                let limited_avail_out = match budget {
+                    Some(budget) => Ord::min(u64::from(avail_out), budget) as c_uint,
+                    None => avail_out,
+                };
+                strm.avail_out = limited_avail_out;
+
                 let corrupt = match s.smallDecompress {
                     DecompressMode::Small => un_rle_obuf_to_output_small(strm, s),
                     DecompressMode::Fast => un_rle_obuf_to_output_fast(strm, s),
                 };
 
+                let produced = limited_avail_out - strm.avail_out;
+                strm.avail_out = avail_out - produced;
+
                 if corrupt {
-                    return ReturnCode::BZ_DATA_ERROR;
+                    if !s.lenient {
+                        return ReturnCode::BZ_DATA_ERROR;
+                    }
+
+                    // the rest of the output of this block is lost
+                    let block = s.currBlockNo as u32;
+                    s.report_damage(Damage::Invalid {
+                        block,
+                        position: bit_position(strm, s),
+                    });
+                    s.state_out_len = 0;
+                    s.state = decompress::State::BZ_X_BLKHDR_1;
+                    return ReturnCode::BZ_OK;
                 }
 
-                if s.nblock_used == s.save.nblock as i32 + 1 && s.state_out_len == 0 {
+                let block_done = s.nblock_used == s.save.nblock as i32 + 1 && s.state_out_len == 0;
+
+                // only fail once all output up to the limit has been handed out, so that a stream
+                // that decompresses to exactly the limit still succeeds
+                if !block_done && budget == Some(0) {
+                    return ReturnCode::BZ_LIMIT_EXCEEDED;
+                }
+
+                if block_done {
                     s.calculatedBlockCRC = !s.calculatedBlockCRC;
                     if s.verbosity >= 3 {
-                        debug_log!(
-                            " {{{:#08x}, {:#08x}}}",
-                            s.storedBlockCRC,
-                            s.calculatedBlockCRC,
-                        );
+                        s.logSink.emit(LogEvent::BlockCrc {
+                            block: s.currBlockNo as u32,
+                            stored: s.storedBlockCRC,
+                            computed: s.calculatedBlockCRC,
+                        });
                     }
                     if s.verbosity >= 2 {
-                        debug_log!("]");
+                        s.logSink.emit(LogEvent::BlockEnd {
+                            block: s.currBlockNo as u32,
+                        });
                     }
                     #[cfg(not(feature = "__internal-fuzz-disable-checksum"))]
                     if s.calculatedBlockCRC != s.storedBlockCRC {
-                        return ReturnCode::BZ_DATA_ERROR;
+                        if !s.lenient {
+                            return ReturnCode::BZ_DATA_ERROR;
+                        }
+
+                        // all of the output of the block was handed out already
+                        s.report_damage(Damage::Crc {
+                            block: s.currBlockNo as u32,
+                            stored: s.storedBlockCRC,
+                            computed: s.calculatedBlockCRC,
+                        });
+                        s.state = decompress::State::BZ_X_BLKHDR_1;
+                        return ReturnCode::BZ_OK;
                     }
                     s.calculatedCombinedCRC = s.calculatedCombinedCRC.rotate_left(1);
                     s.calculatedCombinedCRC ^= s.calculatedBlockCRC;
                     s.state = decompress::State::BZ_X_BLKHDR_1;
 
+                    s.blockCallback.notify(bz_block_info {
+                        block: s.currBlockNo as c_uint,
+                        uncompressed: bytes_produced(strm) - s.blockStartOut,
+                        compressed_bits: s.blockBits,
+                        crc: s.calculatedBlockCRC,
+                        sort_nanos: 0,
+                    });
+
                     continue;
                 } else {
                     return ReturnCode::BZ_OK;
@@ -1852,20 +2939,48 @@ pub(crate) fn BZ2_bzDecompressHelp(strm: &mut BzStream<DState>) -> ReturnCode {
             _ => match decompress(strm, s, &allocator) {
                 ReturnCode::BZ_STREAM_END => {
                     if s.verbosity >= 3 {
-                        debug_log!(
-                            "\n    combined CRCs: stored = {:#08x}, computed = {:#08x}",
-                            s.storedCombinedCRC,
-                            s.calculatedCombinedCRC,
-                        );
+                        s.logSink.emit(LogEvent::CombinedCrc {
+                            stored: s.storedCombinedCRC,
+                            computed: s.calculatedCombinedCRC,
+                        });
                     }
+                    // the damaged blocks were reported already, and can't match the combined CRC
                     #[cfg(not(feature = "__internal-fuzz-disable-checksum"))]
-                    if s.calculatedCombinedCRC != s.storedCombinedCRC {
+                    if s.calculatedCombinedCRC != s.storedCombinedCRC
+                        && !s.skipOutput
+                        && s.damagedBlocks == 0
+                    {
                         return ReturnCode::BZ_DATA_ERROR;
                     }
                     return ReturnCode::BZ_STREAM_END;
                 }
+                ReturnCode::BZ_DATA_ERROR
+                    if s.lenient && s.state >= decompress::State::BZ_X_BLKHDR_1 =>
+                {
+                    // a damaged header belongs to the block after the current one
+                    let in_header = s.state < decompress::State::BZ_X_BCRC_1
+                        || (s.state >= decompress::State::BZ_X_ENDHDR_2
+                            && s.state <= decompress::State::BZ_X_ENDHDR_6);
+                    if in_header {
+                        s.currBlockNo += 1;
+                    }
+
+                    let block = s.currBlockNo as u32;
+                    s.report_damage(Damage::Invalid {
+                        block,
+                        position: bit_position(strm, s),
+                    });
+                    s.resyncBuff = 0;
+                    s.state = decompress::State::BZ_X_RESYNC;
+                    return ReturnCode::BZ_OK;
+                }
                 return_code => match s.state {
-                    decompress::State::BZ_X_OUTPUT => continue,
+                    decompress::State::BZ_X_OUTPUT => {
+                        // the whole block was read, and none of its output was produced yet
+                        s.blockBits = bit_position(strm, s) - s.blockStartBit;
+                        s.blockStartOut = bytes_produced(strm);
+                        continue;
+                    }
                     _ => return return_code,
                 },
             },
@@ -1896,7 +3011,7 @@ pub unsafe extern "C" fn BZ2_bzDecompressEnd(strm: *mut bz_stream) -> c_int {
     BZ2_bzDecompressEndHelp(strm) as c_int
 }
 
-fn BZ2_bzDecompressEndHelp(strm: &mut BzStream<DState>) -> ReturnCode {
+pub(crate) fn BZ2_bzDecompressEndHelp(strm: &mut BzStream<DState>) -> ReturnCode {
     let Some(s) = (unsafe { strm.state.as_mut() }) else {
         return ReturnCode::BZ_PARAM_ERROR;
     };
@@ -2112,7 +3227,7 @@ unsafe fn BZ2_bzBuffToBuffDecompressHelp(
 ) -> Result<c_uint, ReturnCode> {
     let mut strm = BzStream::zeroed();
 
-    match BZ2_bzDecompressInitHelp(&mut strm, verbosity, small) {
+    match BZ2_bzDecompressInitHelp(&mut strm, verbosity, small, bz_decompress_limits::default()) {
         ReturnCode::BZ_OK => {}
         ret => return Err(ret),
     }
@@ -1,8 +1,13 @@
 #![forbid(unsafe_code)]
 
 use crate::blocksort::block_sort;
-use crate::bzlib::{EState, BZ_MAX_SELECTORS, BZ_N_GROUPS, BZ_N_ITERS, BZ_RUNA, BZ_RUNB};
-use crate::{assert_h, debug_log, debug_logln, huffman};
+use core::ffi::c_uint;
+
+use crate::bzlib::{
+    bz_block_info, EState, BZ_MAX_SELECTORS, BZ_N_GROUPS, BZ_N_ITERS, BZ_RUNA, BZ_RUNB,
+};
+use crate::logging::LogEvent;
+use crate::{assert_h, huffman, InternalError};
 
 pub(crate) struct EWriter {
     pub num_z: u32,
@@ -10,6 +15,19 @@ pub(crate) struct EWriter {
     bs_buff: u32,
 }
 
+impl EWriter {
+    /// The number of zero bits that pad the end of a finished stream to a whole byte.
+    pub(crate) fn padding_bits(&self) -> u32 {
+        // `LiveWriter::finish` leaves `bs_live` at minus the number of padding bits
+        self.bs_live.unsigned_abs()
+    }
+
+    /// The number of bits written since `num_z` was last reset.
+    fn bit_count(&self) -> u64 {
+        u64::from(self.num_z) * 8 + self.bs_live as u64
+    }
+}
+
 pub(crate) struct LiveWriter<'a> {
     zbits: &'a mut [u8],
     writer: &'a mut EWriter,
@@ -214,7 +232,7 @@ fn generate_mtf_values(s: &mut EState) {
     s.nMTF = wr;
 }
 
-fn send_mtf_values(s: &mut EState) {
+fn send_mtf_values(s: &mut EState) -> Result<(), InternalError> {
     const BZ_LESSER_ICOST: u8 = 0;
     const BZ_GREATER_ICOST: u8 = 15;
 
@@ -241,12 +259,11 @@ fn send_mtf_values(s: &mut EState) {
     let mtfv = s.arr1.mtfv();
 
     if s.verbosity >= 3 {
-        debug_logln!(
-            "      {} in block, {} after MTF & 1-2 coding, {}+2 syms in use",
-            s.nblock,
-            s.nMTF,
-            s.nInUse,
-        );
+        s.logSink.emit(LogEvent::MtfCoded {
+            block_size: s.nblock as u32,
+            mtf_symbols: s.nMTF as u32,
+            in_use: s.nInUse as u32,
+        });
     }
 
     let alphaSize = usize::try_from(s.nInUse + 2).unwrap_or(0);
@@ -294,14 +311,13 @@ fn send_mtf_values(s: &mut EState) {
             }
 
             if s.verbosity >= 3 {
-                debug_logln!(
-                    "      initial group {}, [{} .. {}], has {} syms ({:4.1}%)",
-                    nPart,
-                    gs,
-                    ge,
-                    aFreq,
-                    100.0f64 * aFreq as f64 / s.nMTF as f64,
-                );
+                s.logSink.emit(LogEvent::InitialGroup {
+                    group: nPart as u32,
+                    start: gs as u32,
+                    end: ge as u32,
+                    frequency: aFreq as u32,
+                    mtf_symbols: s.nMTF as u32,
+                });
             }
 
             for v in 0..alphaSize {
@@ -422,15 +438,16 @@ fn send_mtf_values(s: &mut EState) {
         }
 
         if s.verbosity >= 3 {
-            debug_log!(
-                "      pass {}: size is {}, grp uses are ",
-                iter + 1,
-                totc / 8,
-            );
-            for f in fave.iter() {
-                debug_log!("{} ", f);
+            let mut group_uses = [0; BZ_N_GROUPS];
+            for (uses, f) in group_uses.iter_mut().zip(fave.iter()) {
+                *uses = *f as u32;
             }
-            debug_logln!();
+            s.logSink.emit(LogEvent::HuffmanPass {
+                pass: iter as u32 + 1,
+                size: (totc / 8) as u32,
+                groups: nGroups as u32,
+                group_uses,
+            });
         }
 
         /*--
@@ -439,7 +456,7 @@ fn send_mtf_values(s: &mut EState) {
         /* maxLen was changed from 20 to 17 in bzip2-1.0.3.  See
         comment in huffman.c for details. */
         for t in 0..nGroups {
-            huffman::make_code_lengths(&mut s.len[t], &s.rfreq[t], alphaSize, 17);
+            huffman::make_code_lengths(&mut s.len[t], &s.rfreq[t], alphaSize, 17)?;
         }
     }
 
@@ -487,6 +504,7 @@ fn send_mtf_values(s: &mut EState) {
     }
 
     /*--- Transmit the mapping table. ---*/
+    let mapping_bytes;
     let mut writer = LiveWriter::new(&mut s.writer, s.arr2.zbits(s.nblock as usize));
 
     {
@@ -504,9 +522,7 @@ fn send_mtf_values(s: &mut EState) {
                 }
             }
         }
-        if s.verbosity >= 3 {
-            debug_log!("      bytes: mapping {}, ", writer.num_z as i32 - nBytes,);
-        }
+        mapping_bytes = writer.num_z as i32 - nBytes;
     }
 
     /*--- Now the selectors. ---*/
@@ -520,9 +536,7 @@ fn send_mtf_values(s: &mut EState) {
         }
         writer.write(1, 0);
     }
-    if s.verbosity >= 3 {
-        debug_log!("selectors {}, ", writer.num_z as i32 - nBytes);
-    }
+    let selector_bytes = writer.num_z as i32 - nBytes;
 
     /*--- Now the coding tables. ---*/
     nBytes = writer.num_z as i32;
@@ -542,9 +556,7 @@ fn send_mtf_values(s: &mut EState) {
             writer.write(1, 0);
         }
     }
-    if s.verbosity >= 3 {
-        debug_log!("code lengths {}, ", writer.num_z as i32 - nBytes);
-    }
+    let code_length_bytes = writer.num_z as i32 - nBytes;
 
     /*--- And finally, the block data proper ---*/
     nBytes = writer.num_z as i32;
@@ -588,11 +600,43 @@ fn send_mtf_values(s: &mut EState) {
     assert_h!(selCtr == nSelectors, 3007);
 
     if s.verbosity >= 3 {
-        debug_logln!("codes {}", writer.num_z as i32 - nBytes);
+        s.logSink.emit(LogEvent::HuffmanCoded {
+            mapping: mapping_bytes as u32,
+            selectors: selector_bytes as u32,
+            code_lengths: code_length_bytes as u32,
+            codes: (writer.num_z as i32 - nBytes) as u32,
+        });
+    }
+
+    Ok(())
+}
+
+/// Sorts the block, and returns how long that took in nanoseconds, or zero without a clock.
+fn timed_block_sort(s: &mut EState) -> Result<u64, InternalError> {
+    #[cfg(feature = "std")]
+    {
+        let start = std::time::Instant::now();
+        block_sort(s)?;
+        Ok(start.elapsed().as_nanos() as u64)
+    }
+
+    #[cfg(not(feature = "std"))]
+    {
+        block_sort(s)?;
+        Ok(0)
     }
 }
 
-pub(crate) fn compress_block(s: &mut EState, is_last_block: bool) {
+/// Writes the current block, and the stream header and trailer where needed.
+///
+/// Returns the information about the block for the block callback, except for its number of
+/// uncompressed bytes, or `None` when the block is empty.
+pub(crate) fn compress_block(
+    s: &mut EState,
+    is_last_block: bool,
+) -> Result<Option<bz_block_info>, InternalError> {
+    let mut sort_nanos = 0;
+
     if s.nblock > 0 {
         s.blockCRC = !s.blockCRC;
         s.combinedCRC = s.combinedCRC.rotate_left(1);
@@ -602,18 +646,19 @@ pub(crate) fn compress_block(s: &mut EState, is_last_block: bool) {
         }
 
         if s.verbosity >= 2 {
-            debug_logln!(
-                "    block {}: crc = 0x{:08x}, combined CRC = 0x{:08x}, size = {}",
-                s.blockNo,
-                s.blockCRC,
-                s.combinedCRC,
-                s.nblock,
-            );
+            s.logSink.emit(LogEvent::CompressBlock {
+                block: s.blockNo as u32,
+                crc: s.blockCRC,
+                combined_crc: s.combinedCRC,
+                size: s.nblock as u32,
+            });
         }
 
-        block_sort(s);
+        sort_nanos = timed_block_sort(s)?;
     }
 
+    let mut info = None;
+
     {
         /*-- If this is the first block, create the stream header. --*/
         if s.blockNo == 1 {
@@ -627,6 +672,7 @@ pub(crate) fn compress_block(s: &mut EState, is_last_block: bool) {
         }
 
         if s.nblock > 0 {
+            let start_bits = s.writer.bit_count();
             let mut writer = LiveWriter::new(&mut s.writer, s.arr2.zbits(s.nblock as usize));
 
             writer.write_u8(0x31);
@@ -656,7 +702,15 @@ pub(crate) fn compress_block(s: &mut EState, is_last_block: bool) {
 
             generate_mtf_values(s);
 
-            send_mtf_values(s);
+            send_mtf_values(s)?;
+
+            info = Some(bz_block_info {
+                block: s.blockNo as c_uint,
+                uncompressed: 0,
+                compressed_bits: s.writer.bit_count() - start_bits,
+                crc: s.blockCRC,
+                sort_nanos,
+            });
         }
     }
 
@@ -673,9 +727,13 @@ pub(crate) fn compress_block(s: &mut EState, is_last_block: bool) {
         writer.write_u32(s.combinedCRC);
 
         if s.verbosity >= 2 {
-            debug_log!("    final combined CRC = 0x{:08x}\n   ", s.combinedCRC);
+            s.logSink.emit(LogEvent::CompressEnd {
+                combined_crc: s.combinedCRC,
+            });
         }
 
         writer.finish();
     }
+
+    Ok(info)
 }
@@ -0,0 +1,687 @@
+use core::ffi::{c_char, c_int, c_uint};
+use core::ptr;
+
+use crate::allocator::Allocator;
+#[cfg(feature = "std")]
+use crate::bzlib::{
+    bz_block_info, call_boxed_block_callback, BZ2_bzCompressSetBlockCallbackHelp,
+    BZ2_bzCompressSetLogSinkHelp, BoxedBlockCallback,
+};
+use crate::bzlib::{
+    BZ2_bzCompressEndHelp, BZ2_bzCompressHelp, BZ2_bzCompressInitHelp, BZ2_bzCompressResetHelp,
+    BlockSorter, BzStream, EState, Status,
+};
+#[cfg(feature = "std")]
+use crate::logging::{BoxedLogSink, LogSink};
+#[cfg(any(doc, feature = "std"))]
+use crate::LogEvent;
+use crate::{Action, BZ2_bzCompressMemoryUsage, Error, InternalError, ReturnCode};
+
+#[cfg(doc)]
+use crate::{
+    BZ2_bzCompress, BZ2_bzCompressInit, BZ2_bzCompressReset, BZ2_bzCompressSetBlockCallback,
+    BZ2_bzCompressSetBlockSorter, BZ2_bzCompressSetLogCallback, BZ2_bzCompressSetRecoverable,
+};
+
+/// The outcome of a single compression or decompression call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The status reported by the underlying stream.
+    pub status: Status,
+    /// The number of bytes read from the input slice.
+    pub consumed: usize,
+    /// The number of bytes written to the output slice.
+    pub produced: usize,
+}
+
+/// A safe interface to the compression state.
+///
+/// This type drives the same state machine as [`BZ2_bzCompress`], but takes rust slices instead of
+/// raw pointers. The state is allocated with the default allocator, and freed when the
+/// [`Compressor`] is dropped.
+pub struct Compressor {
+    strm: BzStream<EState>,
+    /// The closure registered with [`Compressor::set_block_callback`]. It is boxed twice, so that
+    /// it has a thin pointer that stays put when `self` is moved.
+    #[cfg(feature = "std")]
+    block_callback: Option<std::boxed::Box<BoxedBlockCallback>>,
+    /// The closure registered with [`Compressor::set_log_sink`], boxed twice for the same reason.
+    #[cfg(feature = "std")]
+    log_sink: Option<std::boxed::Box<BoxedLogSink>>,
+}
+
+// SAFETY: the compressor exclusively owns its state, and the default allocators don't depend on
+// the thread that allocated the memory.
+unsafe impl Send for Compressor {}
+
+impl Compressor {
+    /// Prepares a new compression stream.
+    ///
+    /// For the meaning of `block_size_100k` and `work_factor`, see [`BZ2_bzCompressInit`].
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Param`] if any of
+    ///     - `!(1..=9).contains(&block_size_100k)`
+    ///     - `!(0..=250).contains(&work_factor)`
+    /// - [`Error::Config`] if no default allocator is configured
+    /// - [`Error::Mem`] if insufficient memory is available
+    pub fn new(block_size_100k: u32, work_factor: u32) -> Result<Self, Error> {
+        if Allocator::DEFAULT.is_none() {
+            return Err(Error::Config);
+        }
+
+        let block_size_100k = c_int::try_from(block_size_100k).map_err(|_| Error::Param)?;
+        let work_factor = c_int::try_from(work_factor).map_err(|_| Error::Param)?;
+
+        let mut strm = BzStream::zeroed();
+
+        match BZ2_bzCompressInitHelp(&mut strm, block_size_100k, 0, work_factor) {
+            ReturnCode::BZ_OK => Ok(Self {
+                strm,
+                #[cfg(feature = "std")]
+                block_callback: None,
+                #[cfg(feature = "std")]
+                log_sink: None,
+            }),
+            error => Err(Error::from_return_code(error)),
+        }
+    }
+
+    /// Compresses data from `input` into `output`.
+    ///
+    /// Stops when the input is exhausted or the output is full, and reports how many bytes were
+    /// consumed and produced. The semantics of `action` are those of [`BZ2_bzCompress`]: after the
+    /// first call with [`Action::Flush`] or [`Action::Finish`], the same action must be repeated
+    /// with the remaining input until [`Status::RunOk`] respectively [`Status::StreamEnd`] is
+    /// returned.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Param`] if `action` is [`Action::Run`] and no progress could be made
+    /// - [`Error::Sequence`] if `action` is not valid in the current state of the stream
+    /// - [`Error::Internal`] if an internal check failed in a stream made recoverable with
+    ///   [`Compressor::set_recoverable`]
+    pub fn compress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        action: Action,
+    ) -> Result<Progress, Error> {
+        let avail_in = Ord::min(input.len(), c_uint::MAX as usize) as c_uint;
+        let avail_out = Ord::min(output.len(), c_uint::MAX as usize) as c_uint;
+
+        let strm = self.stream();
+
+        strm.next_in = input.as_ptr().cast::<c_char>();
+        strm.avail_in = avail_in;
+        strm.next_out = output.as_mut_ptr().cast::<c_char>();
+        strm.avail_out = avail_out;
+
+        let ret = BZ2_bzCompressHelp(strm, action as c_int);
+
+        let consumed = (avail_in - strm.avail_in) as usize;
+        let produced = (avail_out - strm.avail_out) as usize;
+
+        // don't keep pointers to the caller's buffers around
+        strm.next_in = ptr::null();
+        strm.avail_in = 0;
+        strm.next_out = ptr::null_mut();
+        strm.avail_out = 0;
+
+        match Status::from_return_code(ret) {
+            Some(status) => Ok(Progress {
+                status,
+                consumed,
+                produced,
+            }),
+            None => Err(Error::from_return_code(ret)),
+        }
+    }
+
+    /// The number of bytes that [`Compressor::new`] allocates for `block_size_100k`, or `None`
+    /// when the block size is invalid.
+    ///
+    /// See [`BZ2_bzCompressMemoryUsage`].
+    pub fn memory_usage(block_size_100k: u32) -> Option<usize> {
+        let block_size_100k = c_int::try_from(block_size_100k).ok()?;
+        match BZ2_bzCompressMemoryUsage(block_size_100k) {
+            0 => None,
+            bytes => Some(bytes),
+        }
+    }
+
+    /// The total number of bytes consumed by this stream.
+    pub fn total_in(&self) -> u64 {
+        (u64::from(self.strm.total_in_hi32) << 32) | u64::from(self.strm.total_in_lo32)
+    }
+
+    /// The total number of bytes produced by this stream.
+    pub fn total_out(&self) -> u64 {
+        (u64::from(self.strm.total_out_hi32) << 32) | u64::from(self.strm.total_out_lo32)
+    }
+
+    /// Starts over with a new stream, keeping the allocated buffers and the settings.
+    ///
+    /// This is the safe counterpart of [`BZ2_bzCompressReset`], and is much cheaper than creating a new
+    /// [`Compressor`] for each of many small streams. The totals start again from zero.
+    pub fn reset(&mut self) {
+        BZ2_bzCompressResetHelp(self.stream());
+    }
+
+    /// Calls `callback` after each block is compressed, replacing any earlier callback.
+    ///
+    /// This is the safe counterpart of [`BZ2_bzCompressSetBlockCallback`]. The callback runs on the
+    /// thread that calls [`Compressor::compress`], and a panic in the callback aborts the process.
+    #[cfg(feature = "std")]
+    pub fn set_block_callback(&mut self, callback: impl FnMut(&bz_block_info) + Send + 'static) {
+        let mut callback: std::boxed::Box<BoxedBlockCallback> =
+            std::boxed::Box::new(std::boxed::Box::new(callback));
+        let opaque = (&mut *callback as *mut BoxedBlockCallback).cast::<core::ffi::c_void>();
+
+        BZ2_bzCompressSetBlockCallbackHelp(self.stream(), Some(call_boxed_block_callback), opaque);
+        self.block_callback = Some(callback);
+    }
+
+    /// Makes a failed internal consistency check fail this stream with [`Error::Internal`], instead
+    /// of terminating the process.
+    ///
+    /// See [`BZ2_bzCompressSetRecoverable`]. Once it failed, every later call to
+    /// [`Compressor::compress`] fails too, and [`Compressor::internal_error`] gives the details.
+    pub fn set_recoverable(&mut self, recoverable: bool) {
+        // SAFETY: the state was initialized in `Compressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.recoverable = recoverable;
+        }
+    }
+
+    /// The internal consistency check that failed this stream, if any.
+    pub fn internal_error(&self) -> Option<InternalError> {
+        // SAFETY: the state was initialized in `Compressor::new`, and is only freed on drop
+        unsafe { self.strm.state.as_ref() }.and_then(|s| s.internalError)
+    }
+
+    /// Selects the algorithm that sorts the blocks, see [`BZ2_bzCompressSetBlockSorter`].
+    ///
+    /// The output is the same for every choice. It applies from the next block that is sorted.
+    pub fn set_block_sorter(&mut self, sorter: BlockSorter) {
+        // SAFETY: the state was initialized in `Compressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.blockSorter = sorter;
+        }
+    }
+
+    /// Sets the level of diagnostic events that are emitted, from 0 (none) to 4 (all).
+    ///
+    /// Higher levels are treated as 4. The events go to the sink registered with
+    /// [`Compressor::set_log_sink`], or to the default sink described in [`LogEvent`].
+    pub fn set_verbosity(&mut self, verbosity: u32) {
+        // SAFETY: the state was initialized in `Compressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.verbosity = Ord::min(verbosity, 4) as i32;
+        }
+    }
+
+    /// Sends the diagnostic events of this stream to `sink`, replacing any earlier sink.
+    ///
+    /// This is the safe counterpart of [`BZ2_bzCompressSetLogCallback`]. Events are only emitted when
+    /// the verbosity was raised with [`Compressor::set_verbosity`]. The sink runs on the thread that
+    /// calls [`Compressor::compress`].
+    #[cfg(feature = "std")]
+    pub fn set_log_sink(&mut self, sink: impl FnMut(&LogEvent) + Send + 'static) {
+        let mut sink: std::boxed::Box<BoxedLogSink> =
+            std::boxed::Box::new(std::boxed::Box::new(sink));
+
+        BZ2_bzCompressSetLogSinkHelp(self.stream(), LogSink::Closure(&mut *sink));
+        self.log_sink = Some(sink);
+    }
+
+    /// The number of zero bits that pad the end of the output to a whole byte.
+    ///
+    /// Only meaningful after [`Status::StreamEnd`] was returned.
+    #[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+    pub(crate) fn padding_bits(&self) -> u32 {
+        // SAFETY: the state was initialized in `Compressor::new`, and is only freed on drop
+        match unsafe { self.strm.state.as_ref() } {
+            Some(s) => s.writer.padding_bits(),
+            None => 0,
+        }
+    }
+
+    /// Returns the stream, after pointing its consistency check at the current location.
+    ///
+    /// The state records the address of its stream, but the stream is moved along with `self`.
+    fn stream(&mut self) -> &mut BzStream<EState> {
+        // SAFETY: the state was initialized in `Compressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.strm_addr = &self.strm as *const _ as usize; // FIXME use .addr() once stable
+        }
+
+        &mut self.strm
+    }
+}
+
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        BZ2_bzCompressEndHelp(self.stream());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use crate::test_data::{SAMPLE1_REF, SAMPLE2_REF};
+    use crate::{BZ2_bzBuffToBuffCompress, BZ2_bzBuffToBuffDecompress};
+
+    fn sample_input() -> Vec<u8> {
+        (0..50_000u32).map(|i| (i * i % 251) as u8).collect()
+    }
+
+    fn compress_all(input: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut compressor = Compressor::new(9, 0).unwrap();
+        let mut output = Vec::new();
+        let mut buf = [0u8; 64];
+
+        for chunk in input.chunks(chunk_size) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                let progress = compressor.compress(chunk, &mut buf, Action::Run).unwrap();
+                assert_eq!(progress.status, Status::RunOk);
+                output.extend_from_slice(&buf[..progress.produced]);
+                chunk = &chunk[progress.consumed..];
+            }
+        }
+
+        loop {
+            let progress = compressor.compress(&[], &mut buf, Action::Finish).unwrap();
+            output.extend_from_slice(&buf[..progress.produced]);
+            if progress.status == Status::StreamEnd {
+                break;
+            }
+            assert_eq!(progress.status, Status::FinishOk);
+        }
+
+        assert_eq!(compressor.total_in(), input.len() as u64);
+        assert_eq!(compressor.total_out(), output.len() as u64);
+
+        output
+    }
+
+    fn buff_to_buff_compress(input: &[u8]) -> Vec<u8> {
+        let mut dest = vec![0u8; input.len() + 1024];
+        let mut dest_len = dest.len() as c_uint;
+
+        let err = unsafe {
+            BZ2_bzBuffToBuffCompress(
+                dest.as_mut_ptr().cast(),
+                &mut dest_len,
+                input.as_ptr() as *mut _,
+                input.len() as _,
+                9,
+                0,
+                0,
+            )
+        };
+        assert_eq!(err, 0);
+
+        dest.truncate(dest_len as usize);
+        dest
+    }
+
+    #[test]
+    fn matches_buff_to_buff() {
+        let input = sample_input();
+        let expected = buff_to_buff_compress(&input);
+
+        assert_eq!(compress_all(&input, input.len()), expected);
+        assert_eq!(compress_all(&input, 1000), expected);
+    }
+
+    #[test]
+    fn round_trip() {
+        let input = sample_input();
+        let compressed = compress_all(&input, 4096);
+
+        let mut dest = vec![0u8; input.len()];
+        let mut dest_len = dest.len() as c_uint;
+        let err = unsafe {
+            BZ2_bzBuffToBuffDecompress(
+                dest.as_mut_ptr().cast(),
+                &mut dest_len,
+                compressed.as_ptr() as *mut _,
+                compressed.len() as _,
+                0,
+                0,
+            )
+        };
+        assert_eq!(err, 0);
+        assert_eq!(dest, input);
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        assert_eq!(Compressor::new(0, 30).err(), Some(Error::Param));
+        assert_eq!(Compressor::new(10, 30).err(), Some(Error::Param));
+        assert_eq!(Compressor::new(9, 251).err(), Some(Error::Param));
+        assert_eq!(Compressor::new(u32::MAX, 30).err(), Some(Error::Param));
+    }
+
+    #[test]
+    fn sequence_error_after_stream_end() {
+        let mut compressor = Compressor::new(1, 30).unwrap();
+        let mut buf = [0u8; 128];
+
+        let progress = compressor
+            .compress(b"abc", &mut buf, Action::Finish)
+            .unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(progress.consumed, 3);
+
+        assert_eq!(
+            compressor.compress(b"abc", &mut buf, Action::Run),
+            Err(Error::Sequence)
+        );
+    }
+
+    #[test]
+    fn moved_compressor() {
+        let mut compressors = Vec::new();
+        compressors.push(Compressor::new(1, 30).unwrap());
+        let mut compressor = compressors.pop().unwrap();
+
+        let mut buf = [0u8; 128];
+        let progress = compressor.compress(b"abc", &mut buf, Action::Run).unwrap();
+        assert_eq!(progress.status, Status::RunOk);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn block_callback() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::{DecompressMode, Decompressor};
+
+        // alternate between runs, some of which straddle the block boundaries, and other data
+        let input: Vec<u8> = (0..500_000u32)
+            .map(|i| match i / 1000 % 2 {
+                0 => (i * 7919 % 251) as u8,
+                _ => (i / 50 % 7) as u8 * 31,
+            })
+            .collect();
+
+        let compressed_blocks = Arc::new(Mutex::new(Vec::new()));
+        let mut compressor = Compressor::new(1, 0).unwrap();
+        let blocks = Arc::clone(&compressed_blocks);
+        compressor.set_block_callback(move |info| blocks.lock().unwrap().push(*info));
+
+        let mut output = vec![0u8; input.len()];
+        let progress = compressor
+            .compress(&input, &mut output, Action::Finish)
+            .unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        output.truncate(progress.produced);
+
+        let compressed_blocks = compressed_blocks.lock().unwrap().clone();
+        assert!(compressed_blocks.len() > 1);
+        for (i, info) in compressed_blocks.iter().enumerate() {
+            assert_eq!(info.block, i as u32 + 1);
+        }
+        let uncompressed: u64 = compressed_blocks.iter().map(|info| info.uncompressed).sum();
+        assert_eq!(uncompressed, input.len() as u64);
+
+        // the stream header, the blocks, the end-of-stream marker and CRC, and the padding
+        let bits: u64 = compressed_blocks
+            .iter()
+            .map(|info| info.compressed_bits)
+            .sum();
+        let padding = u64::from(compressor.padding_bits());
+        assert_eq!(32 + bits + 48 + 32 + padding, output.len() as u64 * 8);
+
+        // decompression reports the same blocks, without a sorting time
+        let decompressed_blocks = Arc::new(Mutex::new(Vec::new()));
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        let blocks = Arc::clone(&decompressed_blocks);
+        decompressor.set_block_callback(move |info| blocks.lock().unwrap().push(*info));
+
+        let mut decompressed = vec![0u8; input.len()];
+        let progress = decompressor.decompress(&output, &mut decompressed).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(decompressed, input);
+
+        let decompressed_blocks = decompressed_blocks.lock().unwrap().clone();
+        let without_sort_time: Vec<_> = compressed_blocks
+            .iter()
+            .map(|info| bz_block_info {
+                sort_nanos: 0,
+                ..*info
+            })
+            .collect();
+        assert_eq!(decompressed_blocks, without_sort_time);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn log_sink() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::LogEvent;
+
+        // a repetitive block exhausts the budget of the main sort
+        let input: Vec<u8> = b"abcd".iter().copied().cycle().take(90_000).collect();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut compressor = Compressor::new(1, 1).unwrap();
+        let sink = Arc::clone(&events);
+        compressor.set_log_sink(move |event| sink.lock().unwrap().push(*event));
+        compressor.set_verbosity(4);
+
+        let mut output = vec![0u8; input.len()];
+        let progress = compressor
+            .compress(&input, &mut output, Action::Finish)
+            .unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+
+        let events = events.lock().unwrap().clone();
+        let LogEvent::CompressBlock {
+            block: 1,
+            combined_crc,
+            size,
+            ..
+        } = events[0]
+        else {
+            panic!("{:?}", events[0]);
+        };
+        assert_eq!(size, 90_000);
+        assert_eq!(events[1], LogEvent::MainSortInit);
+        assert!(events.contains(&LogEvent::SortFallback));
+        assert!(events.contains(&LogEvent::FallbackReconstruct));
+        assert_eq!(events.last(), Some(&LogEvent::CompressEnd { combined_crc }));
+        assert!(events.iter().all(|event| event.verbosity() <= 4));
+    }
+
+    #[test]
+    fn poisoned_stream() {
+        use alloc::string::ToString;
+        use core::ffi::CStr;
+
+        use crate::{bz_stream, BZ2_bzCompressGetInternalError};
+
+        let mut compressor = Compressor::new(9, 0).unwrap();
+        compressor.set_recoverable(true);
+
+        let mut output = [0u8; 1024];
+        let progress = compressor
+            .compress(b"abc", &mut output, Action::Run)
+            .unwrap();
+        assert_eq!(progress.status, Status::RunOk);
+        assert_eq!(compressor.internal_error(), None);
+
+        // pretend that a check failed during the next call
+        let s = unsafe { compressor.strm.state.as_mut() }.unwrap();
+        s.internalError = Some(InternalError(1007));
+
+        let result = compressor.compress(b"abc", &mut output, Action::Finish);
+        assert_eq!(result, Err(Error::Internal));
+        let error = compressor.internal_error().unwrap();
+        assert_eq!(error.code(), 1007);
+        assert!(error
+            .to_string()
+            .contains("libbzip2-rs: internal error number 1007."));
+
+        let strm = (compressor.stream() as *mut BzStream<EState>).cast::<bz_stream>();
+        let mut buf = [0xffu8; 32];
+        unsafe {
+            let code = BZ2_bzCompressGetInternalError(strm, buf.as_mut_ptr().cast(), 32);
+            assert_eq!(code, 1007);
+            let report = CStr::from_bytes_until_nul(&buf).unwrap();
+            assert_eq!(report.to_bytes(), b"\n\nlibbzip2-rs: internal error n");
+
+            let code = BZ2_bzCompressGetInternalError(strm, ptr::null_mut(), 0);
+            assert_eq!(code, 1007);
+        }
+    }
+
+    #[test]
+    fn block_sorter() {
+        use crate::{bz_stream, BZ2_bzCompressSetBlockSorter};
+
+        fn compress(input: &[u8], level: u32, sorter: BlockSorter) -> Vec<u8> {
+            let mut compressor = Compressor::new(level, 30).unwrap();
+            compressor.set_block_sorter(sorter);
+
+            let mut output = vec![0u8; input.len() * 2 + 1024];
+            let (mut consumed, mut produced) = (0, 0);
+            while consumed < input.len() {
+                let progress = compressor
+                    .compress(&input[consumed..], &mut output[produced..], Action::Run)
+                    .unwrap();
+                consumed += progress.consumed;
+                produced += progress.produced;
+            }
+
+            loop {
+                let progress = compressor
+                    .compress(&[], &mut output[produced..], Action::Finish)
+                    .unwrap();
+                produced += progress.produced;
+                if progress.status == Status::StreamEnd {
+                    break;
+                }
+            }
+            output.truncate(produced);
+            output
+        }
+
+        let mut random = 0x1234_5678u32;
+        let noise: Vec<u8> = (0..400_000)
+            .map(|_| {
+                random = random.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (random >> 16) as u8
+            })
+            .collect();
+
+        let mut nearly_periodic = b"abracadabra".repeat(20_000);
+        nearly_periodic.push(b'!');
+
+        let inputs: [&[u8]; 8] = [
+            SAMPLE1_REF,
+            SAMPLE2_REF,
+            &sample_input(),
+            &noise,
+            &nearly_periodic,
+            &[0; 300_000],
+            &b"xyzzy".repeat(30_000),
+            b"a",
+        ];
+
+        for input in inputs {
+            for level in [1, 9] {
+                assert_eq!(
+                    compress(input, level, BlockSorter::SuffixArray),
+                    compress(input, level, BlockSorter::Reference),
+                );
+            }
+        }
+
+        let mut compressor = Compressor::new(9, 0).unwrap();
+        let strm = (compressor.stream() as *mut BzStream<EState>).cast::<bz_stream>();
+        unsafe {
+            assert_eq!(BZ2_bzCompressSetBlockSorter(strm, 1), 0);
+            assert_eq!(BZ2_bzCompressSetBlockSorter(strm, 2), -2);
+            assert_eq!(BZ2_bzCompressSetBlockSorter(ptr::null_mut(), 0), -2);
+        }
+    }
+
+    #[test]
+    fn reset() {
+        use crate::{bz_stream, BZ2_bzCompressReset};
+
+        let input = sample_input();
+        let expected = buff_to_buff_compress(&input);
+
+        let mut compressor = Compressor::new(9, 0).unwrap();
+        let mut output = vec![0u8; input.len() + 1024];
+
+        // abandon a stream halfway through
+        let progress = compressor
+            .compress(&input[..1000], &mut output, Action::Run)
+            .unwrap();
+        assert_eq!(progress.status, Status::RunOk);
+
+        for _ in 0..2 {
+            compressor.reset();
+            assert_eq!((compressor.total_in(), compressor.total_out()), (0, 0));
+
+            let progress = compressor
+                .compress(&input, &mut output, Action::Finish)
+                .unwrap();
+            assert_eq!(progress.status, Status::StreamEnd);
+            assert_eq!(&output[..progress.produced], expected);
+        }
+
+        // a poisoned stream can be used again after a reset
+        compressor.set_recoverable(true);
+        compressor.reset();
+        let s = unsafe { compressor.strm.state.as_mut() }.unwrap();
+        s.internalError = Some(InternalError(1007));
+        let result = compressor.compress(&input, &mut output, Action::Finish);
+        assert_eq!(result, Err(Error::Internal));
+
+        let strm = (compressor.stream() as *mut BzStream<EState>).cast::<bz_stream>();
+        unsafe {
+            assert_eq!(BZ2_bzCompressReset(strm), 0);
+            assert_eq!(BZ2_bzCompressReset(ptr::null_mut()), -2);
+        }
+        assert_eq!(compressor.internal_error(), None);
+        let progress = compressor
+            .compress(&input, &mut output, Action::Finish)
+            .unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(&output[..progress.produced], expected);
+    }
+
+    #[test]
+    fn memory_usage() {
+        use crate::bzlib::FTAB_LEN;
+
+        for level in 1..=9 {
+            let compressor = Compressor::new(level, 30).unwrap();
+            let s = unsafe { compressor.strm.state.as_mut() }.unwrap();
+            let buffers = s.arr1.ptr().len() + s.arr2.eclass().len() + FTAB_LEN;
+            let allocated = core::mem::size_of::<EState>() + buffers * 4;
+
+            assert_eq!(Compressor::memory_usage(level), Some(allocated));
+        }
+
+        assert!(Compressor::memory_usage(9).unwrap() > 7_200_000);
+        assert_eq!(Compressor::memory_usage(0), None);
+        assert_eq!(Compressor::memory_usage(10), None);
+        assert_eq!(BZ2_bzCompressMemoryUsage(-1), 0);
+    }
+}
@@ -4,16 +4,22 @@ use core::ffi::c_int;
 
 use crate::allocator::Allocator;
 use crate::bzlib::{
-    index_into_f, BzStream, DSlice, DState, DecompressMode, ReturnCode, SaveArea, BZ_MAX_SELECTORS,
-    BZ_RAND_UPD_MASK, BZ_RUNA, BZ_RUNB,
+    decompress_memory_usage, index_into_f, BzStream, DState, DecompressMode, ReturnCode, SaveArea,
+    BZ_MAX_SELECTORS, BZ_RAND_UPD_MASK, BZ_RUNA, BZ_RUNB,
 };
-use crate::{debug_log, huffman};
+use crate::huffman;
+use crate::logging::LogEvent;
 
 /*-- Constants for the fast MTF decoder. --*/
 
 const MTFA_SIZE: u16 = 4096;
 const MTFL_SIZE: usize = 16;
 
+/*-- The 48-bit markers that start a block and end a stream. --*/
+
+pub(crate) const BLOCK_MAGIC: u64 = 0x314159265359;
+pub(crate) const END_OF_STREAM_MAGIC: u64 = 0x177245385090;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[allow(non_camel_case_types)]
 pub(crate) enum State {
@@ -60,6 +66,21 @@ pub(crate) enum State {
     BZ_X_CCRC_2 = 48,
     BZ_X_CCRC_3 = 49,
     BZ_X_CCRC_4 = 50,
+    /// Scanning bit by bit for the next block header or end-of-stream marker, after a lenient
+    /// decoder found a damaged block.
+    BZ_X_RESYNC = 51,
+}
+
+/// Whether [`decompress`] stops right before each block header and end-of-stream marker.
+///
+/// This is used to find the exact bit offsets of the blocks. A pause returns [`ReturnCode::BZ_OK`]
+/// without consuming the header, and decompression continues when called again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockPause {
+    Off,
+    Running,
+    Paused,
+    Resuming,
 }
 
 #[allow(non_camel_case_types)]
@@ -105,6 +126,7 @@ enum Block {
     BZ_X_CCRC_2,
     BZ_X_CCRC_3,
     BZ_X_CCRC_4,
+    BZ_X_RESYNC,
     Block1,
     Block11,
     Block18,
@@ -226,6 +248,16 @@ pub(crate) fn decompress(
             };
         }
 
+        // `strm.total_in` is only updated at the end of this function
+        macro_rules! bit_position {
+            () => {{
+                let total_in =
+                    (u64::from(strm.total_in_hi32) << 32) | u64::from(strm.total_in_lo32);
+                let bytes_read = u64::from(old_avail_in - strm.avail_in);
+                (total_in + bytes_read) * 8 - s.bsLive as u64
+            }};
+        }
+
         macro_rules! error {
             ($code:ident) => {{
                 break 'save_state_and_return ReturnCode::$code;
@@ -284,6 +316,7 @@ pub(crate) fn decompress(
             State::BZ_X_CCRC_2 => current_block = BZ_X_CCRC_2,
             State::BZ_X_CCRC_3 => current_block = BZ_X_CCRC_3,
             State::BZ_X_CCRC_4 => current_block = BZ_X_CCRC_4,
+            State::BZ_X_RESYNC => current_block = BZ_X_RESYNC,
             State::BZ_X_IDLE | State::BZ_X_OUTPUT => unreachable!(),
         }
         if current_block == BZ_X_MAGIC_2 {
@@ -319,41 +352,44 @@ pub(crate) fn decompress(
 
             s.blockSize100k -= b'0';
 
-            match s.smallDecompress {
-                DecompressMode::Small => {
-                    // SAFETY: we assume allocation is safe
-                    let ll16_len = usize::from(s.blockSize100k) * 100000;
-                    let Some(ll16) = DSlice::alloc(allocator, ll16_len) else {
-                        error!(BZ_MEM_ERROR);
-                    };
-
-                    // SAFETY: we assume allocation is safe
-                    let ll4_len = (1 + usize::from(s.blockSize100k) * 100000) >> 1;
-                    let Some(ll4) = DSlice::alloc(allocator, ll4_len) else {
-                        error!(BZ_MEM_ERROR);
-                    };
-
-                    s.ll16 = ll16;
-                    s.ll4 = ll4;
+            // a stream that needs too much memory can fall back to the small mode
+            let (max_memory, block_size) = (s.limits.max_memory, s.blockSize100k);
+            let fits = |mode| {
+                max_memory == 0 || decompress_memory_usage(mode, block_size) as u64 <= max_memory
+            };
+            s.smallDecompress = s.initMode;
+            if !fits(s.smallDecompress) {
+                if s.smallDecompress == DecompressMode::Fast
+                    && s.limits.fallback_to_small != 0
+                    && fits(DecompressMode::Small)
+                {
+                    s.smallDecompress = DecompressMode::Small;
+                } else {
+                    error!(BZ_LIMIT_EXCEEDED);
                 }
-                DecompressMode::Fast => {
-                    // SAFETY: we assume allocation is safe
-                    let tt_len = usize::from(s.blockSize100k) * 100000;
-                    let Some(tt) = DSlice::alloc(allocator, tt_len) else {
-                        error!(BZ_MEM_ERROR);
-                    };
+            }
 
-                    s.tt = tt;
-                }
+            if !s.ensure_block_buffers(allocator) {
+                error!(BZ_MEM_ERROR);
             }
 
             current_block = BZ_X_BLKHDR_1;
         }
         if current_block == BZ_X_BLKHDR_1 {
             s.state = State::BZ_X_BLKHDR_1;
+            s.blockStartBit = bit_position!();
+
+            if s.blockPause == BlockPause::Running {
+                s.blockPause = BlockPause::Paused;
+                error!(BZ_OK);
+            }
 
             uc = GET_BYTE!(strm, s);
 
+            if s.blockPause == BlockPause::Resuming {
+                s.blockPause = BlockPause::Running;
+            }
+
             match uc {
                 0x17 => current_block = BZ_X_ENDHDR_2,
                 0x31 => current_block = BZ_X_BLKHDR_2,
@@ -482,14 +518,52 @@ pub(crate) fn decompress(
                 }
 
                 s.currBlockNo += 1;
+                if s.exceeds_max_blocks() {
+                    error!(BZ_LIMIT_EXCEEDED);
+                }
                 if s.verbosity >= 2 {
-                    debug_log!("\n    [{}: huff+mtf ", s.currBlockNo);
+                    s.logSink.emit(LogEvent::DecompressBlock {
+                        block: s.currBlockNo as u32,
+                    });
                 }
                 s.storedBlockCRC = 0_u32;
                 current_block = BZ_X_BCRC_1;
             }
             _ => {}
         }
+        if current_block == BZ_X_RESYNC {
+            s.state = State::BZ_X_RESYNC;
+
+            // the marker may start at any bit, so the bits are shifted in one at a time
+            loop {
+                let bit = GET_BITS!(strm, s, 1);
+                s.resyncBuff = ((s.resyncBuff << 1) | bit) & 0xffff_ffff_ffff;
+
+                match s.resyncBuff {
+                    BLOCK_MAGIC => {
+                        s.blockStartBit = bit_position!() - 48;
+                        s.currBlockNo += 1;
+                        if s.exceeds_max_blocks() {
+                            error!(BZ_LIMIT_EXCEEDED);
+                        }
+                        if s.verbosity >= 2 {
+                            s.logSink.emit(LogEvent::DecompressBlock {
+                                block: s.currBlockNo as u32,
+                            });
+                        }
+                        s.storedBlockCRC = 0_u32;
+                        current_block = BZ_X_BCRC_1;
+                        break;
+                    }
+                    END_OF_STREAM_MAGIC => {
+                        s.storedCombinedCRC = 0_u32;
+                        current_block = BZ_X_CCRC_1;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
         match current_block {
             BZ_X_CCRC_1 => {
                 s.state = State::BZ_X_CCRC_1;
@@ -861,12 +935,20 @@ pub(crate) fn decompress(
                             if s.cftab.windows(2).any(|w| w[0] > w[1]) {
                                 error!(BZ_DATA_ERROR);
                             }
+                            if s.skipOutput {
+                                // the block was validated up to the inverse BWT, which is skipped
+                                // together with the output when only the block layout is needed
+                                s.state = State::BZ_X_BLKHDR_1;
+                                break 'save_state_and_return ReturnCode::BZ_OK;
+                            }
                             s.state_out_len = 0;
                             s.state_out_ch = 0;
                             s.calculatedBlockCRC = u32::MAX;
                             s.state = State::BZ_X_OUTPUT;
                             if s.verbosity >= 2 {
-                                debug_log!("rt+rld");
+                                s.logSink.emit(LogEvent::InverseTransform {
+                                    block: s.currBlockNo as u32,
+                                });
                             }
                             match s.smallDecompress {
                                 DecompressMode::Small => {
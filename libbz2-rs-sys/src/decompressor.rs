@@ -0,0 +1,977 @@
+use core::ffi::{c_char, c_uint};
+use core::ptr;
+
+use crate::allocator::Allocator;
+#[cfg(feature = "std")]
+use crate::bzlib::{
+    bz_block_info, call_boxed_block_callback, BZ2_bzDecompressSetBlockCallbackHelp,
+    BZ2_bzDecompressSetLogSinkHelp, BoxedBlockCallback,
+};
+use crate::bzlib::{
+    bz_decompress_limits, decompress_memory_usage, BZ2_bzDecompressEndHelp, BZ2_bzDecompressHelp,
+    BZ2_bzDecompressInitHelp, BZ2_bzDecompressNextStreamHelp, BZ2_bzDecompressResetHelp, BzStream,
+    DState, DecompressMode, Status,
+};
+use crate::decompress::BlockPause;
+#[cfg(feature = "std")]
+use crate::logging::{BoxedLogSink, LogSink};
+#[cfg(any(doc, feature = "std"))]
+use crate::LogEvent;
+use crate::{Error, Progress, ReturnCode};
+
+#[cfg(doc)]
+use crate::{
+    BZ2_bzDecompress, BZ2_bzDecompressInit, BZ2_bzDecompressMemoryUsage,
+    BZ2_bzDecompressNextStream, BZ2_bzDecompressReset, BZ2_bzDecompressSetBlockCallback,
+    BZ2_bzDecompressSetLogCallback,
+};
+
+/// A damaged block that a lenient [`Decompressor`] passed over.
+///
+/// Blocks are numbered from 1 within their stream, and bit positions are relative to the start of
+/// the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    /// The block decoded, but its data doesn't match the stored block CRC.
+    ///
+    /// All of the decoded data of the block was produced, so it is only partially wrong when the
+    /// damage is near the end of the block.
+    Crc {
+        /// The number of the block.
+        block: u32,
+        /// The CRC stored in the block header.
+        stored: u32,
+        /// The CRC of the decoded data.
+        computed: u32,
+    },
+    /// The block is malformed, e.g. its Huffman coded data contains an invalid symbol.
+    ///
+    /// When the error is found while decoding the block, none of its data is produced. When it is
+    /// found while producing the output, the data up to the error was produced already.
+    Invalid {
+        /// The number of the block.
+        block: u32,
+        /// The bit position at which the error was detected.
+        position: u64,
+    },
+}
+
+/// A safe interface to the decompression state.
+///
+/// This type drives the same state machine as [`BZ2_bzDecompress`], but takes rust slices instead
+/// of raw pointers. The state is allocated with the default allocator, and freed when the
+/// [`Decompressor`] is dropped.
+pub struct Decompressor {
+    strm: BzStream<DState>,
+    /// The closure registered with [`Decompressor::set_block_callback`]. It is boxed twice, so that
+    /// it has a thin pointer that stays put when `self` is moved.
+    #[cfg(feature = "std")]
+    block_callback: Option<std::boxed::Box<BoxedBlockCallback>>,
+    /// The closure registered with [`Decompressor::set_log_sink`], boxed twice for the same reason.
+    #[cfg(feature = "std")]
+    log_sink: Option<std::boxed::Box<BoxedLogSink>>,
+}
+
+// SAFETY: the decompressor exclusively owns its state, and the default allocators don't depend on
+// the thread that allocated the memory.
+unsafe impl Send for Decompressor {}
+
+impl Decompressor {
+    /// Prepares a new decompression stream.
+    ///
+    /// See [`BZ2_bzDecompressInit`] for the trade-off between [`DecompressMode::Fast`] and
+    /// [`DecompressMode::Small`].
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Config`] if no default allocator is configured
+    /// - [`Error::Mem`] if insufficient memory is available
+    pub fn new(mode: DecompressMode) -> Result<Self, Error> {
+        Self::with_limits(mode, bz_decompress_limits::default())
+    }
+
+    /// Prepares a new decompression stream, that fails with [`Error::LimitExceeded`] when it would
+    /// cross one of the `limits`.
+    ///
+    /// See [`bz_decompress_limits`] for the available limits. Output up to the limit is produced
+    /// before the error is reported.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Config`] if no default allocator is configured
+    /// - [`Error::Mem`] if insufficient memory is available
+    pub fn with_limits(mode: DecompressMode, limits: bz_decompress_limits) -> Result<Self, Error> {
+        if Allocator::DEFAULT.is_none() {
+            return Err(Error::Config);
+        }
+
+        let small = match mode {
+            DecompressMode::Small => 1,
+            DecompressMode::Fast => 0,
+        };
+
+        let mut strm = BzStream::zeroed();
+
+        match BZ2_bzDecompressInitHelp(&mut strm, 0, small, limits) {
+            ReturnCode::BZ_OK => Ok(Self {
+                strm,
+                #[cfg(feature = "std")]
+                block_callback: None,
+                #[cfg(feature = "std")]
+                log_sink: None,
+            }),
+            error => Err(Error::from_return_code(error)),
+        }
+    }
+
+    /// Decompresses data from `input` into `output`.
+    ///
+    /// Stops when the input is exhausted, the output is full, or the end of the stream is reached,
+    /// and reports how many bytes were consumed and produced. The returned status is either
+    /// [`Status::Ok`] when more input or output space is needed, or [`Status::StreamEnd`] when the
+    /// logical end of the stream was detected. Any input after the end of the stream is not
+    /// consumed.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Data`] if a data integrity error is detected in the compressed stream
+    /// - [`Error::DataMagic`] if the compressed stream doesn't begin with the right magic bytes
+    /// - [`Error::Mem`] if insufficient memory is available
+    /// - [`Error::Sequence`] if the end of the stream was already reached
+    /// - [`Error::LimitExceeded`] if decompression would cross one of the configured limits
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> Result<Progress, Error> {
+        let avail_in = Ord::min(input.len(), c_uint::MAX as usize) as c_uint;
+        let avail_out = Ord::min(output.len(), c_uint::MAX as usize) as c_uint;
+
+        let strm = self.stream();
+
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { strm.state.as_mut() } {
+            if s.blockPause == BlockPause::Paused {
+                s.blockPause = BlockPause::Resuming;
+            }
+            s.damage = None;
+        }
+
+        strm.next_in = input.as_ptr().cast::<c_char>();
+        strm.avail_in = avail_in;
+        strm.next_out = output.as_mut_ptr().cast::<c_char>();
+        strm.avail_out = avail_out;
+
+        let ret = BZ2_bzDecompressHelp(strm);
+
+        let consumed = (avail_in - strm.avail_in) as usize;
+        let produced = (avail_out - strm.avail_out) as usize;
+
+        // don't keep pointers to the caller's buffers around
+        strm.next_in = ptr::null();
+        strm.avail_in = 0;
+        strm.next_out = ptr::null_mut();
+        strm.avail_out = 0;
+
+        match Status::from_return_code(ret) {
+            Some(status) => Ok(Progress {
+                status,
+                consumed,
+                produced,
+            }),
+            None => Err(Error::from_return_code(ret)),
+        }
+    }
+
+    /// Makes decompression continue past damaged blocks, instead of failing with [`Error::Data`].
+    ///
+    /// A block that fails its CRC check is still produced in full. After a malformed block,
+    /// decompression searches for the next block header or end-of-stream marker and continues
+    /// from there. The combined CRC at the end of the stream is not checked once a block was
+    /// damaged.
+    ///
+    /// Each call to [`Decompressor::decompress`] stops right after a damaged block, which is then
+    /// available from [`Decompressor::take_damage`].
+    pub fn set_lenient(&mut self, lenient: bool) {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.lenient = lenient;
+        }
+    }
+
+    /// Returns the damaged block found by the most recent call to [`Decompressor::decompress`],
+    /// if any.
+    ///
+    /// Only a decompressor that was made lenient with [`Decompressor::set_lenient`] passes over
+    /// damaged blocks.
+    pub fn take_damage(&mut self) -> Option<Damage> {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        unsafe { self.strm.state.as_mut() }?.damage.take()
+    }
+
+    /// The number of bytes that a decompressor in `mode` allocates for a stream with
+    /// `block_size_100k` in its header, or `None` when the block size is invalid.
+    ///
+    /// See [`BZ2_bzDecompressMemoryUsage`], and [`bz_decompress_limits::max_memory`] to cap it.
+    pub fn memory_usage(mode: DecompressMode, block_size_100k: u32) -> Option<usize> {
+        let block_size_100k = u8::try_from(block_size_100k).ok()?;
+        (1..=9)
+            .contains(&block_size_100k)
+            .then(|| decompress_memory_usage(mode, block_size_100k))
+    }
+
+    /// The total number of bytes consumed by this stream.
+    pub fn total_in(&self) -> u64 {
+        (u64::from(self.strm.total_in_hi32) << 32) | u64::from(self.strm.total_in_lo32)
+    }
+
+    /// The total number of bytes produced by this stream.
+    pub fn total_out(&self) -> u64 {
+        (u64::from(self.strm.total_out_hi32) << 32) | u64::from(self.strm.total_out_lo32)
+    }
+
+    /// Starts over with a new stream, keeping the allocated buffers and the settings.
+    ///
+    /// This is the safe counterpart of [`BZ2_bzDecompressReset`], and is much cheaper than creating a new
+    /// [`Decompressor`] for each of many small streams. The totals start again from zero.
+    pub fn reset(&mut self) {
+        BZ2_bzDecompressResetHelp(self.stream());
+    }
+
+    /// Continues with the next of a concatenation of streams, keeping the allocated buffers and the
+    /// settings.
+    ///
+    /// This is the safe counterpart of [`BZ2_bzDecompressNextStream`]. Unlike
+    /// [`Decompressor::reset`], the limits keep counting, so that they apply to all of the streams
+    /// together. The totals of the stream start again from zero.
+    pub fn next_stream(&mut self) {
+        BZ2_bzDecompressNextStreamHelp(self.stream());
+    }
+
+    /// Calls `callback` after each block is decompressed and has passed its CRC check, replacing
+    /// any earlier callback.
+    ///
+    /// This is the safe counterpart of [`BZ2_bzDecompressSetBlockCallback`]. The callback runs on
+    /// the thread that calls [`Decompressor::decompress`], and a panic in the callback aborts the
+    /// process.
+    #[cfg(feature = "std")]
+    pub fn set_block_callback(&mut self, callback: impl FnMut(&bz_block_info) + Send + 'static) {
+        let mut callback: std::boxed::Box<BoxedBlockCallback> =
+            std::boxed::Box::new(std::boxed::Box::new(callback));
+        let opaque = (&mut *callback as *mut BoxedBlockCallback).cast::<core::ffi::c_void>();
+
+        BZ2_bzDecompressSetBlockCallbackHelp(
+            self.stream(),
+            Some(call_boxed_block_callback),
+            opaque,
+        );
+        self.block_callback = Some(callback);
+    }
+
+    /// Sets the level of diagnostic events that are emitted, from 0 (none) to 4 (all).
+    ///
+    /// Higher levels are treated as 4. The events go to the sink registered with
+    /// [`Decompressor::set_log_sink`], or to the default sink described in [`LogEvent`].
+    pub fn set_verbosity(&mut self, verbosity: u32) {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.verbosity = Ord::min(verbosity, 4) as i32;
+        }
+    }
+
+    /// Sends the diagnostic events of this stream to `sink`, replacing any earlier sink.
+    ///
+    /// This is the safe counterpart of [`BZ2_bzDecompressSetLogCallback`]. Events are only emitted when
+    /// the verbosity was raised with [`Decompressor::set_verbosity`]. The sink runs on the thread that
+    /// calls [`Decompressor::decompress`].
+    #[cfg(feature = "std")]
+    pub fn set_log_sink(&mut self, sink: impl FnMut(&LogEvent) + Send + 'static) {
+        let mut sink: std::boxed::Box<BoxedLogSink> =
+            std::boxed::Box::new(std::boxed::Box::new(sink));
+
+        BZ2_bzDecompressSetLogSinkHelp(self.stream(), LogSink::Closure(&mut *sink));
+        self.log_sink = Some(sink);
+    }
+
+    /// Makes [`Decompressor::decompress`] return right before each block header and end-of-stream
+    /// marker, so that [`Decompressor::block_boundary`] can report where a block starts.
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn pause_at_block_boundaries(&mut self) {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.blockPause = BlockPause::Running;
+        }
+    }
+
+    /// When decompression paused at a block header or end-of-stream marker, returns its bit offset
+    /// relative to the start of the stream, and the block size of the stream.
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn block_boundary(&self) -> Option<(u64, u8)> {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        let s = unsafe { self.strm.state.as_ref() }?;
+        if s.blockPause != BlockPause::Paused {
+            return None;
+        }
+
+        // the bits in the bit buffer were read from the input, but not consumed yet
+        let bits = self.total_in() * 8 - s.bsLive as u64;
+        Some((bits, s.blockSize100k))
+    }
+
+    /// Skips the inverse BWT and the output of every block, so that decompression only decodes
+    /// and validates the block headers and the Huffman coded data.
+    ///
+    /// Without the output, the block and combined CRCs are not checked.
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn skip_output(&mut self) {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.skipOutput = true;
+        }
+    }
+
+    /// The decompression state, e.g. to read the header fields of the most recent block.
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn state(&self) -> &DState {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        unsafe { &*self.strm.state }
+    }
+
+    /// Returns the stream, after pointing its consistency check at the current location.
+    ///
+    /// The state records the address of its stream, but the stream is moved along with `self`.
+    fn stream(&mut self) -> &mut BzStream<DState> {
+        // SAFETY: the state was initialized in `Decompressor::new`, and is only freed on drop
+        if let Some(s) = unsafe { self.strm.state.as_mut() } {
+            s.strm_addr = &self.strm as *const _ as usize; // FIXME use .addr() once stable
+        }
+
+        &mut self.strm
+    }
+}
+
+impl Drop for Decompressor {
+    fn drop(&mut self) {
+        BZ2_bzDecompressEndHelp(self.stream());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+
+    use alloc::vec::Vec;
+
+    use crate::test_data::{SAMPLE1_BZ2, SAMPLE1_REF, SAMPLE2_BZ2, SAMPLE2_REF};
+
+    fn decompress_all(mode: DecompressMode, input: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut decompressor = Decompressor::new(mode).unwrap();
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1000];
+
+        for chunk in input.chunks(chunk_size) {
+            let mut chunk = chunk;
+            loop {
+                let progress = decompressor.decompress(chunk, &mut buf).unwrap();
+                output.extend_from_slice(&buf[..progress.produced]);
+                chunk = &chunk[progress.consumed..];
+
+                match progress.status {
+                    Status::StreamEnd => {
+                        assert_eq!(decompressor.total_in(), input.len() as u64);
+                        assert_eq!(decompressor.total_out(), output.len() as u64);
+                        return output;
+                    }
+                    Status::Ok if chunk.is_empty() && progress.produced < buf.len() => break,
+                    status => assert_eq!(status, Status::Ok),
+                }
+            }
+        }
+
+        panic!("unexpected end of input")
+    }
+
+    #[test]
+    fn decompress_sample1() {
+        for mode in [DecompressMode::Fast, DecompressMode::Small] {
+            assert_eq!(
+                decompress_all(mode, SAMPLE1_BZ2, SAMPLE1_BZ2.len()),
+                SAMPLE1_REF
+            );
+            assert_eq!(decompress_all(mode, SAMPLE1_BZ2, 7), SAMPLE1_REF);
+        }
+    }
+
+    #[test]
+    fn trailing_input_is_not_consumed() {
+        let mut input = SAMPLE1_BZ2.to_vec();
+        input.extend_from_slice(b"trailing garbage");
+
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        let mut output = alloc::vec![0u8; SAMPLE1_REF.len()];
+
+        let progress = decompressor.decompress(&input, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(progress.consumed, SAMPLE1_BZ2.len());
+        assert_eq!(progress.produced, SAMPLE1_REF.len());
+
+        assert_eq!(
+            decompressor.decompress(b"BZh9", &mut output),
+            Err(Error::Sequence)
+        );
+    }
+
+    #[test]
+    fn data_errors() {
+        let mut output = [0u8; 1000];
+
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        assert_eq!(
+            decompressor.decompress(b"not bzip2", &mut output),
+            Err(Error::DataMagic)
+        );
+
+        let mut corrupt = SAMPLE1_BZ2.to_vec();
+        corrupt[100] ^= 0x10;
+
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        let mut error = None;
+        let mut input = corrupt.as_slice();
+        while error.is_none() && !input.is_empty() {
+            match decompressor.decompress(input, &mut output) {
+                Ok(progress) => input = &input[progress.consumed..],
+                Err(e) => error = Some(e),
+            }
+        }
+        assert_eq!(error, Some(Error::Data));
+    }
+
+    /// Decompresses `input` under the given `limits`, and returns the output up to the first error.
+    fn decompress_limited(
+        mode: DecompressMode,
+        input: &[u8],
+        limits: bz_decompress_limits,
+    ) -> (Vec<u8>, Result<(), Error>) {
+        let mut decompressor = Decompressor::with_limits(mode, limits).unwrap();
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1000];
+        let mut input = input;
+
+        loop {
+            match decompressor.decompress(input, &mut buf) {
+                Ok(progress) => {
+                    output.extend_from_slice(&buf[..progress.produced]);
+                    input = &input[progress.consumed..];
+                    if progress.status == Status::StreamEnd {
+                        return (output, Ok(()));
+                    }
+                }
+                Err(error) => return (output, Err(error)),
+            }
+        }
+    }
+
+    fn zeros_bz2(len: usize) -> Vec<u8> {
+        let mut compressor = crate::Compressor::new(9, 0).unwrap();
+        let mut output = alloc::vec![0u8; 4096];
+        let progress = compressor
+            .compress(&alloc::vec![0u8; len], &mut output, crate::Action::Finish)
+            .unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        output.truncate(progress.produced);
+        output
+    }
+
+    #[test]
+    fn output_limit() {
+        let input = zeros_bz2(2_000_000);
+
+        for mode in [DecompressMode::Fast, DecompressMode::Small] {
+            let limits = bz_decompress_limits {
+                max_output: 123_456,
+                ..Default::default()
+            };
+            let (output, result) = decompress_limited(mode, &input, limits);
+            assert_eq!(result, Err(Error::LimitExceeded));
+            assert_eq!(output.len(), 123_456);
+
+            // a stream that decompresses to exactly the limit is fine
+            let limits = bz_decompress_limits {
+                max_output: SAMPLE2_REF.len() as u64,
+                ..Default::default()
+            };
+            let (output, result) = decompress_limited(mode, SAMPLE2_BZ2, limits);
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, SAMPLE2_REF);
+        }
+    }
+
+    #[test]
+    fn ratio_limit() {
+        let input = zeros_bz2(2_000_000);
+        let limits = bz_decompress_limits {
+            max_ratio: 100,
+            ..Default::default()
+        };
+
+        let (output, result) = decompress_limited(DecompressMode::Fast, &input, limits);
+        assert_eq!(result, Err(Error::LimitExceeded));
+        assert!(output.len() <= input.len() * 100);
+
+        let (output, result) = decompress_limited(DecompressMode::Fast, SAMPLE1_BZ2, limits);
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, SAMPLE1_REF);
+    }
+
+    #[test]
+    fn block_limit() {
+        // sample2 consists of two blocks
+        let limits = bz_decompress_limits {
+            max_blocks: 1,
+            ..Default::default()
+        };
+        let (output, result) = decompress_limited(DecompressMode::Fast, SAMPLE2_BZ2, limits);
+        assert_eq!(result, Err(Error::LimitExceeded));
+        assert!(SAMPLE2_REF.starts_with(&output));
+
+        let limits = bz_decompress_limits {
+            max_blocks: 2,
+            ..Default::default()
+        };
+        let (output, result) = decompress_limited(DecompressMode::Fast, SAMPLE2_BZ2, limits);
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, SAMPLE2_REF);
+    }
+
+    #[test]
+    fn limits_span_streams() {
+        // each stream stays under the limits on its own, the concatenation does not
+        let limits = bz_decompress_limits {
+            max_output: SAMPLE1_REF.len() as u64 + 1000,
+            ..Default::default()
+        };
+        let mut decompressor = Decompressor::with_limits(DecompressMode::Fast, limits).unwrap();
+        let mut output = alloc::vec![0u8; SAMPLE1_REF.len()];
+        let progress = decompressor.decompress(SAMPLE1_BZ2, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+
+        decompressor.next_stream();
+        assert_eq!(decompressor.total_out(), 0);
+        let mut output = alloc::vec![0u8; SAMPLE1_REF.len()];
+        let progress = decompressor.decompress(SAMPLE1_BZ2, &mut output).unwrap();
+        assert_eq!(progress.produced, 1000);
+        let error = decompressor
+            .decompress(&SAMPLE1_BZ2[progress.consumed..], &mut output)
+            .unwrap_err();
+        assert_eq!(error, Error::LimitExceeded);
+
+        // a reset forgets the earlier streams
+        decompressor.reset();
+        let progress = decompressor.decompress(SAMPLE1_BZ2, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+
+        // sample1 and sample2 have one and two blocks
+        let limits = bz_decompress_limits {
+            max_blocks: 2,
+            ..Default::default()
+        };
+        let mut decompressor = Decompressor::with_limits(DecompressMode::Fast, limits).unwrap();
+        let mut output = alloc::vec![0u8; SAMPLE2_REF.len()];
+        let progress = decompressor.decompress(SAMPLE1_BZ2, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        decompressor.next_stream();
+        let error = decompressor
+            .decompress(SAMPLE2_BZ2, &mut output)
+            .unwrap_err();
+        assert_eq!(error, Error::LimitExceeded);
+    }
+
+    #[test]
+    fn c_interface_limits() {
+        use crate::{bz_stream, BZ2_bzDecompress, BZ2_bzDecompressEnd, BZ2_bzDecompressInitLimits};
+
+        let input = zeros_bz2(2_000_000);
+        let mut output = alloc::vec![0u8; 2_000_000];
+        let limits = bz_decompress_limits {
+            max_output: 1000,
+            ..Default::default()
+        };
+
+        let mut strm = bz_stream::zeroed();
+        unsafe {
+            assert_eq!(
+                BZ2_bzDecompressInitLimits(&mut strm, 0, 0, &limits),
+                crate::BZ_OK
+            );
+
+            strm.next_in = input.as_ptr().cast();
+            strm.avail_in = input.len() as _;
+            strm.next_out = output.as_mut_ptr().cast();
+            strm.avail_out = output.len() as _;
+
+            // output up to the limit is handed out first
+            assert_eq!(BZ2_bzDecompress(&mut strm), crate::BZ_OK);
+            assert_eq!(strm.total_out_lo32, 1000);
+            assert_eq!(strm.avail_out as usize, output.len() - 1000);
+
+            assert_eq!(BZ2_bzDecompress(&mut strm), crate::BZ_LIMIT_EXCEEDED);
+            assert_eq!(strm.total_out_lo32, 1000);
+
+            assert_eq!(BZ2_bzDecompressEnd(&mut strm), crate::BZ_OK);
+        }
+    }
+
+    /// Decompresses `input` leniently, and returns the output, the damaged blocks, and the result.
+    fn decompress_lenient(
+        mode: DecompressMode,
+        input: &[u8],
+    ) -> (Vec<u8>, Vec<Damage>, Result<(), Error>) {
+        let mut decompressor = Decompressor::new(mode).unwrap();
+        decompressor.set_lenient(true);
+
+        let mut output = Vec::new();
+        let mut damage = Vec::new();
+        let mut buf = [0u8; 1000];
+        let mut input = input;
+
+        loop {
+            let result = decompressor.decompress(input, &mut buf);
+            damage.extend(decompressor.take_damage());
+
+            match result {
+                Ok(progress) => {
+                    output.extend_from_slice(&buf[..progress.produced]);
+                    input = &input[progress.consumed..];
+                    match progress.status {
+                        Status::StreamEnd => return (output, damage, Ok(())),
+                        _ if input.is_empty()
+                            && progress.produced == 0
+                            && progress.consumed == 0 =>
+                        {
+                            return (output, damage, Err(Error::UnexpectedEof))
+                        }
+                        _ => {}
+                    }
+                }
+                Err(error) => return (output, damage, Err(error)),
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_crc_mismatch() {
+        // the stored CRC of the first block follows the stream header and the block magic
+        let mut corrupt = SAMPLE2_BZ2.to_vec();
+        corrupt[10] ^= 0x01;
+
+        for mode in [DecompressMode::Fast, DecompressMode::Small] {
+            let (output, damage, result) = decompress_lenient(mode, &corrupt);
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, SAMPLE2_REF);
+
+            let [Damage::Crc {
+                block,
+                stored,
+                computed,
+            }] = damage[..]
+            else {
+                panic!("unexpected damage {damage:?}");
+            };
+            assert_eq!(block, 1);
+            assert_eq!(stored ^ computed, 0x0100_0000);
+        }
+
+        // without lenient mode, the block is an error
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        let mut output = alloc::vec![0u8; SAMPLE2_REF.len()];
+        assert_eq!(
+            decompressor.decompress(&corrupt, &mut output),
+            Err(Error::Data)
+        );
+        assert_eq!(decompressor.take_damage(), None);
+    }
+
+    #[test]
+    fn lenient_invalid_block() {
+        let mut corrupt = SAMPLE2_BZ2.to_vec();
+        corrupt[2000..2100].fill(0xff);
+
+        for mode in [DecompressMode::Fast, DecompressMode::Small] {
+            let (output, damage, result) = decompress_lenient(mode, &corrupt);
+            assert_eq!(result, Ok(()));
+
+            // decoding fails within the first block, and resumes at the second one
+            let [Damage::Invalid { block, position }] = damage[..] else {
+                panic!("unexpected damage {damage:?}");
+            };
+            assert_eq!(block, 1);
+            assert!((2000 * 8..544936).contains(&position), "{position}");
+
+            assert!(!output.is_empty());
+            assert!(SAMPLE2_REF.ends_with(&output));
+        }
+    }
+
+    #[test]
+    fn lenient_damaged_block_header() {
+        // the magic of the second block starts at bit 544888
+        let mut corrupt = SAMPLE2_BZ2.to_vec();
+        corrupt[544888 / 8 + 2] ^= 0x01;
+
+        let (output, damage, result) = decompress_lenient(DecompressMode::Fast, &corrupt);
+        assert_eq!(result, Ok(()));
+
+        // the first block is intact, and decoding resumes at the end-of-stream marker
+        let [Damage::Invalid { block, .. }] = damage[..] else {
+            panic!("unexpected damage {damage:?}");
+        };
+        assert_eq!(block, 2);
+        assert!(SAMPLE2_REF.starts_with(&output));
+    }
+
+    #[test]
+    fn c_interface_block_callback() {
+        use crate::{
+            bz_block_info, bz_stream, BZ2_bzDecompress, BZ2_bzDecompressEnd, BZ2_bzDecompressInit,
+            BZ2_bzDecompressSetBlockCallback,
+        };
+
+        unsafe extern "C" fn record(opaque: *mut core::ffi::c_void, info: *const bz_block_info) {
+            let blocks = unsafe { &mut *opaque.cast::<Vec<bz_block_info>>() };
+            blocks.push(unsafe { *info });
+        }
+
+        let mut blocks: Vec<bz_block_info> = Vec::new();
+        let mut output = alloc::vec![0u8; SAMPLE2_REF.len()];
+
+        let mut strm = bz_stream::zeroed();
+        unsafe {
+            assert_eq!(BZ2_bzDecompressInit(&mut strm, 0, 0), crate::BZ_OK);
+            assert_eq!(
+                BZ2_bzDecompressSetBlockCallback(
+                    &mut strm,
+                    Some(record),
+                    (&mut blocks as *mut Vec<bz_block_info>).cast()
+                ),
+                crate::BZ_OK
+            );
+
+            strm.next_in = SAMPLE2_BZ2.as_ptr().cast();
+            strm.avail_in = SAMPLE2_BZ2.len() as _;
+            strm.next_out = output.as_mut_ptr().cast();
+            strm.avail_out = output.len() as _;
+
+            assert_eq!(BZ2_bzDecompress(&mut strm), crate::BZ_STREAM_END);
+            assert_eq!(BZ2_bzDecompressEnd(&mut strm), crate::BZ_OK);
+        }
+        assert_eq!(output, SAMPLE2_REF);
+
+        // the second block header starts at bit 544888, and the end-of-stream marker at 589772
+        assert_eq!(blocks.len(), 2);
+        assert_eq!((blocks[0].block, blocks[1].block), (1, 2));
+        assert_eq!(blocks[0].compressed_bits, 544888 - 32);
+        assert_eq!(blocks[1].compressed_bits, 589772 - 544888);
+        assert_eq!(
+            blocks[0].uncompressed + blocks[1].uncompressed,
+            SAMPLE2_REF.len() as u64
+        );
+        assert!(blocks.iter().all(|info| info.sort_nanos == 0));
+    }
+
+    #[test]
+    fn c_interface_log_callback() {
+        use crate::{
+            bz_log_event, bz_stream, BZ2_bzDecompress, BZ2_bzDecompressEnd, BZ2_bzDecompressInit,
+            BZ2_bzDecompressSetLogCallback, BZ_LOG_BLOCK_CRC, BZ_LOG_BLOCK_END,
+            BZ_LOG_COMBINED_CRC, BZ_LOG_DECOMPRESS_BLOCK, BZ_LOG_INVERSE_TRANSFORM,
+        };
+
+        use core::ffi::c_int;
+
+        type Events = Vec<(c_int, [c_uint; 9], alloc::string::String)>;
+
+        unsafe extern "C" fn record(opaque: *mut core::ffi::c_void, event: *const bz_log_event) {
+            let events = unsafe { &mut *opaque.cast::<Events>() };
+            let event = unsafe { &*event };
+            let message = unsafe { core::ffi::CStr::from_ptr(event.message) };
+            let message = message.to_str().unwrap().into();
+            events.push((event.kind, event.values, message));
+        }
+
+        let mut events: Events = Vec::new();
+        let mut output = alloc::vec![0u8; SAMPLE2_REF.len()];
+
+        let mut strm = bz_stream::zeroed();
+        unsafe {
+            assert_eq!(BZ2_bzDecompressInit(&mut strm, 3, 0), crate::BZ_OK);
+            assert_eq!(
+                BZ2_bzDecompressSetLogCallback(
+                    &mut strm,
+                    Some(record),
+                    (&mut events as *mut Events).cast()
+                ),
+                crate::BZ_OK
+            );
+
+            strm.next_in = SAMPLE2_BZ2.as_ptr().cast();
+            strm.avail_in = SAMPLE2_BZ2.len() as _;
+            strm.next_out = output.as_mut_ptr().cast();
+            strm.avail_out = output.len() as _;
+
+            assert_eq!(BZ2_bzDecompress(&mut strm), crate::BZ_STREAM_END);
+            assert_eq!(BZ2_bzDecompressEnd(&mut strm), crate::BZ_OK);
+        }
+        assert_eq!(output, SAMPLE2_REF);
+
+        let kinds: Vec<c_int> = events.iter().map(|(kind, _, _)| *kind).collect();
+        let block = [
+            BZ_LOG_DECOMPRESS_BLOCK,
+            BZ_LOG_INVERSE_TRANSFORM,
+            BZ_LOG_BLOCK_CRC,
+            BZ_LOG_BLOCK_END,
+        ];
+        assert_eq!(kinds[..4], block);
+        assert_eq!(kinds[4..8], block);
+        assert_eq!(kinds[8..], [BZ_LOG_COMBINED_CRC]);
+
+        // the stored and computed CRCs match, and the messages carry the same values
+        for (kind, values, message) in &events {
+            match *kind {
+                BZ_LOG_BLOCK_CRC => {
+                    assert_eq!(values[1], values[2]);
+                    assert!(message.contains(&alloc::format!("{:#08x}", values[1])));
+                }
+                BZ_LOG_COMBINED_CRC => assert_eq!(values[0], values[1]),
+                _ => {}
+            }
+        }
+        assert_eq!(events[4].1[0], 2);
+        assert_eq!(events[4].2, "block 2: huff+mtf");
+    }
+
+    #[test]
+    fn reset() {
+        fn decompress(decompressor: &mut Decompressor, input: &[u8]) -> Vec<u8> {
+            let mut output = alloc::vec![0u8; 1 << 20];
+            let (mut consumed, mut produced) = (0, 0);
+            loop {
+                let progress = decompressor
+                    .decompress(&input[consumed..], &mut output[produced..])
+                    .unwrap();
+                consumed += progress.consumed;
+                produced += progress.produced;
+                if progress.status == Status::StreamEnd {
+                    break;
+                }
+            }
+            assert_eq!(decompressor.total_in(), input.len() as u64);
+            output.truncate(produced);
+            output
+        }
+
+        for mode in [DecompressMode::Fast, DecompressMode::Small] {
+            let mut decompressor = Decompressor::new(mode).unwrap();
+            assert_eq!(decompress(&mut decompressor, SAMPLE1_BZ2), SAMPLE1_REF);
+
+            // the same block size reuses the buffers, a different one replaces them
+            decompressor.reset();
+            assert_eq!(decompressor.total_in(), 0);
+            assert_eq!(decompress(&mut decompressor, SAMPLE1_BZ2), SAMPLE1_REF);
+            decompressor.reset();
+            assert_eq!(decompress(&mut decompressor, SAMPLE2_BZ2), SAMPLE2_REF);
+
+            // abandon a stream halfway through
+            decompressor.reset();
+            let mut buf = [0u8; 100];
+            let progress = decompressor.decompress(SAMPLE1_BZ2, &mut buf).unwrap();
+            assert_eq!(progress.status, Status::Ok);
+            decompressor.reset();
+            assert_eq!(decompress(&mut decompressor, SAMPLE2_BZ2), SAMPLE2_REF);
+        }
+    }
+
+    #[test]
+    fn memory_usage() {
+        use crate::BZ2_bzDecompressMemoryUsage;
+
+        /// The bytes allocated by the state and its buffers.
+        fn allocated(decompressor: &Decompressor) -> usize {
+            let s = decompressor.state();
+            core::mem::size_of::<DState>()
+                + s.tt.as_slice().len() * 4
+                + s.ll16.as_slice().len() * 2
+                + s.ll4.as_slice().len()
+        }
+
+        // sample1 has a block size of 100k, sample2 of 200k
+        for (mode, small) in [(DecompressMode::Fast, 0), (DecompressMode::Small, 1)] {
+            let output = decompress_all(mode, SAMPLE2_BZ2, 1 << 20);
+            assert_eq!(output, SAMPLE2_REF);
+
+            let mut decompressor = Decompressor::new(mode).unwrap();
+            let mut buf = [0u8; 100];
+            decompressor.decompress(SAMPLE2_BZ2, &mut buf).unwrap();
+
+            let expected = Decompressor::memory_usage(mode, 2).unwrap();
+            assert_eq!(allocated(&decompressor), expected);
+            assert_eq!(BZ2_bzDecompressMemoryUsage(2, small), expected);
+        }
+
+        assert_eq!(Decompressor::memory_usage(DecompressMode::Fast, 0), None);
+        assert_eq!(Decompressor::memory_usage(DecompressMode::Small, 10), None);
+        assert_eq!(BZ2_bzDecompressMemoryUsage(9, 2), 0);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let fast = Decompressor::memory_usage(DecompressMode::Fast, 2).unwrap() as u64;
+        let small = Decompressor::memory_usage(DecompressMode::Small, 2).unwrap() as u64;
+        assert!(small < fast);
+
+        let limits = bz_decompress_limits {
+            max_memory: fast,
+            ..Default::default()
+        };
+        let (output, result) = decompress_limited(DecompressMode::Fast, SAMPLE2_BZ2, limits);
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, SAMPLE2_REF);
+
+        // the stream is refused before anything is allocated for it
+        let limits = bz_decompress_limits {
+            max_memory: fast - 1,
+            ..Default::default()
+        };
+        let (output, result) = decompress_limited(DecompressMode::Fast, SAMPLE2_BZ2, limits);
+        assert_eq!(result, Err(Error::LimitExceeded));
+        assert!(output.is_empty());
+
+        // unless it can switch to the small mode
+        let limits = bz_decompress_limits {
+            max_memory: small,
+            fallback_to_small: 1,
+            ..Default::default()
+        };
+        let mut decompressor = Decompressor::with_limits(DecompressMode::Fast, limits).unwrap();
+        let mut output = alloc::vec![0u8; SAMPLE2_REF.len()];
+        let progress = decompressor.decompress(SAMPLE2_BZ2, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(output, SAMPLE2_REF);
+        assert_eq!(decompressor.state().smallDecompress, DecompressMode::Small);
+
+        // the next stream starts in the fast mode again, and releases the small buffers
+        decompressor.reset();
+        let progress = decompressor.decompress(SAMPLE1_BZ2, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(&output[..progress.produced], SAMPLE1_REF);
+        assert_eq!(decompressor.state().smallDecompress, DecompressMode::Fast);
+        assert!(decompressor.state().ll16.as_slice().is_empty());
+
+        let limits = bz_decompress_limits {
+            max_memory: small - 1,
+            fallback_to_small: 1,
+            ..Default::default()
+        };
+        let (_, result) = decompress_limited(DecompressMode::Fast, SAMPLE2_BZ2, limits);
+        assert_eq!(result, Err(Error::LimitExceeded));
+        let (_, result) = decompress_limited(DecompressMode::Small, SAMPLE2_BZ2, limits);
+        assert_eq!(result, Err(Error::LimitExceeded));
+    }
+}
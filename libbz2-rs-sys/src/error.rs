@@ -0,0 +1,250 @@
+use core::ffi::c_int;
+use core::fmt;
+
+use crate::ReturnCode;
+
+/// The textual descriptions of the [`ReturnCode`]s, indexed by the negated return code.
+pub(crate) const BZERRORSTRINGS: [&str; 16] = [
+    "OK\0",
+    "SEQUENCE_ERROR\0",
+    "PARAM_ERROR\0",
+    "MEM_ERROR\0",
+    "DATA_ERROR\0",
+    "DATA_ERROR_MAGIC\0",
+    "IO_ERROR\0",
+    "UNEXPECTED_EOF\0",
+    "OUTBUFF_FULL\0",
+    "CONFIG_ERROR\0",
+    "LIMIT_EXCEEDED\0",
+    "INTERNAL_ERROR\0",
+    "???\0",
+    "???\0",
+    "???\0",
+    "???\0",
+];
+
+/// Errors reported by the safe rust interface.
+///
+/// Every variant corresponds to one of the negative return codes of the C interface. The `TryFrom`
+/// and `From` implementations convert between an [`Error`] and its `c_int` code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A function was called in the wrong order, e.g. after the end of the stream.
+    ///
+    /// Corresponds to [`BZ_SEQUENCE_ERROR`](crate::BZ_SEQUENCE_ERROR).
+    Sequence,
+    /// An argument was out of range.
+    ///
+    /// Corresponds to [`BZ_PARAM_ERROR`](crate::BZ_PARAM_ERROR).
+    Param,
+    /// Insufficient memory was available.
+    ///
+    /// Corresponds to [`BZ_MEM_ERROR`](crate::BZ_MEM_ERROR).
+    Mem,
+    /// A data integrity error was detected in the compressed stream.
+    ///
+    /// Corresponds to [`BZ_DATA_ERROR`](crate::BZ_DATA_ERROR).
+    Data,
+    /// The compressed stream does not begin with the right magic bytes.
+    ///
+    /// Corresponds to [`BZ_DATA_ERROR_MAGIC`](crate::BZ_DATA_ERROR_MAGIC).
+    DataMagic,
+    /// Reading from or writing to the underlying file or stream failed.
+    ///
+    /// Corresponds to [`BZ_IO_ERROR`](crate::BZ_IO_ERROR).
+    Io,
+    /// The compressed data ended before the logical end of the stream was detected.
+    ///
+    /// Corresponds to [`BZ_UNEXPECTED_EOF`](crate::BZ_UNEXPECTED_EOF).
+    UnexpectedEof,
+    /// No default allocator is configured.
+    ///
+    /// Corresponds to [`BZ_CONFIG_ERROR`](crate::BZ_CONFIG_ERROR).
+    Config,
+    /// Decompression stopped because it would cross one of the configured limits.
+    ///
+    /// Corresponds to [`BZ_LIMIT_EXCEEDED`](crate::BZ_LIMIT_EXCEEDED).
+    LimitExceeded,
+    /// An internal consistency check failed in a stream that was made recoverable.
+    ///
+    /// Corresponds to [`BZ_INTERNAL_ERROR`](crate::BZ_INTERNAL_ERROR).
+    Internal,
+}
+
+impl Error {
+    /// Maps an error [`ReturnCode`] to its rust equivalent.
+    ///
+    /// Codes that do not signal an error, and [`ReturnCode::BZ_OUTBUFF_FULL`] which the safe
+    /// interface cannot produce, are reported as [`Error::Param`].
+    pub(crate) fn from_return_code(return_code: ReturnCode) -> Self {
+        match return_code {
+            ReturnCode::BZ_SEQUENCE_ERROR => Self::Sequence,
+            ReturnCode::BZ_MEM_ERROR => Self::Mem,
+            ReturnCode::BZ_DATA_ERROR => Self::Data,
+            ReturnCode::BZ_DATA_ERROR_MAGIC => Self::DataMagic,
+            ReturnCode::BZ_IO_ERROR => Self::Io,
+            ReturnCode::BZ_UNEXPECTED_EOF => Self::UnexpectedEof,
+            ReturnCode::BZ_CONFIG_ERROR => Self::Config,
+            ReturnCode::BZ_LIMIT_EXCEEDED => Self::LimitExceeded,
+            ReturnCode::BZ_INTERNAL_ERROR => Self::Internal,
+            _ => Self::Param,
+        }
+    }
+
+    pub(crate) const fn return_code(self) -> ReturnCode {
+        match self {
+            Self::Sequence => ReturnCode::BZ_SEQUENCE_ERROR,
+            Self::Param => ReturnCode::BZ_PARAM_ERROR,
+            Self::Mem => ReturnCode::BZ_MEM_ERROR,
+            Self::Data => ReturnCode::BZ_DATA_ERROR,
+            Self::DataMagic => ReturnCode::BZ_DATA_ERROR_MAGIC,
+            Self::Io => ReturnCode::BZ_IO_ERROR,
+            Self::UnexpectedEof => ReturnCode::BZ_UNEXPECTED_EOF,
+            Self::Config => ReturnCode::BZ_CONFIG_ERROR,
+            Self::LimitExceeded => ReturnCode::BZ_LIMIT_EXCEEDED,
+            Self::Internal => ReturnCode::BZ_INTERNAL_ERROR,
+        }
+    }
+}
+
+impl From<Error> for c_int {
+    fn from(error: Error) -> Self {
+        error.return_code() as c_int
+    }
+}
+
+impl TryFrom<c_int> for Error {
+    type Error = c_int;
+
+    /// Converts a return code of the C interface into an [`Error`].
+    ///
+    /// Codes that don't have a corresponding variant, i.e. the non-negative codes and
+    /// [`BZ_OUTBUFF_FULL`](crate::BZ_OUTBUFF_FULL), are given back unchanged.
+    fn try_from(code: c_int) -> Result<Self, Self::Error> {
+        match code {
+            crate::BZ_SEQUENCE_ERROR => Ok(Self::Sequence),
+            crate::BZ_PARAM_ERROR => Ok(Self::Param),
+            crate::BZ_MEM_ERROR => Ok(Self::Mem),
+            crate::BZ_DATA_ERROR => Ok(Self::Data),
+            crate::BZ_DATA_ERROR_MAGIC => Ok(Self::DataMagic),
+            crate::BZ_IO_ERROR => Ok(Self::Io),
+            crate::BZ_UNEXPECTED_EOF => Ok(Self::UnexpectedEof),
+            crate::BZ_CONFIG_ERROR => Ok(Self::Config),
+            crate::BZ_LIMIT_EXCEEDED => Ok(Self::LimitExceeded),
+            crate::BZ_INTERNAL_ERROR => Ok(Self::Internal),
+            _ => Err(code),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    /// Uses the same text as [`BZ2_bzerror`](crate::BZ2_bzerror), e.g. `DATA_ERROR_MAGIC`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let index = -(self.return_code() as i32) as usize;
+        f.write_str(BZERRORSTRINGS[index].trim_end_matches('\0'))
+    }
+}
+
+impl core::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        use std::io::ErrorKind;
+
+        let kind = match error {
+            Error::Data | Error::DataMagic | Error::LimitExceeded => ErrorKind::InvalidData,
+            Error::UnexpectedEof => ErrorKind::UnexpectedEof,
+            Error::Param => ErrorKind::InvalidInput,
+            Error::Mem => ErrorKind::OutOfMemory,
+            Error::Sequence | Error::Io | Error::Config | Error::Internal => ErrorKind::Other,
+        };
+
+        std::io::Error::new(kind, error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    /// Recovers the original [`Error`] if `error` was created from one, and is [`Error::Io`]
+    /// otherwise.
+    fn from(error: std::io::Error) -> Self {
+        match error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Error>())
+        {
+            Some(inner) => *inner,
+            None => Self::Io,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+
+    use alloc::string::ToString;
+
+    const ALL: [Error; 10] = [
+        Error::Sequence,
+        Error::Param,
+        Error::Mem,
+        Error::Data,
+        Error::DataMagic,
+        Error::Io,
+        Error::UnexpectedEof,
+        Error::Config,
+        Error::LimitExceeded,
+        Error::Internal,
+    ];
+
+    #[test]
+    fn c_int_round_trip() {
+        for error in ALL {
+            let code = c_int::from(error);
+            assert!(code < 0);
+            assert_eq!(Error::try_from(code), Ok(error));
+        }
+
+        assert_eq!(Error::try_from(crate::BZ_OK), Err(crate::BZ_OK));
+        assert_eq!(
+            Error::try_from(crate::BZ_STREAM_END),
+            Err(crate::BZ_STREAM_END)
+        );
+        assert_eq!(
+            Error::try_from(crate::BZ_OUTBUFF_FULL),
+            Err(crate::BZ_OUTBUFF_FULL)
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Error::Sequence.to_string(), "SEQUENCE_ERROR");
+        assert_eq!(Error::Param.to_string(), "PARAM_ERROR");
+        assert_eq!(Error::Mem.to_string(), "MEM_ERROR");
+        assert_eq!(Error::Data.to_string(), "DATA_ERROR");
+        assert_eq!(Error::DataMagic.to_string(), "DATA_ERROR_MAGIC");
+        assert_eq!(Error::Io.to_string(), "IO_ERROR");
+        assert_eq!(Error::UnexpectedEof.to_string(), "UNEXPECTED_EOF");
+        assert_eq!(Error::Config.to_string(), "CONFIG_ERROR");
+        assert_eq!(Error::LimitExceeded.to_string(), "LIMIT_EXCEEDED");
+        assert_eq!(Error::Internal.to_string(), "INTERNAL_ERROR");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn io_error_round_trip() {
+        for error in ALL {
+            let io_error = std::io::Error::from(error);
+            assert_eq!(Error::from(io_error), error);
+        }
+
+        let io_error = std::io::Error::from(Error::Data);
+        assert_eq!(io_error.kind(), std::io::ErrorKind::InvalidData);
+
+        let io_error = std::io::Error::other("disk on fire");
+        assert_eq!(Error::from(io_error), Error::Io);
+    }
+}
@@ -9,17 +9,20 @@ use libc::{fclose, fdopen, ferror, fflush, fgetc, fopen, fread, fwrite, ungetc};
 use crate::allocator::Allocator;
 use crate::bzlib::prefix;
 use crate::bzlib::BZ_MAX_UNUSED_U32;
-use crate::bzlib::{bz_stream, BZ2_bzCompressEnd, BZ2_bzDecompressEnd};
+use crate::bzlib::{bz_decompress_limits, bz_stream, BZ2_bzCompressEnd, BZ2_bzDecompressEnd};
 use crate::bzlib::{Action, BzStream, ReturnCode};
 use crate::bzlib::{
     BZ2_bzCompressHelp, BZ2_bzCompressInitHelp, BZ2_bzDecompressHelp, BZ2_bzDecompressInitHelp,
+    BZ2_bzDecompressNextStreamHelp,
 };
+use crate::error::BZERRORSTRINGS;
 use crate::BZ_MAX_UNUSED;
 
 #[cfg(doc)]
 use crate::{
-    BZ2_bzCompressInit, BZ2_bzDecompressInit, BZ_CONFIG_ERROR, BZ_DATA_ERROR, BZ_DATA_ERROR_MAGIC,
-    BZ_FINISH, BZ_FINISH_OK, BZ_FLUSH, BZ_FLUSH_OK, BZ_IO_ERROR, BZ_MEM_ERROR, BZ_OK,
+    BZ2_bzCompressInit, BZ2_bzDecompressInit, BZ2_bzDecompressInitLimits,
+    BZ2_bzDecompressNextStream, BZ_CONFIG_ERROR, BZ_DATA_ERROR, BZ_DATA_ERROR_MAGIC, BZ_FINISH,
+    BZ_FINISH_OK, BZ_FLUSH, BZ_FLUSH_OK, BZ_IO_ERROR, BZ_LIMIT_EXCEEDED, BZ_MEM_ERROR, BZ_OK,
     BZ_OUTBUFF_FULL, BZ_PARAM_ERROR, BZ_RUN, BZ_RUN_OK, BZ_SEQUENCE_ERROR, BZ_STREAM_END,
     BZ_UNEXPECTED_EOF,
 };
@@ -610,7 +613,69 @@ pub unsafe extern "C" fn BZ2_bzReadOpen(
     unused: *mut c_void,
     nUnused: c_int,
 ) -> *mut BZFILE {
-    BZ2_bzReadOpenHelp(bzerror.as_mut(), f, verbosity, small, unused, nUnused)
+    BZ2_bzReadOpenHelp(
+        bzerror.as_mut(),
+        f,
+        verbosity,
+        small,
+        unused,
+        nUnused,
+        bz_decompress_limits::default(),
+    )
+}
+
+/// Prepare to read compressed data from a file handle, like [`BZ2_bzReadOpen`], and limit the
+/// resources that decompression may use.
+///
+/// When decompression would cross one of the `limits`, [`BZ2_bzRead`] sets `*bzerror` to
+/// [`BZ_LIMIT_EXCEEDED`]. A `NULL` value for `limits` means that there are no limits. See
+/// [`BZ2_bzDecompressInitLimits`] for the details.
+///
+/// A file of concatenated streams is limited as a whole when its streams are read with
+/// [`BZ2_bzReadNextStream`], instead of with a new `BZFILE` for each stream.
+///
+/// # Returns
+///
+/// - if `*bzerror` is [`BZ_OK`], a valid pointer to an abstract `BZFILE`
+/// - otherwise `NULL`
+///
+/// # Possible assignments to `bzerror`
+///
+/// See [`BZ2_bzReadOpen`].
+///
+/// # Safety
+///
+/// The caller must guarantee that
+///
+/// * `bzerror` satisfies the requirements of [`pointer::as_mut`]
+/// * Either
+///     - `unused` is `NULL`
+///     - `unused` is readable for `nUnused` bytes
+/// * Either
+///     - `limits` is `NULL`
+///     - `limits` satisfies the requirements of `&*limits`
+///
+/// [`pointer::as_mut`]: https://doc.rust-lang.org/core/primitive.pointer.html#method.as_mut
+#[export_name = prefix!(BZ2_bzReadOpenLimits)]
+pub unsafe extern "C" fn BZ2_bzReadOpenLimits(
+    bzerror: *mut c_int,
+    f: *mut FILE,
+    verbosity: c_int,
+    small: c_int,
+    unused: *mut c_void,
+    nUnused: c_int,
+    limits: *const bz_decompress_limits,
+) -> *mut BZFILE {
+    let limits = limits.as_ref().copied().unwrap_or_default();
+    BZ2_bzReadOpenHelp(
+        bzerror.as_mut(),
+        f,
+        verbosity,
+        small,
+        unused,
+        nUnused,
+        limits,
+    )
 }
 
 unsafe fn BZ2_bzReadOpenHelp(
@@ -620,6 +685,7 @@ unsafe fn BZ2_bzReadOpenHelp(
     small: c_int,
     unused: *mut c_void,
     nUnused: c_int,
+    limits: bz_decompress_limits,
 ) -> *mut BZFILE {
     let mut bzf: Option<&mut BZFILE> = None;
 
@@ -672,7 +738,7 @@ unsafe fn BZ2_bzReadOpenHelp(
         bzf.bufN += nUnused;
     }
 
-    match BZ2_bzDecompressInitHelp(BzStream::from_mut(&mut bzf.strm), verbosity, small) {
+    match BZ2_bzDecompressInitHelp(BzStream::from_mut(&mut bzf.strm), verbosity, small, limits) {
         ReturnCode::BZ_OK => {
             bzf.strm.avail_in = bzf.bufN as c_uint;
             bzf.strm.next_in = bzf.buf.as_mut_ptr().cast::<c_char>();
@@ -762,6 +828,9 @@ unsafe fn BZ2_bzReadCloseHelp(mut bzerror: Option<&mut c_int>, mut b: Option<&mu
 /// - [`BZ_DATA_ERROR`] if a data integrity error is detected in the compressed stream
 /// - [`BZ_DATA_ERROR_MAGIC`] if the compressed stream doesn't begin with the right magic bytes
 /// - [`BZ_MEM_ERROR`] if insufficient memory is available
+/// - [`BZ_LIMIT_EXCEEDED`] if decompression would cross one of the limits given to
+///   [`BZ2_bzReadOpenLimits`]. A read that produces the output up to the limit still returns that
+///   output with [`BZ_OK`], the limit is then reported by the next read
 /// - [`BZ_STREAM_END`] if the logical end-of-stream was detected
 /// - [`BZ_OK`] otherwise
 ///
@@ -858,6 +927,11 @@ unsafe fn BZ2_bzReadHelp(
                 BZ_SETERR!(bzerror, bzf, ReturnCode::BZ_STREAM_END);
                 return (len as c_uint - bzf.strm.avail_out) as c_int;
             }
+            ReturnCode::BZ_LIMIT_EXCEEDED if bzf.strm.avail_out < len as c_uint => {
+                // hand out the output up to the limit first, the next read fails again
+                BZ_SETERR!(bzerror, bzf, ReturnCode::BZ_OK);
+                return (len as c_uint - bzf.strm.avail_out) as c_int;
+            }
             error => {
                 BZ_SETERR!(bzerror, bzf, error);
                 return 0;
@@ -940,6 +1014,65 @@ unsafe fn BZ2_bzReadGetUnusedHelp(
     *unused = bzf.strm.next_in as *mut c_void;
 }
 
+/// Continues reading with the stream that follows the one that just ended.
+///
+/// The next [`BZ2_bzRead`] decompresses the data after the end of the previous stream, as if the
+/// data returned by [`BZ2_bzReadGetUnused`] had been passed to a new `BZFILE`. Unlike a new
+/// `BZFILE`, the limits given to [`BZ2_bzReadOpenLimits`] keep counting, see
+/// [`BZ2_bzDecompressNextStream`].
+///
+/// # Possible assignments to `bzerror`
+///
+/// - [`BZ_PARAM_ERROR`] if `b.is_null()`
+/// - [`BZ_SEQUENCE_ERROR`] if any of
+///     - [`BZ_STREAM_END`] has not been signaled
+///     - b was opened with [`BZ2_bzWriteOpen`]
+/// - [`BZ_IO_ERROR`] if there is an error reading from the compressed file
+/// - [`BZ_STREAM_END`] if no data follows the previous stream
+/// - [`BZ_OK`] otherwise
+///
+/// # Safety
+///
+/// The caller must guarantee that
+///
+/// * `bzerror` satisfies the requirements of [`pointer::as_mut`]
+/// * Either
+///     - `b` is `NULL`
+///     - `b` is initialized with [`BZ2_bzReadOpen`] or [`BZ2_bzWriteOpen`]
+///
+/// [`pointer::as_mut`]: https://doc.rust-lang.org/core/primitive.pointer.html#method.as_mut
+#[export_name = prefix!(BZ2_bzReadNextStream)]
+pub unsafe extern "C" fn BZ2_bzReadNextStream(bzerror: *mut c_int, b: *mut BZFILE) {
+    BZ2_bzReadNextStreamHelp(bzerror.as_mut(), b.as_mut())
+}
+
+unsafe fn BZ2_bzReadNextStreamHelp(mut bzerror: Option<&mut c_int>, mut b: Option<&mut BZFILE>) {
+    let Some(bzf) = b.as_mut() else {
+        BZ_SETERR_RAW!(bzerror, b, ReturnCode::BZ_PARAM_ERROR);
+        return;
+    };
+
+    if !matches!(bzf.operation, Operation::Reading) || bzf.lastErr != ReturnCode::BZ_STREAM_END {
+        BZ_SETERR!(bzerror, bzf, ReturnCode::BZ_SEQUENCE_ERROR);
+        return;
+    }
+
+    if ferror(bzf.handle) != 0 {
+        BZ_SETERR!(bzerror, bzf, ReturnCode::BZ_IO_ERROR);
+        return;
+    }
+
+    // the same check as in `uncompressStream` of the bzip2 binary
+    if bzf.strm.avail_in == 0 && myfeof(bzf.handle) {
+        BZ_SETERR!(bzerror, bzf, ReturnCode::BZ_STREAM_END);
+        return;
+    }
+
+    // the unused data stays where it is in the buffer
+    let ret = BZ2_bzDecompressNextStreamHelp(BzStream::from_mut(&mut bzf.strm));
+    BZ_SETERR!(bzerror, bzf, ret);
+}
+
 #[derive(Copy, Clone)]
 pub(crate) enum Operation {
     Reading,
@@ -1224,25 +1357,6 @@ unsafe fn BZ2_bzcloseHelp(mut b: Option<&mut BZFILE>) {
     }
 }
 
-const BZERRORSTRINGS: [&str; 16] = [
-    "OK\0",
-    "SEQUENCE_ERROR\0",
-    "PARAM_ERROR\0",
-    "MEM_ERROR\0",
-    "DATA_ERROR\0",
-    "DATA_ERROR_MAGIC\0",
-    "IO_ERROR\0",
-    "UNEXPECTED_EOF\0",
-    "OUTBUFF_FULL\0",
-    "CONFIG_ERROR\0",
-    "???\0",
-    "???\0",
-    "???\0",
-    "???\0",
-    "???\0",
-    "???\0",
-];
-
 /// Describes the most recent error.
 ///
 /// # Returns
@@ -1291,6 +1405,66 @@ fn BZ2_bzerrorHelp(b: &BZFILE, errnum: Option<&mut c_int>) -> *const c_char {
 mod tests {
     use super::*;
 
+    use crate::{BZ_LIMIT_EXCEEDED, BZ_OK, BZ_STREAM_END};
+    use std::vec::Vec;
+
+    use crate::test_data::{SAMPLE1_BZ2, SAMPLE1_REF};
+
+    /// Reads all of the streams in `input` with `BZ2_bzReadNextStream`, and returns the output
+    /// with the last error.
+    fn read_streams(input: &[u8], limits: *const bz_decompress_limits) -> (Vec<u8>, c_int) {
+        let file = unsafe { libc::tmpfile() };
+        assert!(!file.is_null());
+        unsafe { libc::fwrite(input.as_ptr().cast(), 1, input.len(), file) };
+        unsafe { libc::rewind(file) };
+
+        let mut bzerror = 0;
+        let b =
+            unsafe { BZ2_bzReadOpenLimits(&mut bzerror, file, 0, 0, ptr::null_mut(), 0, limits) };
+        assert_eq!(bzerror, BZ_OK);
+
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = unsafe { BZ2_bzRead(&mut bzerror, b, buf.as_mut_ptr().cast(), buf.len() as _) };
+            match bzerror {
+                BZ_OK => output.extend_from_slice(&buf[..n as usize]),
+                BZ_STREAM_END => {
+                    output.extend_from_slice(&buf[..n as usize]);
+                    unsafe { BZ2_bzReadNextStream(&mut bzerror, b) };
+                    if bzerror != BZ_OK {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        let result = bzerror;
+        unsafe { BZ2_bzReadClose(&mut bzerror, b) };
+        unsafe { libc::fclose(file) };
+
+        (output, result)
+    }
+
+    #[test]
+    fn read_next_stream() {
+        let input = [SAMPLE1_BZ2, SAMPLE1_BZ2, SAMPLE1_BZ2].concat();
+
+        let (output, result) = read_streams(&input, ptr::null());
+        assert_eq!(result, BZ_STREAM_END);
+        assert_eq!(output, [SAMPLE1_REF, SAMPLE1_REF, SAMPLE1_REF].concat());
+
+        // the limits count all of the streams
+        let limits = bz_decompress_limits {
+            max_output: 2 * SAMPLE1_REF.len() as u64 + 1000,
+            ..Default::default()
+        };
+        let (output, result) = read_streams(&input, &limits);
+        assert_eq!(result, BZ_LIMIT_EXCEEDED);
+        assert_eq!(output.len() as u64, limits.max_output);
+    }
+
     #[test]
     fn bz_error_file_is_null_ptr() {
         let mut errnum = 0;
@@ -1345,6 +1519,8 @@ mod tests {
             ReturnCode::BZ_UNEXPECTED_EOF,
             ReturnCode::BZ_OUTBUFF_FULL,
             ReturnCode::BZ_CONFIG_ERROR,
+            ReturnCode::BZ_LIMIT_EXCEEDED,
+            ReturnCode::BZ_INTERNAL_ERROR,
         ];
 
         for return_code in return_codes {
@@ -1372,6 +1548,8 @@ mod tests {
                 ReturnCode::BZ_UNEXPECTED_EOF => "UNEXPECTED_EOF",
                 ReturnCode::BZ_OUTBUFF_FULL => "OUTBUFF_FULL",
                 ReturnCode::BZ_CONFIG_ERROR => "CONFIG_ERROR",
+                ReturnCode::BZ_LIMIT_EXCEEDED => "LIMIT_EXCEEDED",
+                ReturnCode::BZ_INTERNAL_ERROR => "INTERNAL_ERROR",
             };
 
             assert_eq!(msg, expected);
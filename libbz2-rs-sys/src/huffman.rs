@@ -3,6 +3,7 @@
 use crate::{
     assert_h,
     bzlib::{BZ_MAX_ALPHA_SIZE, BZ_MAX_CODE_LEN},
+    InternalError,
 };
 
 #[inline]
@@ -59,7 +60,12 @@ fn downheap(
     heap[z] = tmp;
 }
 
-pub(crate) fn make_code_lengths(len: &mut [u8], freq: &[i32], alphaSize: usize, maxLen: i32) {
+pub(crate) fn make_code_lengths(
+    len: &mut [u8],
+    freq: &[i32],
+    alphaSize: usize,
+    maxLen: i32,
+) -> Result<(), InternalError> {
     /*--
        Nodes and heap entries run from 1.  Entry 0
        for both the heap and nodes is a sentinel.
@@ -153,6 +159,8 @@ pub(crate) fn make_code_lengths(len: &mut [u8], freq: &[i32], alphaSize: usize,
             *weight = (1 + (*weight >> 8) / 2) << 8;
         }
     }
+
+    Ok(())
 }
 
 #[inline]
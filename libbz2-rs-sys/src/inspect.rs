@@ -0,0 +1,282 @@
+//! Reports the layout of compressed data without decompressing it.
+//!
+//! Inspection decodes the stream and block headers and the Huffman coded contents of every block,
+//! and rejects the same malformed headers, tables and symbols as decompression. The inverse
+//! Burrows-Wheeler transform and the final run-length decoding, which make up most of the cost of
+//! decompression, are skipped. As a consequence the block CRCs are not verified, and neither are
+//! the bounds of the permutation that the inverse transform follows, so a block that inspects
+//! cleanly can still fail to decompress. The reported CRCs are the ones stored in the compressed
+//! data.
+//!
+//! [`inspect_verified`] makes the same single pass over the data, but decompresses the blocks
+//! too, which verifies their CRCs and reports the decompressed size of every stream.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::vec::Vec;
+
+use crate::bzlib::BZ_MAX_UNUSED_U32;
+use crate::{DecompressMode, Decompressor, Error, Status};
+
+/// The layout of a single stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+    /// The byte offset of the stream in the compressed data.
+    pub offset: u64,
+    /// The number of compressed bytes in the stream, including the header and trailer.
+    pub len: u64,
+    /// The declared block size, from 1 to 9.
+    pub block_size_100k: u8,
+    /// The combined CRC stored in the stream trailer.
+    pub stored_combined_crc: u32,
+    /// The number of bytes that the stream decompresses to, when it was decompressed by
+    /// [`inspect_verified`].
+    pub uncompressed_len: Option<u64>,
+    /// The blocks of the stream, in order.
+    pub blocks: Vec<BlockInfo>,
+}
+
+/// The layout of a single block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// The bit offset of the block header in the compressed data.
+    pub bit_offset: u64,
+    /// The number of bits in the block, up to the next block header or end-of-stream marker.
+    pub bit_len: u64,
+    /// The CRC of the decompressed block, as stored in the block header.
+    pub stored_crc: u32,
+    /// The position of the original data in the sorted rotations of the block.
+    pub orig_ptr: u32,
+    /// Whether the block was randomised, which only very old versions of bzip2 did.
+    pub randomised: bool,
+    /// The number of bytes that the Burrows-Wheeler transform was applied to, i.e. the size of the
+    /// block after the initial run-length encoding.
+    pub bwt_len: u32,
+}
+
+/// Reports the streams and blocks in the compressed data of `reader`.
+///
+/// Like [`crate::read::BzDecoder`], inspection covers a concatenation of compressed streams, and
+/// ignores data after the last stream that does not begin with the bzip2 magic bytes.
+///
+/// # Errors
+///
+/// Fails with the error of the underlying reader, or with an [`Error`] when the compressed data
+/// is invalid. Use [`Error::from`] to recover the [`Error`]. When the combined CRC of a stream
+/// does not match its stored block CRCs, the error is [`Error::Data`].
+pub fn inspect<R: Read>(reader: R) -> io::Result<Vec<StreamInfo>> {
+    inspect_streams(reader, false)
+}
+
+/// Reports the streams and blocks in the compressed data of `reader` like [`inspect`], and
+/// decompresses them in the same pass.
+///
+/// The decompressed data is discarded, but the block CRCs are verified, and
+/// [`StreamInfo::uncompressed_len`] is set. That makes this about as slow as decompression.
+///
+/// # Errors
+///
+/// Fails with the error of the underlying reader, or with an [`Error`] when the compressed data
+/// is invalid, including a mismatch of a block CRC. Use [`Error::from`] to recover the [`Error`].
+pub fn inspect_verified<R: Read>(reader: R) -> io::Result<Vec<StreamInfo>> {
+    inspect_streams(reader, true)
+}
+
+fn inspect_streams<R: Read>(reader: R, decompress: bool) -> io::Result<Vec<StreamInfo>> {
+    let mut reader = BufReader::with_capacity(BZ_MAX_UNUSED_U32 as usize, reader);
+    let mut streams = Vec::new();
+    let mut offset = 0u64;
+
+    loop {
+        if !streams.is_empty() && reader.fill_buf()?.is_empty() {
+            return Ok(streams);
+        }
+
+        match inspect_stream(&mut reader, offset, decompress) {
+            Ok(stream) => {
+                offset += stream.len;
+                streams.push(stream);
+            }
+            Err(error) if !streams.is_empty() && is_data_magic(&error) => return Ok(streams),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+fn is_data_magic(error: &io::Error) -> bool {
+    let inner = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<Error>());
+    inner == Some(&Error::DataMagic)
+}
+
+/// Inspects the stream that starts at byte `offset`, which is the current position of `reader`,
+/// and decompresses it if `decompress` is set.
+fn inspect_stream<R: BufRead>(
+    reader: &mut R,
+    offset: u64,
+    decompress: bool,
+) -> io::Result<StreamInfo> {
+    let mut out = Vec::new();
+    let mut decompressor = if decompress {
+        out.resize(64 * 1024, 0);
+        Decompressor::new(DecompressMode::Fast)?
+    } else {
+        // without output, the memory of the small mode is only used to hold the decoded symbols
+        let mut decompressor = Decompressor::new(DecompressMode::Small)?;
+        decompressor.skip_output();
+        decompressor
+    };
+    decompressor.pause_at_block_boundaries();
+
+    let mut blocks: Vec<BlockInfo> = Vec::new();
+    let mut block_size_100k = 0;
+
+    loop {
+        let input = reader.fill_buf()?;
+        let input_len = input.len();
+
+        let progress = decompressor.decompress(input, &mut out)?;
+        reader.consume(progress.consumed);
+
+        let boundary = decompressor.block_boundary();
+        if let Some((bits, level)) = boundary {
+            block_size_100k = level;
+            let bit_offset = offset * 8 + bits;
+
+            // a boundary ends the previous block, whose header fields are still in the state
+            if let Some(block) = blocks.last_mut() {
+                let s = decompressor.state();
+                block.bit_len = bit_offset - block.bit_offset;
+                block.stored_crc = s.storedBlockCRC;
+                block.orig_ptr = s.origPtr as u32;
+                block.randomised = s.blockRandomised;
+                block.bwt_len = s.save.nblock;
+            }
+
+            blocks.push(BlockInfo {
+                bit_offset,
+                bit_len: 0,
+                stored_crc: 0,
+                orig_ptr: 0,
+                randomised: false,
+                bwt_len: 0,
+            });
+        }
+
+        match progress.status {
+            Status::StreamEnd => break,
+            _ if input_len == 0 && progress.produced == 0 && boundary.is_none() => {
+                return Err(Error::UnexpectedEof.into());
+            }
+            _ => {}
+        }
+    }
+
+    // the last boundary is the end-of-stream marker
+    blocks.pop();
+
+    let stored_combined_crc = decompressor.state().storedCombinedCRC;
+    let combined_crc = blocks
+        .iter()
+        .fold(0u32, |acc, block| acc.rotate_left(1) ^ block.stored_crc);
+    if combined_crc != stored_combined_crc {
+        return Err(Error::Data.into());
+    }
+
+    Ok(StreamInfo {
+        offset,
+        len: decompressor.total_in(),
+        block_size_100k,
+        stored_combined_crc,
+        uncompressed_len: decompress.then(|| decompressor.total_out()),
+        blocks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::seek::BlockIndex;
+
+    use crate::test_data::{SAMPLE1_BZ2, SAMPLE1_REF, SAMPLE2_BZ2, SAMPLE2_REF, SAMPLE3_BZ2};
+
+    #[test]
+    fn layout() {
+        let input = [SAMPLE1_BZ2, SAMPLE2_BZ2, SAMPLE3_BZ2, b"trailing garbage"].concat();
+        let streams = inspect(input.as_slice()).unwrap();
+
+        assert_eq!(streams.len(), 3);
+        let sizes: Vec<_> = streams.iter().map(|s| s.block_size_100k).collect();
+        assert_eq!(sizes, [1, 2, 3]);
+        let lens: Vec<_> = streams.iter().map(|s| s.len as usize).collect();
+        assert_eq!(
+            lens,
+            [SAMPLE1_BZ2.len(), SAMPLE2_BZ2.len(), SAMPLE3_BZ2.len()]
+        );
+        assert_eq!(streams[1].offset, SAMPLE1_BZ2.len() as u64);
+
+        // the stored CRCs are read from the headers, and sample1 consists of a single block
+        let block = &streams[0].blocks[0];
+        assert_eq!(
+            block.stored_crc,
+            u32::from_be_bytes(SAMPLE1_BZ2[10..14].try_into().unwrap())
+        );
+        assert_eq!(streams[0].stored_combined_crc, block.stored_crc);
+
+        // the blocks are where the index finds them
+        let index = BlockIndex::build(input.as_slice()).unwrap();
+        let blocks: Vec<_> = streams.iter().flat_map(|s| &s.blocks).collect();
+        assert_eq!(blocks.len(), index.blocks().len());
+        for (block, entry) in blocks.iter().zip(index.blocks()) {
+            assert_eq!(block.bit_offset, entry.bit_offset);
+            assert_eq!(block.bit_len, entry.bit_len);
+            assert!(!block.randomised);
+            assert!(block.orig_ptr < block.bwt_len);
+        }
+    }
+
+    #[test]
+    fn verified() {
+        let input = [SAMPLE1_BZ2, SAMPLE2_BZ2, b"trailing garbage"].concat();
+        let streams = inspect_verified(input.as_slice()).unwrap();
+
+        // the same layout, plus the decompressed sizes
+        let mut inspected = inspect(input.as_slice()).unwrap();
+        inspected[0].uncompressed_len = Some(SAMPLE1_REF.len() as u64);
+        inspected[1].uncompressed_len = Some(SAMPLE2_REF.len() as u64);
+        assert_eq!(streams, inspected);
+
+        // a corrupt block CRC is detected, even when the combined CRC matches it
+        let mut corrupt = SAMPLE1_BZ2.to_vec();
+        corrupt[12] ^= 0x10;
+        let block = streams[0].blocks[0];
+        let bit = block.bit_offset + block.bit_len + 48 + 19;
+        corrupt[(bit / 8) as usize] ^= 0x80 >> (bit % 8);
+        assert!(inspect(corrupt.as_slice()).is_ok());
+        let error = inspect_verified(corrupt.as_slice()).unwrap_err();
+        assert_eq!(Error::from(error), Error::Data);
+    }
+
+    #[test]
+    fn errors() {
+        let error = inspect(&b"not bzip2"[..]).unwrap_err();
+        assert_eq!(Error::from(error), Error::DataMagic);
+
+        let truncated = &SAMPLE2_BZ2[..SAMPLE2_BZ2.len() - 10];
+        let error = inspect(truncated).unwrap_err();
+        assert_eq!(Error::from(error), Error::UnexpectedEof);
+
+        // a corrupt block CRC goes unnoticed, but it no longer matches the combined CRC
+        let mut corrupt = SAMPLE1_BZ2.to_vec();
+        corrupt[12] ^= 0x10;
+        let error = inspect(corrupt.as_slice()).unwrap_err();
+        assert_eq!(Error::from(error), Error::Data);
+
+        // corrupt Huffman coded data is detected
+        let mut corrupt = SAMPLE1_BZ2.to_vec();
+        corrupt[40] ^= 0xff;
+        let error = inspect(corrupt.as_slice()).unwrap_err();
+        assert_eq!(Error::from(error), Error::Data);
+    }
+}
@@ -14,17 +14,52 @@ use core::ffi::c_int;
 use core::sync::atomic::{AtomicI32, Ordering};
 
 mod allocator;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+mod async_io;
+#[cfg(feature = "std")]
+mod block;
 mod blocksort;
+#[cfg(feature = "std")]
+pub mod bufread;
 mod bzlib;
 mod compress;
+mod compressor;
 mod crctable;
 mod decompress;
+mod decompressor;
+mod error;
 #[cfg(feature = "stdio")]
 mod high_level;
 mod huffman;
+#[cfg(feature = "std")]
+pub mod inspect;
+mod logging;
+#[cfg(feature = "parallel")]
+mod parallel;
 mod randtable;
+#[cfg(feature = "std")]
+pub mod read;
+mod sais;
+#[cfg(feature = "std")]
+pub mod seek;
+pub mod write;
+
+/// The samples of the test suite, shared by the unit tests so that they are embedded only once.
+#[cfg(test)]
+pub(crate) mod test_data {
+    // which of the samples are used depends on the enabled features
+    #![allow(dead_code)]
+
+    pub(crate) const SAMPLE1_REF: &[u8] = include_bytes!("../../tests/input/quick/sample1.ref");
+    pub(crate) const SAMPLE1_BZ2: &[u8] = include_bytes!("../../tests/input/quick/sample1.bz2");
+    pub(crate) const SAMPLE2_REF: &[u8] = include_bytes!("../../tests/input/quick/sample2.ref");
+    pub(crate) const SAMPLE2_BZ2: &[u8] = include_bytes!("../../tests/input/quick/sample2.bz2");
+    pub(crate) const SAMPLE3_REF: &[u8] = include_bytes!("../../tests/input/quick/sample3.ref");
+    pub(crate) const SAMPLE3_BZ2: &[u8] = include_bytes!("../../tests/input/quick/sample3.bz2");
+}
 
-pub(crate) use bzlib::{Action, ReturnCode};
+pub use bzlib::Action;
+pub(crate) use bzlib::ReturnCode;
 
 pub const BZ_OK: c_int = ReturnCode::BZ_OK as c_int;
 pub const BZ_RUN_OK: c_int = ReturnCode::BZ_RUN_OK as c_int;
@@ -40,6 +75,8 @@ pub const BZ_IO_ERROR: c_int = ReturnCode::BZ_IO_ERROR as c_int;
 pub const BZ_UNEXPECTED_EOF: c_int = ReturnCode::BZ_UNEXPECTED_EOF as c_int;
 pub const BZ_OUTBUFF_FULL: c_int = ReturnCode::BZ_OUTBUFF_FULL as c_int;
 pub const BZ_CONFIG_ERROR: c_int = ReturnCode::BZ_CONFIG_ERROR as c_int;
+pub const BZ_LIMIT_EXCEEDED: c_int = ReturnCode::BZ_LIMIT_EXCEEDED as c_int;
+pub const BZ_INTERNAL_ERROR: c_int = ReturnCode::BZ_INTERNAL_ERROR as c_int;
 
 pub const BZ_RUN: c_int = Action::Run as c_int;
 pub const BZ_FLUSH: c_int = Action::Flush as c_int;
@@ -47,21 +84,54 @@ pub const BZ_FINISH: c_int = Action::Finish as c_int;
 
 pub const BZ_MAX_UNUSED: c_int = bzlib::BZ_MAX_UNUSED_U32 as c_int;
 
+pub use logging::{
+    BZ_LOG_BLOCK_CRC, BZ_LOG_BLOCK_END, BZ_LOG_COMBINED_CRC, BZ_LOG_COMPRESS_BLOCK,
+    BZ_LOG_COMPRESS_END, BZ_LOG_DECOMPRESS_BLOCK, BZ_LOG_FALLBACK_BUCKET_SORT,
+    BZ_LOG_FALLBACK_DEPTH, BZ_LOG_FALLBACK_RECONSTRUCT, BZ_LOG_HUFFMAN_CODED, BZ_LOG_HUFFMAN_PASS,
+    BZ_LOG_INITIAL_GROUP, BZ_LOG_INVERSE_TRANSFORM, BZ_LOG_MAIN_BUCKET_SORT, BZ_LOG_MAIN_SORT_DONE,
+    BZ_LOG_MAIN_SORT_INIT, BZ_LOG_MTF_CODED, BZ_LOG_QUICK_SORT, BZ_LOG_SORT_FALLBACK,
+    BZ_LOG_SORT_WORK,
+};
+
 // types
-pub use bzlib::bz_stream;
 #[cfg(feature = "stdio")]
 pub use bzlib::BZFILE;
+pub use bzlib::{bz_block_callback, bz_block_info, bz_decompress_limits, bz_stream};
+pub use logging::{bz_log_callback, bz_log_event};
 
 // the low-level interface
-pub use bzlib::{BZ2_bzCompress, BZ2_bzCompressEnd, BZ2_bzCompressInit};
-pub use bzlib::{BZ2_bzDecompress, BZ2_bzDecompressEnd, BZ2_bzDecompressInit};
+pub use bzlib::{
+    BZ2_bzCompress, BZ2_bzCompressEnd, BZ2_bzCompressGetInternalError, BZ2_bzCompressInit,
+    BZ2_bzCompressMemoryUsage, BZ2_bzCompressReset, BZ2_bzCompressSetBlockCallback,
+    BZ2_bzCompressSetBlockSorter, BZ2_bzCompressSetLogCallback, BZ2_bzCompressSetRecoverable,
+};
+pub use bzlib::{
+    BZ2_bzDecompress, BZ2_bzDecompressEnd, BZ2_bzDecompressInit, BZ2_bzDecompressInitLimits,
+    BZ2_bzDecompressMemoryUsage, BZ2_bzDecompressNextStream, BZ2_bzDecompressReset,
+    BZ2_bzDecompressSetBlockCallback, BZ2_bzDecompressSetLogCallback,
+};
 
 // utility functions
 pub use bzlib::{BZ2_bzBuffToBuffCompress, BZ2_bzBuffToBuffDecompress};
 
+// the safe rust interface
+pub use bzlib::{BlockSorter, DecompressMode, Status};
+pub use compressor::{Compressor, Progress};
+pub use decompressor::{Damage, Decompressor};
+pub use error::Error;
+pub use logging::LogEvent;
+
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub use async_io::{AsyncBzDecoder, AsyncBzEncoder};
+#[cfg(feature = "parallel")]
+pub use parallel::{ParallelCompressor, ParallelDecompressor};
+
 // the high-level interface
 #[cfg(feature = "stdio")]
-pub use bzlib::{BZ2_bzRead, BZ2_bzReadClose, BZ2_bzReadGetUnused, BZ2_bzReadOpen};
+pub use bzlib::{
+    BZ2_bzRead, BZ2_bzReadClose, BZ2_bzReadGetUnused, BZ2_bzReadNextStream, BZ2_bzReadOpen,
+    BZ2_bzReadOpenLimits,
+};
 #[cfg(feature = "stdio")]
 pub use bzlib::{BZ2_bzWrite, BZ2_bzWriteClose, BZ2_bzWriteClose64, BZ2_bzWriteOpen};
 
@@ -82,7 +152,7 @@ macro_rules! libbz2_rs_sys_version {
 
 pub(crate) use libbz2_rs_sys_version;
 
-// --- debug logs
+// --- stderr output of the default log sink
 
 #[cfg(all(not(feature = "std"), feature = "stdio"))]
 pub(crate) struct StderrWritter;
@@ -99,39 +169,13 @@ impl core::fmt::Write for StderrWritter {
     }
 }
 
-macro_rules! debug_log {
-    ($($arg:tt)*) => {
-        #[cfg(feature = "std")]
-        std::eprint!($($arg)*);
-        #[cfg(all(not(feature = "std"), feature = "stdio"))]
-        {
-            use core::fmt::Write;
-            let _ = write!($crate::StderrWritter, $($arg)*);
-        }
-    };
-}
-
-macro_rules! debug_logln {
-    ($($arg:tt)*) => {
-        #[cfg(feature = "std")]
-        std::eprintln!($($arg)*);
-        #[cfg(all(not(feature = "std"), feature = "stdio"))]
-        {
-            use core::fmt::Write;
-            let _ = writeln!($crate::StderrWritter, $($arg)*);
-        }
-    };
-}
-
-pub(crate) use debug_log;
-pub(crate) use debug_logln;
-
 // --- assert failure logic
 
+/// Fails the surrounding function with an [`InternalError`] when `$condition` does not hold.
 macro_rules! assert_h {
     ($condition:expr, $errcode:expr) => {
         if !$condition {
-            $crate::handle_assert_failure($errcode)
+            return Err($crate::InternalError($errcode));
         }
     };
 }
@@ -140,10 +184,12 @@ macro_rules! assert_h {
 #[doc(hidden)]
 pub static ASSERT_CODE: AtomicI32 = AtomicI32::new(-1);
 
+/// Reports an internal error of a stream that wasn't made recoverable, like the reference
+/// implementation does.
 #[cold]
-fn handle_assert_failure(errcode: c_int) -> ! {
+fn handle_assert_failure(InternalError(errcode): InternalError) -> ! {
     #[cfg(feature = "std")]
-    std::eprint!("{}", AssertFail(errcode));
+    std::eprint!("{}", InternalError(errcode));
     #[cfg(feature = "std")]
     std::process::exit(3);
 
@@ -153,14 +199,29 @@ fn handle_assert_failure(errcode: c_int) -> ! {
     #[allow(clippy::unnecessary_cast)]
     ASSERT_CODE.store(errcode as i32, Ordering::Relaxed);
     #[cfg(not(feature = "std"))]
-    panic!("{}", AssertFail(errcode));
+    panic!("{}", InternalError(errcode));
 }
 
 use assert_h;
 
-struct AssertFail(i32);
+/// A failed internal consistency check, which indicates a bug or unreliable hardware.
+///
+/// It is only reported by streams that were made recoverable, see [`BZ2_bzCompressSetRecoverable`].
+/// Otherwise the process is terminated. The [`Display`](core::fmt::Display) implementation gives
+/// the report that would have been printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InternalError(c_int);
+
+impl InternalError {
+    /// The number of the failed check, e.g. 1007.
+    pub fn code(&self) -> c_int {
+        self.0
+    }
+}
+
+impl core::error::Error for InternalError {}
 
-impl core::fmt::Display for AssertFail {
+impl core::fmt::Display for InternalError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
@@ -229,6 +290,6 @@ mod test {
     #[test]
     fn print_assert_fail_coverage() {
         use core::fmt::Write;
-        write!(&mut String::new(), "{}", AssertFail(1007)).unwrap();
+        write!(&mut String::new(), "{}", InternalError(1007)).unwrap();
     }
 }
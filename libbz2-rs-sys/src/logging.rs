@@ -0,0 +1,792 @@
+//! Diagnostic events, reported when a stream is configured with a verbosity above 0.
+//!
+//! By default the events are written to stderr as text, in the same format as the reference
+//! implementation. With the `tracing` feature they are emitted as `tracing` events with structured
+//! fields, and with the `log` feature as `log` records with the same fields as key-values. A
+//! per-stream callback, registered with
+//! [`BZ2_bzCompressSetLogCallback`] or [`BZ2_bzDecompressSetLogCallback`], overrides either.
+
+use core::ffi::{c_char, c_int, c_uint, c_void};
+use core::fmt;
+
+use crate::bzlib::TruncatingWriter;
+
+#[cfg(doc)]
+use crate::{BZ2_bzCompressSetLogCallback, BZ2_bzDecompressSetLogCallback};
+
+/// The `log` target and `tracing` target of all events.
+#[cfg(any(feature = "log", feature = "tracing"))]
+const TARGET: &str = "libbz2_rs_sys";
+
+/// The maximum length of [`bz_log_event::message`], including the terminating nul byte.
+const MESSAGE_LEN: usize = 128;
+
+/// A diagnostic event, emitted when the verbosity of the stream is at least
+/// [`LogEvent::verbosity`].
+///
+/// Blocks are numbered from 1 within their stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum LogEvent {
+    /// A block is about to be sorted and compressed.
+    CompressBlock {
+        block: u32,
+        crc: u32,
+        combined_crc: u32,
+        size: u32,
+    },
+    /// The main sorting algorithm finished, or ran out of its work budget.
+    SortWork { work: u32, block_size: u32 },
+    /// The block is too repetitive for the main sorting algorithm, and is sorted again with the
+    /// fallback algorithm.
+    SortFallback,
+    /// The fallback sort starts its initial radix sort.
+    FallbackBucketSort,
+    /// The fallback sort refined the block at sorting depth `depth`.
+    FallbackDepth { depth: u32, unresolved: u32 },
+    /// The fallback sort reconstructs the block.
+    FallbackReconstruct,
+    /// The main sort sets up its frequency table.
+    MainSortInit,
+    /// The main sort starts its initial radix sort.
+    MainBucketSort,
+    /// The main sort quicksorts the small bucket `[big, small]`.
+    QuickSort {
+        big: u32,
+        small: u32,
+        sorted: u32,
+        size: u32,
+    },
+    /// The main sort is done.
+    MainSortDone {
+        pointers: u32,
+        sorted: u32,
+        scanned: u32,
+    },
+    /// The sorted block was move-to-front and run-length coded.
+    MtfCoded {
+        block_size: u32,
+        mtf_symbols: u32,
+        in_use: u32,
+    },
+    /// The initial assignment of symbols to a Huffman table.
+    InitialGroup {
+        group: u32,
+        start: u32,
+        end: u32,
+        frequency: u32,
+        mtf_symbols: u32,
+    },
+    /// An iteration of Huffman table refinement, with the estimated size in bytes and how often
+    /// each of the first `groups` tables is used.
+    HuffmanPass {
+        pass: u32,
+        size: u32,
+        groups: u32,
+        group_uses: [u32; 6],
+    },
+    /// The number of bytes written for each part of the coded block.
+    HuffmanCoded {
+        mapping: u32,
+        selectors: u32,
+        code_lengths: u32,
+        codes: u32,
+    },
+    /// The stream trailer is written.
+    CompressEnd { combined_crc: u32 },
+    /// A block header was found, and its Huffman and move-to-front coded data is decoded.
+    DecompressBlock { block: u32 },
+    /// The decoded block is unsorted, and its runs are expanded.
+    InverseTransform { block: u32 },
+    /// All of the data of a block was produced.
+    BlockCrc {
+        block: u32,
+        stored: u32,
+        computed: u32,
+    },
+    /// The block is done.
+    BlockEnd { block: u32 },
+    /// The end of the stream was found.
+    CombinedCrc { stored: u32, computed: u32 },
+}
+
+/// See [`LogEvent::CompressBlock`]; values: block, crc, combined crc, size.
+pub const BZ_LOG_COMPRESS_BLOCK: c_int = 1;
+/// See [`LogEvent::SortWork`]; values: work, block size.
+pub const BZ_LOG_SORT_WORK: c_int = 2;
+/// See [`LogEvent::SortFallback`]; no values.
+pub const BZ_LOG_SORT_FALLBACK: c_int = 3;
+/// See [`LogEvent::FallbackBucketSort`]; no values.
+pub const BZ_LOG_FALLBACK_BUCKET_SORT: c_int = 4;
+/// See [`LogEvent::FallbackDepth`]; values: depth, unresolved.
+pub const BZ_LOG_FALLBACK_DEPTH: c_int = 5;
+/// See [`LogEvent::FallbackReconstruct`]; no values.
+pub const BZ_LOG_FALLBACK_RECONSTRUCT: c_int = 6;
+/// See [`LogEvent::MainSortInit`]; no values.
+pub const BZ_LOG_MAIN_SORT_INIT: c_int = 7;
+/// See [`LogEvent::MainBucketSort`]; no values.
+pub const BZ_LOG_MAIN_BUCKET_SORT: c_int = 8;
+/// See [`LogEvent::QuickSort`]; values: big bucket, small bucket, sorted, size.
+pub const BZ_LOG_QUICK_SORT: c_int = 9;
+/// See [`LogEvent::MainSortDone`]; values: pointers, sorted, scanned.
+pub const BZ_LOG_MAIN_SORT_DONE: c_int = 10;
+/// See [`LogEvent::MtfCoded`]; values: block size, MTF symbols, symbols in use.
+pub const BZ_LOG_MTF_CODED: c_int = 11;
+/// See [`LogEvent::InitialGroup`]; values: group, start, end, frequency, MTF symbols.
+pub const BZ_LOG_INITIAL_GROUP: c_int = 12;
+/// See [`LogEvent::HuffmanPass`]; values: pass, size, groups, followed by the use of each group.
+pub const BZ_LOG_HUFFMAN_PASS: c_int = 13;
+/// See [`LogEvent::HuffmanCoded`]; values: mapping, selectors, code lengths, codes.
+pub const BZ_LOG_HUFFMAN_CODED: c_int = 14;
+/// See [`LogEvent::CompressEnd`]; values: combined crc.
+pub const BZ_LOG_COMPRESS_END: c_int = 15;
+/// See [`LogEvent::DecompressBlock`]; values: block.
+pub const BZ_LOG_DECOMPRESS_BLOCK: c_int = 16;
+/// See [`LogEvent::InverseTransform`]; values: block.
+pub const BZ_LOG_INVERSE_TRANSFORM: c_int = 17;
+/// See [`LogEvent::BlockCrc`]; values: block, stored crc, computed crc.
+pub const BZ_LOG_BLOCK_CRC: c_int = 18;
+/// See [`LogEvent::BlockEnd`]; values: block.
+pub const BZ_LOG_BLOCK_END: c_int = 19;
+/// See [`LogEvent::CombinedCrc`]; values: stored crc, computed crc.
+pub const BZ_LOG_COMBINED_CRC: c_int = 20;
+
+impl LogEvent {
+    /// The lowest verbosity at which the event is emitted.
+    pub fn verbosity(&self) -> i32 {
+        match self {
+            Self::CompressBlock { .. }
+            | Self::SortFallback
+            | Self::CompressEnd { .. }
+            | Self::DecompressBlock { .. }
+            | Self::InverseTransform { .. }
+            | Self::BlockEnd { .. } => 2,
+            Self::SortWork { .. }
+            | Self::MtfCoded { .. }
+            | Self::InitialGroup { .. }
+            | Self::HuffmanPass { .. }
+            | Self::HuffmanCoded { .. }
+            | Self::BlockCrc { .. }
+            | Self::CombinedCrc { .. } => 3,
+            Self::FallbackBucketSort
+            | Self::FallbackDepth { .. }
+            | Self::FallbackReconstruct
+            | Self::MainSortInit
+            | Self::MainBucketSort
+            | Self::QuickSort { .. }
+            | Self::MainSortDone { .. } => 4,
+        }
+    }
+
+    /// The `BZ_LOG_*` kind of the event, and its values in the order of the C interface.
+    fn raw(&self) -> (c_int, [c_uint; 9]) {
+        let mut values = [0; 9];
+
+        let kind = match *self {
+            Self::CompressBlock {
+                block,
+                crc,
+                combined_crc,
+                size,
+            } => {
+                values[..4].copy_from_slice(&[block, crc, combined_crc, size]);
+                BZ_LOG_COMPRESS_BLOCK
+            }
+            Self::SortWork { work, block_size } => {
+                values[..2].copy_from_slice(&[work, block_size]);
+                BZ_LOG_SORT_WORK
+            }
+            Self::SortFallback => BZ_LOG_SORT_FALLBACK,
+            Self::FallbackBucketSort => BZ_LOG_FALLBACK_BUCKET_SORT,
+            Self::FallbackDepth { depth, unresolved } => {
+                values[..2].copy_from_slice(&[depth, unresolved]);
+                BZ_LOG_FALLBACK_DEPTH
+            }
+            Self::FallbackReconstruct => BZ_LOG_FALLBACK_RECONSTRUCT,
+            Self::MainSortInit => BZ_LOG_MAIN_SORT_INIT,
+            Self::MainBucketSort => BZ_LOG_MAIN_BUCKET_SORT,
+            Self::QuickSort {
+                big,
+                small,
+                sorted,
+                size,
+            } => {
+                values[..4].copy_from_slice(&[big, small, sorted, size]);
+                BZ_LOG_QUICK_SORT
+            }
+            Self::MainSortDone {
+                pointers,
+                sorted,
+                scanned,
+            } => {
+                values[..3].copy_from_slice(&[pointers, sorted, scanned]);
+                BZ_LOG_MAIN_SORT_DONE
+            }
+            Self::MtfCoded {
+                block_size,
+                mtf_symbols,
+                in_use,
+            } => {
+                values[..3].copy_from_slice(&[block_size, mtf_symbols, in_use]);
+                BZ_LOG_MTF_CODED
+            }
+            Self::InitialGroup {
+                group,
+                start,
+                end,
+                frequency,
+                mtf_symbols,
+            } => {
+                values[..5].copy_from_slice(&[group, start, end, frequency, mtf_symbols]);
+                BZ_LOG_INITIAL_GROUP
+            }
+            Self::HuffmanPass {
+                pass,
+                size,
+                groups,
+                group_uses,
+            } => {
+                values[..3].copy_from_slice(&[pass, size, groups]);
+                values[3..].copy_from_slice(&group_uses);
+                BZ_LOG_HUFFMAN_PASS
+            }
+            Self::HuffmanCoded {
+                mapping,
+                selectors,
+                code_lengths,
+                codes,
+            } => {
+                values[..4].copy_from_slice(&[mapping, selectors, code_lengths, codes]);
+                BZ_LOG_HUFFMAN_CODED
+            }
+            Self::CompressEnd { combined_crc } => {
+                values[0] = combined_crc;
+                BZ_LOG_COMPRESS_END
+            }
+            Self::DecompressBlock { block } => {
+                values[0] = block;
+                BZ_LOG_DECOMPRESS_BLOCK
+            }
+            Self::InverseTransform { block } => {
+                values[0] = block;
+                BZ_LOG_INVERSE_TRANSFORM
+            }
+            Self::BlockCrc {
+                block,
+                stored,
+                computed,
+            } => {
+                values[..3].copy_from_slice(&[block, stored, computed]);
+                BZ_LOG_BLOCK_CRC
+            }
+            Self::BlockEnd { block } => {
+                values[0] = block;
+                BZ_LOG_BLOCK_END
+            }
+            Self::CombinedCrc { stored, computed } => {
+                values[..2].copy_from_slice(&[stored, computed]);
+                BZ_LOG_COMBINED_CRC
+            }
+        };
+
+        (kind, values)
+    }
+
+    /// The names of the values of [`LogEvent::raw`], which match the fields of the `tracing` events.
+    ///
+    /// The group uses of [`LogEvent::HuffmanPass`] are not included.
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    fn field_names(&self) -> &'static [&'static str] {
+        match self {
+            Self::CompressBlock { .. } => &["block", "crc", "combined_crc", "size"],
+            Self::SortWork { .. } => &["work", "block_size"],
+            Self::SortFallback
+            | Self::FallbackBucketSort
+            | Self::FallbackReconstruct
+            | Self::MainSortInit
+            | Self::MainBucketSort => &[],
+            Self::FallbackDepth { .. } => &["depth", "unresolved"],
+            Self::QuickSort { .. } => &["big", "small", "sorted", "size"],
+            Self::MainSortDone { .. } => &["pointers", "sorted", "scanned"],
+            Self::MtfCoded { .. } => &["block_size", "mtf_symbols", "in_use"],
+            Self::InitialGroup { .. } => &["group", "start", "end", "frequency", "mtf_symbols"],
+            Self::HuffmanPass { .. } => &["pass", "size", "groups"],
+            Self::HuffmanCoded { .. } => &["mapping", "selectors", "code_lengths", "codes"],
+            Self::CompressEnd { .. } => &["combined_crc"],
+            Self::DecompressBlock { .. }
+            | Self::InverseTransform { .. }
+            | Self::BlockEnd { .. } => &["block"],
+            Self::BlockCrc { .. } => &["block", "stored", "computed"],
+            Self::CombinedCrc { .. } => &["stored", "computed"],
+        }
+    }
+
+    /// Writes the event in the layout of the reference implementation, which spreads the
+    /// decompression events of a block over a single line.
+    #[cfg(all(
+        not(feature = "log"),
+        not(feature = "tracing"),
+        any(feature = "std", feature = "stdio")
+    ))]
+    fn write_text(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        match *self {
+            Self::CompressBlock { .. } | Self::SortFallback | Self::CompressEnd { .. } => {
+                write!(f, "    {self}")?
+            }
+            Self::SortWork { .. }
+            | Self::MtfCoded { .. }
+            | Self::InitialGroup { .. }
+            | Self::HuffmanPass { .. } => write!(f, "      {self}")?,
+            Self::HuffmanCoded {
+                mapping,
+                selectors,
+                code_lengths,
+                codes,
+            } => write!(
+                f,
+                "      bytes: mapping {mapping}, selectors {selectors}, \
+                 code lengths {code_lengths}, codes {codes}"
+            )?,
+            Self::FallbackBucketSort
+            | Self::FallbackDepth { .. }
+            | Self::FallbackReconstruct
+            | Self::MainSortInit
+            | Self::MainBucketSort
+            | Self::QuickSort { .. }
+            | Self::MainSortDone { .. } => write!(f, "        {self}")?,
+            Self::DecompressBlock { block } => return write!(f, "\n    [{block}: huff+mtf "),
+            Self::InverseTransform { .. } => return f.write_str("rt+rld"),
+            Self::BlockCrc {
+                stored, computed, ..
+            } => return write!(f, " {{{stored:#08x}, {computed:#08x}}}"),
+            Self::BlockEnd { .. } => return f.write_str("]"),
+            Self::CombinedCrc { .. } => return write!(f, "\n    {self}"),
+        }
+
+        match self {
+            Self::HuffmanPass { .. } => f.write_str(" \n"),
+            Self::CompressEnd { .. } => f.write_str("\n   "),
+            _ => f.write_str("\n"),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn trace(&self) {
+        use tracing::{debug, trace};
+
+        match *self {
+            Self::CompressBlock {
+                block,
+                crc,
+                combined_crc,
+                size,
+            } => debug!(target: TARGET, block, crc, combined_crc, size, "{self}"),
+            Self::SortWork { work, block_size } => {
+                trace!(target: TARGET, work, block_size, "{self}")
+            }
+            Self::SortFallback => debug!(target: TARGET, "{self}"),
+            Self::FallbackDepth { depth, unresolved } => {
+                trace!(target: TARGET, depth, unresolved, "{self}")
+            }
+            Self::FallbackBucketSort
+            | Self::FallbackReconstruct
+            | Self::MainSortInit
+            | Self::MainBucketSort => trace!(target: TARGET, "{self}"),
+            Self::QuickSort {
+                big,
+                small,
+                sorted,
+                size,
+            } => trace!(target: TARGET, big, small, sorted, size, "{self}"),
+            Self::MainSortDone {
+                pointers,
+                sorted,
+                scanned,
+            } => trace!(target: TARGET, pointers, sorted, scanned, "{self}"),
+            Self::MtfCoded {
+                block_size,
+                mtf_symbols,
+                in_use,
+            } => trace!(target: TARGET, block_size, mtf_symbols, in_use, "{self}"),
+            Self::InitialGroup {
+                group,
+                start,
+                end,
+                frequency,
+                mtf_symbols,
+            } => trace!(target: TARGET, group, start, end, frequency, mtf_symbols, "{self}"),
+            Self::HuffmanPass {
+                pass,
+                size,
+                groups,
+                ref group_uses,
+            } => {
+                let group_uses = &group_uses[..groups as usize];
+                trace!(target: TARGET, pass, size, ?group_uses, "{self}")
+            }
+            Self::HuffmanCoded {
+                mapping,
+                selectors,
+                code_lengths,
+                codes,
+            } => trace!(target: TARGET, mapping, selectors, code_lengths, codes, "{self}"),
+            Self::CompressEnd { combined_crc } => debug!(target: TARGET, combined_crc, "{self}"),
+            Self::DecompressBlock { block }
+            | Self::InverseTransform { block }
+            | Self::BlockEnd { block } => debug!(target: TARGET, block, "{self}"),
+            Self::BlockCrc {
+                block,
+                stored,
+                computed,
+            } => trace!(target: TARGET, block, stored, computed, "{self}"),
+            Self::CombinedCrc { stored, computed } => {
+                trace!(target: TARGET, stored, computed, "{self}")
+            }
+        }
+    }
+}
+
+/// The key-values of the `log` records.
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+impl log::kv::Source for LogEvent {
+    fn visit<'kvs>(
+        &'kvs self,
+        visitor: &mut dyn log::kv::VisitSource<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        use log::kv::{Key, Value};
+
+        let (_, values) = self.raw();
+        for (name, value) in self.field_names().iter().zip(values) {
+            visitor.visit_pair(Key::from_str(name), Value::from(value))?;
+        }
+
+        // only the first `groups` entries are used, the others are zero
+        if let Self::HuffmanPass { group_uses, .. } = self {
+            visitor.visit_pair(Key::from_str("group_uses"), Value::from_debug(group_uses))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for LogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::CompressBlock {
+                block,
+                crc,
+                combined_crc,
+                size,
+            } => write!(
+                f,
+                "block {block}: crc = 0x{crc:08x}, combined CRC = 0x{combined_crc:08x}, \
+                 size = {size}"
+            ),
+            Self::SortWork { work, block_size } => {
+                let ratio = f64::from(work) / f64::from(Ord::max(block_size, 1));
+                write!(f, "{work} work, {block_size} block, ratio {ratio:5.2}")
+            }
+            Self::SortFallback => f.write_str("too repetitive; using fallback sorting algorithm"),
+            Self::FallbackBucketSort | Self::MainBucketSort => f.write_str("bucket sorting ..."),
+            Self::FallbackDepth { depth, unresolved } => {
+                write!(f, "depth {depth:>6} has {unresolved:>6} unresolved strings")
+            }
+            Self::FallbackReconstruct => f.write_str("reconstructing block ..."),
+            Self::MainSortInit => f.write_str("main sort initialise ..."),
+            Self::QuickSort {
+                big,
+                small,
+                sorted,
+                size,
+            } => write!(
+                f,
+                "qsort [{big:#x}, {small:#x}]   done {sorted}   this {size}"
+            ),
+            Self::MainSortDone {
+                pointers,
+                sorted,
+                scanned,
+            } => write!(f, "{pointers} pointers, {sorted} sorted, {scanned} scanned"),
+            Self::MtfCoded {
+                block_size,
+                mtf_symbols,
+                in_use,
+            } => write!(
+                f,
+                "{block_size} in block, {mtf_symbols} after MTF & 1-2 coding, \
+                 {in_use}+2 syms in use"
+            ),
+            Self::InitialGroup {
+                group,
+                start,
+                end,
+                frequency,
+                mtf_symbols,
+            } => {
+                let percent = 100.0 * f64::from(frequency) / f64::from(Ord::max(mtf_symbols, 1));
+                write!(
+                    f,
+                    "initial group {group}, [{start} .. {end}], has {frequency} syms \
+                     ({percent:4.1}%)"
+                )
+            }
+            Self::HuffmanPass {
+                pass,
+                size,
+                groups,
+                ref group_uses,
+            } => {
+                write!(f, "pass {pass}: size is {size}, grp uses are")?;
+                for uses in &group_uses[..groups as usize] {
+                    write!(f, " {uses}")?;
+                }
+                Ok(())
+            }
+            Self::HuffmanCoded {
+                mapping,
+                selectors,
+                code_lengths,
+                codes,
+            } => write!(
+                f,
+                "bytes: mapping {mapping}, selectors {selectors}, code lengths {code_lengths}, \
+                 codes {codes}"
+            ),
+            Self::CompressEnd { combined_crc } => {
+                write!(f, "final combined CRC = 0x{combined_crc:08x}")
+            }
+            Self::DecompressBlock { block } => write!(f, "block {block}: huff+mtf"),
+            Self::InverseTransform { block } => write!(f, "block {block}: rt+rld"),
+            Self::BlockCrc {
+                block,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "block {block}: stored CRC = {stored:#08x}, computed = {computed:#08x}"
+            ),
+            Self::BlockEnd { block } => write!(f, "block {block}: done"),
+            Self::CombinedCrc { stored, computed } => write!(
+                f,
+                "combined CRCs: stored = {stored:#08x}, computed = {computed:#08x}"
+            ),
+        }
+    }
+}
+
+/// A diagnostic event, passed to a [`bz_log_callback`].
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct bz_log_event {
+    /// One of the `BZ_LOG_*` constants, e.g. [`BZ_LOG_COMPRESS_BLOCK`].
+    pub kind: c_int,
+    /// The lowest verbosity at which the event is emitted.
+    pub verbosity: c_int,
+    /// A nul-terminated description of the event, that contains all of its values.
+    pub message: *const c_char,
+    /// The values of the event, in the order listed for its kind. Unused values are zero.
+    pub values: [c_uint; 9],
+}
+
+/// A function that is called with the `opaque` pointer it was registered with, for every
+/// diagnostic event of the stream.
+///
+/// See [`BZ2_bzCompressSetLogCallback`] and [`BZ2_bzDecompressSetLogCallback`]. The `event` pointer,
+/// including its message, is only valid for the duration of the call. The callback must not call
+/// back into the library with the same stream.
+#[allow(non_camel_case_types)]
+pub type bz_log_callback = unsafe extern "C" fn(opaque: *mut c_void, event: *const bz_log_event);
+
+/// Where the diagnostic events of a stream go.
+#[derive(Clone, Copy)]
+pub(crate) enum LogSink {
+    /// Write to stderr, or emit `log` or `tracing` events when those features are enabled.
+    Default,
+    /// Call a [`bz_log_callback`] with its `opaque` pointer.
+    Callback {
+        callback: bz_log_callback,
+        opaque: *mut c_void,
+    },
+    /// Call the [`BoxedLogSink`] that the pointer points to.
+    #[cfg(feature = "std")]
+    Closure(*mut BoxedLogSink),
+}
+
+/// Wraps a rust closure, so that it can be registered as a [`LogSink`].
+#[cfg(feature = "std")]
+pub(crate) type BoxedLogSink = std::boxed::Box<dyn FnMut(&LogEvent) + Send>;
+
+impl LogSink {
+    pub(crate) fn emit(&self, event: LogEvent) {
+        match *self {
+            Self::Default => emit_default(&event),
+            Self::Callback { callback, opaque } => {
+                let mut buf = [0u8; MESSAGE_LEN];
+                let _ = fmt::write(
+                    &mut TruncatingWriter::new(&mut buf),
+                    format_args!("{event}"),
+                );
+
+                let (kind, values) = event.raw();
+                let raw = bz_log_event {
+                    kind,
+                    verbosity: event.verbosity(),
+                    message: buf.as_ptr().cast::<c_char>(),
+                    values,
+                };
+
+                // SAFETY: the caller that registered the callback guarantees that it can be called
+                // with its opaque pointer
+                unsafe { callback(opaque, &raw) };
+            }
+            #[cfg(feature = "std")]
+            Self::Closure(sink) => {
+                // SAFETY: the pointer was registered by the safe interface, and points to a boxed
+                // closure that lives as long as the stream
+                let sink = unsafe { &mut *sink };
+                sink(&event);
+            }
+        }
+    }
+}
+
+fn emit_default(event: &LogEvent) {
+    #[cfg(feature = "tracing")]
+    event.trace();
+
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    {
+        let level = match event.verbosity() {
+            2 => log::Level::Debug,
+            _ => log::Level::Trace,
+        };
+        if log::log_enabled!(target: TARGET, level) {
+            log::logger().log(
+                &log::Record::builder()
+                    .args(format_args!("{event}"))
+                    .level(level)
+                    .target(TARGET)
+                    .module_path_static(Some(module_path!()))
+                    .file_static(Some(file!()))
+                    .line(Some(line!()))
+                    .key_values(event)
+                    .build(),
+            );
+        }
+    }
+
+    #[cfg(all(not(feature = "log"), not(feature = "tracing"), feature = "std"))]
+    {
+        struct Stderr;
+
+        impl fmt::Write for Stderr {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                std::eprint!("{s}");
+                Ok(())
+            }
+        }
+
+        let _ = event.write_text(&mut Stderr);
+    }
+
+    #[cfg(all(
+        not(feature = "log"),
+        not(feature = "tracing"),
+        not(feature = "std"),
+        feature = "stdio"
+    ))]
+    let _ = event.write_text(&mut crate::StderrWritter);
+
+    #[cfg(all(
+        not(feature = "log"),
+        not(feature = "tracing"),
+        not(feature = "std"),
+        not(feature = "stdio")
+    ))]
+    let _ = event;
+}
+
+#[cfg(all(test, feature = "log", not(feature = "tracing"), feature = "std"))]
+mod tests {
+    use std::string::{String, ToString};
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{Action, Compressor, Status};
+
+    type KeyValues = Vec<(String, String)>;
+
+    /// The message and key-values of every record.
+    static RECORDS: Mutex<Vec<(String, KeyValues)>> = Mutex::new(Vec::new());
+
+    struct Logger;
+
+    impl log::Log for Logger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == TARGET
+        }
+
+        fn log(&self, record: &log::Record) {
+            struct Collect(KeyValues);
+
+            impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+                fn visit_pair(
+                    &mut self,
+                    key: log::kv::Key<'kvs>,
+                    value: log::kv::Value<'kvs>,
+                ) -> Result<(), log::kv::Error> {
+                    self.0.push((key.to_string(), value.to_string()));
+                    Ok(())
+                }
+            }
+
+            if self.enabled(record.metadata()) {
+                let mut kvs = Collect(Vec::new());
+                record.key_values().visit(&mut kvs).unwrap();
+                RECORDS
+                    .lock()
+                    .unwrap()
+                    .push((record.args().to_string(), kvs.0));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn key_values() {
+        static LOGGER: Logger = Logger;
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let input = b"hello world".repeat(100);
+        let mut compressor = Compressor::new(1, 30).unwrap();
+        compressor.set_verbosity(3);
+        let mut output = [0u8; 1024];
+        let progress = compressor
+            .compress(&input, &mut output, Action::Finish)
+            .unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+
+        let records = RECORDS.lock().unwrap();
+        let (message, kvs) = records
+            .iter()
+            .find(|(message, _)| message.starts_with("block 1: crc"))
+            .unwrap();
+        let keys: Vec<_> = kvs.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["block", "crc", "combined_crc", "size"]);
+        assert_eq!(kvs[0].1, "1");
+        assert_eq!(kvs[3].1, "1100");
+        let crc: u32 = kvs[1].1.parse().unwrap();
+        assert!(message.contains(&std::format!("crc = 0x{crc:08x}")));
+
+        let (_, kvs) = records
+            .iter()
+            .find(|(message, _)| message.starts_with("pass 1:"))
+            .unwrap();
+        let keys: Vec<_> = kvs.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["pass", "size", "groups", "group_uses"]);
+    }
+}
@@ -0,0 +1,1041 @@
+//! Compression and decompression of independent blocks on multiple threads.
+//!
+//! After the initial run-length encoding, the blocks of a bzip2 stream are compressed
+//! independently. The only state that crosses a block boundary is the run that is being collected
+//! when a block fills up, so the block boundaries of the serial compressor can be found with a
+//! cheap scan over the input. Each block is then compressed on its own, and the resulting blocks
+//! are concatenated at the bit level, producing exactly the output of the serial compressor.
+//!
+//! Blocks are not byte-aligned and their lengths are not stored, so decompression first scans for
+//! the 48-bit block magic at every bit offset. The candidates are decoded concurrently, each as a
+//! stream of its own, and the block CRCs reject the magic numbers that happen to occur inside of
+//! the compressed data. The scan runs over a window of the input that only holds the next few
+//! blocks per thread. A block that doesn't decode between any of the markers in reach, because it
+//! is damaged or unusually long, is decoded serially instead.
+
+use core::num::NonZeroUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::vec::Vec;
+
+use crate::block::{decode_block, max_block_bits, read_bits, BitWriter};
+use crate::decompress::{BLOCK_MAGIC, END_OF_STREAM_MAGIC};
+use crate::{Action, Compressor, DecompressMode, Decompressor, Error, Status};
+
+/// The bit length of the stream header, `BZh` followed by the block size.
+const HEADER_BITS: u64 = 32;
+/// The bit length of the stream trailer: a 48-bit magic number and the combined CRC.
+const TRAILER_BITS: u64 = 80;
+
+/// The largest number of threads that is used. The input and output of a few blocks per thread
+/// are held in memory, so this also bounds the memory use.
+const MAX_THREADS: NonZeroUsize = match NonZeroUsize::new(128) {
+    Some(threads) => threads,
+    None => unreachable!(),
+};
+
+/// The number of bytes that are read from the input at a time during decompression.
+const READ_SIZE: usize = 1 << 20;
+
+/// Compresses data into a single bzip2 stream, compressing multiple blocks in parallel.
+///
+/// The output is identical to that of a [`Compressor`] with the same parameters. Input is
+/// buffered until enough blocks are available to keep all threads busy, so memory use grows with
+/// the number of threads, which is at most 128.
+pub struct ParallelCompressor {
+    block_size_100k: u32,
+    work_factor: u32,
+    threads: NonZeroUsize,
+    scanner: BlockScanner,
+    /// Input that was not compressed yet. It starts at a block boundary.
+    input: Vec<u8>,
+    /// The ends of the complete blocks in `input`.
+    boundaries: Vec<usize>,
+    writer: BitWriter,
+    combined_crc: u32,
+    total_in: u64,
+    total_out: u64,
+}
+
+/// A compressed block, without the stream header and trailer.
+struct Block {
+    /// A complete stream containing just this block.
+    stream: Vec<u8>,
+    /// The number of bits of the block itself.
+    bits: u64,
+    crc: u32,
+}
+
+impl ParallelCompressor {
+    /// Prepares a new compression stream that uses up to `threads` threads, but no more than 128.
+    ///
+    /// For the meaning of `block_size_100k` and `work_factor`, see [`Compressor::new`].
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::Param`] if any of
+    ///     - `!(1..=9).contains(&block_size_100k)`
+    ///     - `!(0..=250).contains(&work_factor)`
+    pub fn new(
+        block_size_100k: u32,
+        work_factor: u32,
+        threads: NonZeroUsize,
+    ) -> Result<Self, Error> {
+        if !(1..=9).contains(&block_size_100k) || work_factor > 250 {
+            return Err(Error::Param);
+        }
+
+        let mut writer = BitWriter::default();
+        writer.write_bytes(b"BZh");
+        writer.write(8, u32::from(b'0') + block_size_100k);
+
+        Ok(Self {
+            block_size_100k,
+            work_factor,
+            threads: Ord::min(threads, MAX_THREADS),
+            scanner: BlockScanner::new(block_size_100k),
+            input: Vec::new(),
+            boundaries: Vec::new(),
+            writer,
+            combined_crc: 0,
+            total_in: 0,
+            total_out: 0,
+        })
+    }
+
+    /// Compresses `input`, appending any compressed data that is ready to `output`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of a [`Compressor`] that compresses one of the blocks.
+    pub fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        let offset = self.input.len();
+        self.input.extend_from_slice(input);
+        self.total_in += input.len() as u64;
+
+        for (i, &byte) in input.iter().enumerate() {
+            if let Some(pending) = self.scanner.push(byte) {
+                self.boundaries.push(offset + i - pending);
+            }
+        }
+
+        if self.boundaries.len() >= self.threads.get() {
+            let end = *self.boundaries.last().unwrap();
+            let boundaries = core::mem::take(&mut self.boundaries);
+            self.compress_blocks(&boundaries)?;
+            self.input.drain(..end);
+        }
+
+        self.drain_output(output);
+
+        Ok(())
+    }
+
+    /// Compresses the remaining input, and appends the rest of the stream to `output`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of a [`Compressor`] that compresses one of the blocks.
+    pub fn finish(mut self, output: &mut Vec<u8>) -> Result<(), Error> {
+        let mut boundaries = core::mem::take(&mut self.boundaries);
+        if !self.input.is_empty() {
+            // the byte that ends a block is part of the next block, so this is a new boundary
+            boundaries.push(self.input.len());
+        }
+        self.compress_blocks(&boundaries)?;
+        self.input.clear();
+
+        self.writer
+            .write_bytes(&END_OF_STREAM_MAGIC.to_be_bytes()[2..]);
+        self.writer.write_bytes(&self.combined_crc.to_be_bytes());
+        self.writer.finish();
+
+        self.drain_output(output);
+
+        Ok(())
+    }
+
+    /// The total number of bytes consumed by this stream.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// The total number of bytes produced by this stream.
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Compresses the blocks of `self.input` that end at `boundaries`, and writes them.
+    fn compress_blocks(&mut self, boundaries: &[usize]) -> Result<(), Error> {
+        let chunks: Vec<&[u8]> = core::iter::once(0)
+            .chain(boundaries.iter().copied())
+            .zip(boundaries)
+            .map(|(start, &end)| &self.input[start..end])
+            .collect();
+
+        let (block_size_100k, work_factor) = (self.block_size_100k, self.work_factor);
+        let blocks = run_parallel(&chunks, self.threads, |chunk| {
+            compress_block(chunk, block_size_100k, work_factor)
+        });
+
+        for block in blocks {
+            let block = block?;
+            self.combined_crc = self.combined_crc.rotate_left(1) ^ block.crc;
+            self.writer
+                .write_bits(&block.stream, HEADER_BITS, block.bits);
+        }
+
+        Ok(())
+    }
+
+    fn drain_output(&mut self, output: &mut Vec<u8>) {
+        self.total_out += self.writer.bytes.len() as u64;
+        output.append(&mut self.writer.bytes);
+    }
+}
+
+/// Compresses `chunk`, which must fit in a single block.
+fn compress_block(chunk: &[u8], block_size_100k: u32, work_factor: u32) -> Result<Block, Error> {
+    let mut compressor = Compressor::new(block_size_100k, work_factor)?;
+
+    let mut stream = Vec::with_capacity(chunk.len() + chunk.len() / 100 + 600);
+    let mut input = chunk;
+    loop {
+        if stream.len() == stream.capacity() {
+            stream.reserve(4096);
+        }
+
+        let len = stream.len();
+        stream.resize(stream.capacity(), 0);
+        let progress = compressor.compress(input, &mut stream[len..], Action::Finish)?;
+        stream.truncate(len + progress.produced);
+        input = &input[progress.consumed..];
+
+        if progress.status == Status::StreamEnd {
+            break;
+        }
+    }
+
+    let total_bits = stream.len() as u64 * 8 - u64::from(compressor.padding_bits());
+
+    // the block CRC follows the stream header and the 48-bit block magic
+    let crc = u32::from_be_bytes(stream[10..14].try_into().unwrap());
+
+    Ok(Block {
+        bits: total_bits - HEADER_BITS - TRAILER_BITS,
+        stream,
+        crc,
+    })
+}
+
+/// Finds the block boundaries of the serial compressor.
+///
+/// This mirrors `ADD_CHAR_TO_BLOCK` and `copy_input_until_stop`, but only counts the bytes that
+/// are added to the block.
+struct BlockScanner {
+    nblock: i32,
+    nblock_max: i32,
+    state_in_ch: u32,
+    state_in_len: i32,
+}
+
+impl BlockScanner {
+    fn new(block_size_100k: u32) -> Self {
+        Self {
+            nblock: 0,
+            nblock_max: 100000 * block_size_100k as i32 - 19,
+            state_in_ch: 256,
+            state_in_len: 0,
+        }
+    }
+
+    /// The number of bytes a run adds to the block.
+    fn pair_len(len: i32) -> i32 {
+        if len <= 3 {
+            len
+        } else {
+            5
+        }
+    }
+
+    /// Adds a byte of input.
+    ///
+    /// When the block was full before this byte, returns the number of preceding input bytes that
+    /// belong to the next block.
+    fn push(&mut self, byte: u8) -> Option<usize> {
+        let mut boundary = None;
+
+        if self.nblock >= self.nblock_max {
+            // the run that is being collected carries over into the next block
+            let pending = if self.state_in_ch < 256 {
+                self.state_in_len as usize
+            } else {
+                0
+            };
+            boundary = Some(pending);
+            self.nblock = 0;
+        }
+
+        let ch = u32::from(byte);
+        if ch != self.state_in_ch && self.state_in_len == 1 {
+            self.nblock += 1;
+            self.state_in_ch = ch;
+        } else if ch != self.state_in_ch || self.state_in_len == 255 {
+            if self.state_in_ch < 256 {
+                self.nblock += Self::pair_len(self.state_in_len);
+            }
+            self.state_in_ch = ch;
+            self.state_in_len = 1;
+        } else {
+            self.state_in_len += 1;
+        }
+
+        boundary
+    }
+}
+
+/// Runs `f` on every item, using up to `threads` threads, and returns the results in order.
+fn run_parallel<T: Sync, R: Send>(
+    items: &[T],
+    threads: NonZeroUsize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let workers = Ord::min(threads.get(), items.len());
+    let next = AtomicUsize::new(0);
+
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            return done;
+                        };
+                        done.push((index, f(item)));
+                    }
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    results.sort_unstable_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Decompresses a complete bzip2 file, decoding multiple blocks in parallel.
+///
+/// Like the `bzip2` binary, a concatenation of streams is accepted. Data after the last stream
+/// that does not begin with the bzip2 magic bytes is ignored, but only once at least one stream
+/// has been decoded successfully. The output is identical to that of a [`Decompressor`](crate::Decompressor).
+pub struct ParallelDecompressor {
+    threads: NonZeroUsize,
+}
+
+/// A position in the input where one of the 48-bit magic numbers was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    Block(u64),
+    EndOfStream(u64),
+}
+
+impl Marker {
+    fn offset(self) -> u64 {
+        match self {
+            Marker::Block(offset) | Marker::EndOfStream(offset) => offset,
+        }
+    }
+}
+
+impl ParallelDecompressor {
+    /// Prepares decompression that uses up to `threads` threads, but no more than 128.
+    pub fn new(threads: NonZeroUsize) -> Self {
+        Self {
+            threads: Ord::min(threads, MAX_THREADS),
+        }
+    }
+
+    /// Decompresses all of `input`, and appends the decompressed data to `output`.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::DataMagic`] if the input doesn't begin with the right magic bytes
+    /// - [`Error::Data`] if a data integrity error is detected in the compressed data, including a
+    ///   mismatch of a block CRC or of the combined CRC
+    /// - [`Error::UnexpectedEof`] if the input ends before the end of the last stream
+    /// - [`Error::Mem`] if insufficient memory is available
+    pub fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        self.decompress_to(input, output)
+            .map(drop)
+            .map_err(Error::from)
+    }
+
+    /// Decompresses all of `input`, and writes the decompressed data to `writer` one block at a
+    /// time. Returns the number of bytes of `input` that make up the compressed streams, so any
+    /// bytes after that are the ignored trailing data.
+    ///
+    /// Unlike [`decompress`](Self::decompress), the decompressed data is not collected in
+    /// memory: only a few blocks per thread are decoded ahead of the output.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of `writer`, or with one of the errors of
+    /// [`decompress`](Self::decompress). Use [`Error::from`] to recover the [`Error`].
+    pub fn decompress_to<W: Write>(&self, input: &[u8], writer: W) -> io::Result<usize> {
+        self.decompress_from(input, writer).map(|len| len as usize)
+    }
+
+    /// Decompresses all of the data of `reader`, and writes the decompressed data to `writer` one
+    /// block at a time. Returns the number of bytes that make up the compressed streams.
+    ///
+    /// The input is read in chunks, and only the part that holds the next few blocks per thread is
+    /// kept in memory, so this works for inputs of any size. The reader is only read past the end
+    /// of the streams when trailing data follows them.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of `reader` or `writer`, or with one of the errors of
+    /// [`decompress`](Self::decompress). Use [`Error::from`] to recover the [`Error`].
+    pub fn decompress_from<R: Read, W: Write>(&self, reader: R, mut writer: W) -> io::Result<u64> {
+        let mut window = Window::new(reader);
+
+        let mut start = 0;
+        let mut streams = 0;
+        loop {
+            window.discard(start * 8);
+            window.fill_bits((start + 4) * 8)?;
+
+            let rest = window.bytes_from(start);
+            if streams > 0 && rest.is_empty() {
+                return Ok(start);
+            }
+
+            let level = match rest {
+                [b'B', b'Z', b'h', level @ b'1'..=b'9', ..] => *level,
+                _ if streams > 0 => return Ok(start),
+                _ if rest.len() < 4 && b"BZh".starts_with(&rest[..Ord::min(rest.len(), 3)]) => {
+                    return Err(Error::UnexpectedEof.into())
+                }
+                _ => return Err(Error::DataMagic.into()),
+            };
+
+            let header_end = start * 8 + HEADER_BITS;
+            let end = self.decompress_stream(&mut window, level, header_end, &mut writer)?;
+
+            start = end.div_ceil(8);
+            streams += 1;
+        }
+    }
+
+    /// Decompresses the stream whose first block starts at bit `header_end`, and returns the bit
+    /// offset of the end of the stream.
+    fn decompress_stream<R: Read, W: Write>(
+        &self,
+        window: &mut Window<R>,
+        level: u8,
+        header_end: u64,
+        output: &mut W,
+    ) -> io::Result<u64> {
+        let max_bits = max_block_bits(level - b'0');
+
+        // segments between consecutive markers are decoded in batches, optimistically assuming
+        // that all markers are real. That bounds the decoded data that is held in memory
+        let batch = self.threads.get() * 4;
+        let mut decoded = VecDeque::new();
+
+        let mut combined_crc = 0u32;
+        let mut pos = header_end;
+        loop {
+            // the next marker must start exactly where the previous block ended
+            window.discard(pos);
+            window.fill_markers(batch + 1)?;
+
+            match window.markers.first() {
+                Some(Marker::EndOfStream(offset)) if *offset == pos => {
+                    let end = pos + TRAILER_BITS;
+                    if !window.fill_bits(end)? {
+                        return Err(Error::UnexpectedEof.into());
+                    }
+                    if window.read_bits(pos + 48, 32) != combined_crc {
+                        return Err(Error::Data.into());
+                    }
+                    return Ok(end);
+                }
+                Some(Marker::Block(offset)) if *offset == pos => {}
+                Some(_) => return Err(Error::Data.into()),
+                None => return Err(Error::UnexpectedEof.into()),
+            }
+
+            // extend the block over the following markers, until the block decodes correctly
+            let mut result = Err(Error::Data);
+            let mut next = 1;
+            loop {
+                let Some(marker) = window.markers.get(next) else {
+                    // no block is longer than `max_bits`, so the window doesn't need to be either
+                    if window.end() * 8 - pos > max_bits || !window.read_more()? {
+                        break;
+                    }
+                    continue;
+                };
+
+                let end = marker.offset();
+                if end - pos > max_bits {
+                    break;
+                }
+
+                result = if next == 1 {
+                    while decoded.front().is_some_and(|&((start, _), _)| start < pos) {
+                        decoded.pop_front();
+                    }
+                    if decoded.is_empty() {
+                        decoded = window.decode_segments(level, batch, self.threads);
+                    }
+                    match decoded.front() {
+                        Some(&(segment, _)) if segment == (pos, end) => {
+                            decoded.pop_front().unwrap().1
+                        }
+                        _ => window.decode(level, pos, end),
+                    }
+                } else {
+                    window.decode(level, pos, end)
+                };
+
+                if result.is_ok() {
+                    pos = end;
+                    break;
+                }
+                next += 1;
+            }
+
+            let (block, crc) = match result {
+                Ok(decoded) => decoded,
+                Err(_) => {
+                    // the block is damaged, truncated, or longer than `max_bits`. Decoding it on its
+                    // own gives the result of serial decompression, including the error
+                    let (block, crc, end) = window.decode_serially(level, pos)?;
+                    pos = end;
+                    (block, crc)
+                }
+            };
+            combined_crc = combined_crc.rotate_left(1) ^ crc;
+            output.write_all(&block)?;
+        }
+    }
+}
+
+/// The decompressed data and the CRC of a block, see [`decode_block`].
+type DecodedBlock = Result<(Vec<u8>, u32), Error>;
+
+/// The part of the input that is still needed for decompression, and the markers in it.
+struct Window<R> {
+    reader: R,
+    /// The input from byte offset `base` onwards.
+    buf: Vec<u8>,
+    base: u64,
+    /// The markers in `buf`, in order.
+    markers: Vec<Marker>,
+    scanner: MarkerScanner,
+    /// Whether the reader has reached the end of the input.
+    eof: bool,
+}
+
+impl<R: Read> Window<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            base: 0,
+            markers: Vec::new(),
+            scanner: MarkerScanner::default(),
+            eof: false,
+        }
+    }
+
+    /// The byte offset of the end of the input that was read so far.
+    fn end(&self) -> u64 {
+        self.base + self.buf.len() as u64
+    }
+
+    /// The input from byte offset `start` up to the end of the window.
+    fn bytes_from(&self, start: u64) -> &[u8] {
+        &self.buf[(start - self.base) as usize..]
+    }
+
+    fn read_bits(&self, pos: u64, n: u32) -> u32 {
+        read_bits(&self.buf, pos - self.base * 8, n)
+    }
+
+    /// Reads the next chunk of the input. Returns `false` at the end of the input.
+    fn read_more(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        let len = self.buf.len();
+        let n = (&mut self.reader)
+            .take(READ_SIZE as u64)
+            .read_to_end(&mut self.buf)?;
+
+        if n == 0 {
+            self.eof = true;
+            return Ok(false);
+        }
+
+        self.scanner.scan(&self.buf[len..], &mut self.markers);
+        Ok(true)
+    }
+
+    /// Reads until the window extends to bit `end`. Returns `false` if the input is shorter.
+    fn fill_bits(&mut self, end: u64) -> io::Result<bool> {
+        while self.end() * 8 < end {
+            if !self.read_more()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads until the window holds `count` markers, or up to the end of the input.
+    fn fill_markers(&mut self, count: usize) -> io::Result<()> {
+        while self.markers.len() < count && self.read_more()? {}
+        Ok(())
+    }
+
+    /// Drops the markers before bit `pos`, and eventually the input before it.
+    fn discard(&mut self, pos: u64) {
+        let n = self.markers.partition_point(|marker| marker.offset() < pos);
+        self.markers.drain(..n);
+
+        // moving the rest of the window is only worth it once it makes room for a lot of input
+        let n = (pos / 8 - self.base) as usize;
+        if n >= READ_SIZE && n >= self.buf.len() / 2 {
+            self.buf.drain(..n);
+            self.base += n as u64;
+        }
+    }
+
+    /// Decodes the bits `start..end` of the input as a single block.
+    fn decode(&self, level: u8, start: u64, end: u64) -> DecodedBlock {
+        let base = self.base * 8;
+        decode_block(&self.buf, level, start - base, end - base)
+    }
+
+    /// Decodes the block that starts at bit `pos` with a serial decompressor, which finds the end
+    /// of the block without relying on the markers. Returns the decompressed data, the block CRC
+    /// and the bit offset of the end of the block.
+    ///
+    /// The input is passed on as it is read, so a long block is not held in memory.
+    fn decode_serially(&mut self, level: u8, pos: u64) -> io::Result<(Vec<u8>, u32, u64)> {
+        if !self.fill_bits(pos + 48 + 32)? {
+            return Err(Error::UnexpectedEof.into());
+        }
+        let crc = self.read_bits(pos + 48, 32);
+
+        let mut decompressor = Decompressor::new(DecompressMode::Fast)?;
+        decompressor.pause_at_block_boundaries();
+
+        // the block is wrapped in a stream of its own, which pauses at the start of the block and
+        // at the marker that follows it
+        let mut stream = BitWriter::default();
+        stream.write_bytes(&[b'B', b'Z', b'h', level]);
+        let mut copied = pos;
+        let mut boundaries = 0;
+
+        let mut block = Vec::new();
+        loop {
+            if stream.bytes.is_empty() {
+                // only the bits that the decompressor may not have consumed yet are still needed
+                let consumed = pos + (decompressor.total_in() * 8).saturating_sub(HEADER_BITS);
+                self.discard(consumed.saturating_sub(64));
+
+                if copied == self.end() * 8 && !self.read_more()? {
+                    return Err(Error::UnexpectedEof.into());
+                }
+                let base = self.base * 8;
+                stream.write_bits(&self.buf, copied - base, self.end() * 8 - copied);
+                copied = self.end() * 8;
+                continue;
+            }
+
+            if block.len() == block.capacity() {
+                block.reserve(64 * 1024);
+            }
+            let len = block.len();
+            block.resize(block.capacity(), 0);
+            let progress = decompressor.decompress(&stream.bytes, &mut block[len..])?;
+            block.truncate(len + progress.produced);
+            stream.bytes.drain(..progress.consumed);
+
+            if let Some((bits, _)) = decompressor.block_boundary() {
+                boundaries += 1;
+                if boundaries == 2 {
+                    return Ok((block, crc, pos + bits - HEADER_BITS));
+                }
+            }
+        }
+    }
+
+    /// Decodes up to `count` segments from a block marker to the next marker, starting at the
+    /// first marker.
+    fn decode_segments(
+        &self,
+        level: u8,
+        count: usize,
+        threads: NonZeroUsize,
+    ) -> VecDeque<((u64, u64), DecodedBlock)> {
+        let segments: Vec<(u64, u64)> = self
+            .markers
+            .windows(2)
+            .filter_map(|pair| match pair[0] {
+                Marker::Block(start) => Some((start, pair[1].offset())),
+                Marker::EndOfStream(_) => None,
+            })
+            .take(count)
+            .collect();
+
+        let (buf, base) = (self.buf.as_slice(), self.base * 8);
+        let blocks = run_parallel(&segments, threads, |&(start, end)| {
+            decode_block(buf, level, start - base, end - base)
+        });
+        segments.into_iter().zip(blocks).collect()
+    }
+}
+
+/// Finds the occurrences of the block and end-of-stream magic numbers, at any bit offset, in
+/// input that arrives in chunks.
+#[derive(Default)]
+struct MarkerScanner {
+    /// The last 8 bytes that were scanned.
+    window: u64,
+    /// The number of bytes that were scanned.
+    len: u64,
+}
+
+impl MarkerScanner {
+    /// Scans the next chunk of the input, and appends the markers that end in it.
+    fn scan(&mut self, input: &[u8], markers: &mut Vec<Marker>) {
+        const MASK: u64 = (1 << 48) - 1;
+
+        for &byte in input {
+            self.window = (self.window << 8) | u64::from(byte);
+            self.len += 1;
+
+            let end = self.len * 8;
+            for shift in (0..8).rev() {
+                let Some(start) = end.checked_sub(48 + shift) else {
+                    continue;
+                };
+                match (self.window >> shift) & MASK {
+                    BLOCK_MAGIC => markers.push(Marker::Block(start)),
+                    END_OF_STREAM_MAGIC => markers.push(Marker::EndOfStream(start)),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Finds all occurrences of the block and end-of-stream magic numbers, at any bit offset.
+#[cfg(test)]
+fn find_markers(input: &[u8]) -> Vec<Marker> {
+    let mut markers = Vec::new();
+    MarkerScanner::default().scan(input, &mut markers);
+    markers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_data::{
+        SAMPLE1_BZ2, SAMPLE1_REF, SAMPLE2_BZ2, SAMPLE2_REF, SAMPLE3_BZ2, SAMPLE3_REF,
+    };
+    use crate::{DecompressMode, Decompressor};
+
+    /// Pseudo-random data, interspersed with runs that often straddle a block boundary.
+    fn sample_input(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        let mut input = Vec::with_capacity(len);
+        while input.len() < len {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            match state % 64 {
+                0 => input.extend(core::iter::repeat_n(
+                    state as u8,
+                    (state >> 8) as usize % 700,
+                )),
+                1..=8 => input.extend_from_slice(b"abracadabra"),
+                _ => input.push((state >> 24) as u8 % 16),
+            }
+        }
+        input.truncate(len);
+        input
+    }
+
+    fn serial(input: &[u8], block_size_100k: u32) -> Vec<u8> {
+        let mut compressor = Compressor::new(block_size_100k, 30).unwrap();
+        let mut output = std::vec![0u8; input.len() + input.len() / 100 + 600];
+        let progress = compressor
+            .compress(input, &mut output, Action::Finish)
+            .unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        output.truncate(progress.produced);
+        output
+    }
+
+    fn parallel(input: &[u8], block_size_100k: u32, threads: usize, chunk_size: usize) -> Vec<u8> {
+        let threads = NonZeroUsize::new(threads).unwrap();
+        let mut compressor = ParallelCompressor::new(block_size_100k, 30, threads).unwrap();
+        let mut output = Vec::new();
+        for chunk in input.chunks(chunk_size) {
+            compressor.compress(chunk, &mut output).unwrap();
+        }
+        assert_eq!(compressor.total_in(), input.len() as u64);
+        compressor.finish(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn identical_to_serial() {
+        let input = sample_input(1_000_000);
+
+        for block_size_100k in [1, 3] {
+            let expected = serial(&input, block_size_100k);
+            assert_eq!(parallel(&input, block_size_100k, 4, input.len()), expected);
+            assert_eq!(parallel(&input, block_size_100k, 2, 77_777), expected);
+            assert_eq!(parallel(&input, block_size_100k, 1, 100_000), expected);
+        }
+    }
+
+    #[test]
+    fn long_runs() {
+        // the block fills up in the middle of a run of identical bytes
+        let input = [std::vec![7u8; 2_000_000], sample_input(300_000)].concat();
+        assert_eq!(parallel(&input, 1, 3, 65_536), serial(&input, 1));
+    }
+
+    #[test]
+    fn small_inputs() {
+        for input in [&b""[..], b"a", b"hello world"] {
+            assert_eq!(parallel(input, 9, 2, 1), serial(input, 9));
+        }
+    }
+
+    #[test]
+    fn decompresses() {
+        let input = sample_input(500_000);
+        let compressed = parallel(&input, 1, 4, 10_000);
+
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        let mut output = std::vec![0u8; input.len() + 1];
+        let progress = decompressor.decompress(&compressed, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(&output[..progress.produced], input);
+    }
+
+    const SAMPLES: [(&[u8], &[u8]); 3] = [
+        (SAMPLE1_BZ2, SAMPLE1_REF),
+        (SAMPLE2_BZ2, SAMPLE2_REF),
+        (SAMPLE3_BZ2, SAMPLE3_REF),
+    ];
+
+    fn decompress_parallel(input: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        ParallelDecompressor::new(NonZeroUsize::new(4).unwrap()).decompress(input, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn decompress_samples() {
+        for (compressed, expected) in SAMPLES {
+            assert_eq!(decompress_parallel(compressed).unwrap(), expected);
+        }
+
+        let compressed: Vec<u8> = SAMPLES
+            .iter()
+            .flat_map(|(c, _)| c.iter())
+            .copied()
+            .collect();
+        let expected: Vec<u8> = SAMPLES
+            .iter()
+            .flat_map(|(_, e)| e.iter())
+            .copied()
+            .collect();
+        assert_eq!(decompress_parallel(&compressed).unwrap(), expected);
+
+        let garbage = [compressed.as_slice(), b"trailing garbage"].concat();
+        assert_eq!(decompress_parallel(&garbage).unwrap(), expected);
+
+        // the length of the streams tells where the trailing garbage starts
+        let mut output = Vec::new();
+        let decompressor = ParallelDecompressor::new(NonZeroUsize::new(1).unwrap());
+        let len = decompressor.decompress_to(&garbage, &mut output).unwrap();
+        assert_eq!(len, compressed.len());
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn decompress_many_blocks() {
+        let input = [sample_input(700_000), std::vec![3u8; 500_000]].concat();
+        let compressed = serial(&input, 1);
+        assert!(find_markers(&compressed).len() > 3);
+        assert_eq!(decompress_parallel(&compressed).unwrap(), input);
+
+        let empty = serial(b"", 9);
+        assert_eq!(decompress_parallel(&empty).unwrap(), b"");
+    }
+
+    /// A reader that returns at most `n` bytes at a time.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = Ord::min(self.1, buf.len());
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn decompress_from_reader() {
+        // random bytes barely compress, so the window has to slide over the input
+        let mut state = 0x9e37_79b9u32;
+        let input: Vec<u8> = (0..3_000_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let compressed = [parallel(&input, 1, 4, 1 << 20), serial(b"abc", 9)].concat();
+        assert!(compressed.len() > 2 * READ_SIZE);
+
+        let decompressor = ParallelDecompressor::new(NonZeroUsize::new(3).unwrap());
+        let mut output = Vec::new();
+        let len = decompressor
+            .decompress_from(Trickle(&compressed, 100_000), &mut output)
+            .unwrap();
+        assert_eq!(len, compressed.len() as u64);
+        assert_eq!(output, [input.as_slice(), b"abc"].concat());
+    }
+
+    /// Compresses `input`, and pads the delta coding of the first code length of the first block
+    /// with `pairs` increments that are undone right away. That makes the block longer without
+    /// changing what it decodes to.
+    fn inflate_coding_table(input: &[u8], block_size_100k: u32, pairs: usize) -> Vec<u8> {
+        let mut compressor = Compressor::new(block_size_100k, 30).unwrap();
+        let mut stream = std::vec![0u8; input.len() + input.len() / 100 + 600];
+        let progress = compressor
+            .compress(input, &mut stream, Action::Finish)
+            .unwrap();
+        let bits = progress.produced as u64 * 8 - u64::from(compressor.padding_bits());
+
+        // skip the block header, the symbol map and the selectors, up to the first code length
+        let mut pos = HEADER_BITS + 48 + 32 + 1 + 24;
+        let used = read_bits(&stream, pos, 16);
+        pos += 16 + 16 * u64::from(used.count_ones());
+        let selectors = read_bits(&stream, pos + 3, 15);
+        pos += 3 + 15;
+        for _ in 0..selectors {
+            while read_bits(&stream, pos, 1) == 1 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+        pos += 5;
+
+        let mut writer = BitWriter::default();
+        writer.write_bits(&stream, 0, pos);
+        for _ in 0..pairs {
+            writer.write(4, 0b1011);
+        }
+        writer.write_bits(&stream, pos, bits - pos);
+        writer.finish();
+        writer.bytes
+    }
+
+    #[test]
+    fn inflated_coding_table() {
+        let input = sample_input(250_000);
+        let compressed = inflate_coding_table(&input, 1, 700_000);
+        assert!(compressed.len() as u64 * 8 > max_block_bits(1));
+
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        let mut output = std::vec![0u8; input.len() + 1];
+        let progress = decompressor.decompress(&compressed, &mut output).unwrap();
+        assert_eq!(progress.status, Status::StreamEnd);
+        assert_eq!(&output[..progress.produced], input);
+
+        // the first block is longer than any segment that is tried in parallel
+        assert_eq!(decompress_parallel(&compressed).unwrap(), input);
+
+        let truncated = &compressed[..compressed.len() / 2];
+        assert_eq!(decompress_parallel(truncated), Err(Error::UnexpectedEof));
+    }
+
+    #[test]
+    fn false_positive_markers() {
+        let input = sample_input(300_000);
+        let compressed = serial(&input, 1);
+
+        let mut window = Window::new(compressed.as_slice());
+        while window.read_more().unwrap() {}
+
+        let markers = &mut window.markers;
+        let real = markers.len();
+        let first = markers[0].offset();
+        let second = markers[1].offset();
+        markers.push(Marker::Block(first + 1001));
+        markers.push(Marker::EndOfStream(first + 2002));
+        markers.push(Marker::Block(second + 3));
+        markers.sort_by_key(|marker| marker.offset());
+        assert_eq!(markers.len(), real + 3);
+
+        let mut output = Vec::new();
+        let decompressor = ParallelDecompressor::new(NonZeroUsize::new(2).unwrap());
+        let end = decompressor
+            .decompress_stream(&mut window, compressed[3], HEADER_BITS, &mut output)
+            .unwrap();
+        assert_eq!(end.div_ceil(8), compressed.len() as u64);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn decompress_errors() {
+        let (compressed, _) = SAMPLES[1];
+
+        assert_eq!(decompress_parallel(b""), Err(Error::UnexpectedEof));
+        assert_eq!(decompress_parallel(b"BZ"), Err(Error::UnexpectedEof));
+        assert_eq!(decompress_parallel(b"not bzip2"), Err(Error::DataMagic));
+
+        let truncated = &compressed[..compressed.len() - 10];
+        assert_eq!(decompress_parallel(truncated), Err(Error::UnexpectedEof));
+        let truncated = &compressed[..compressed.len() / 2];
+        assert_eq!(decompress_parallel(truncated), Err(Error::UnexpectedEof));
+
+        let mut corrupt = compressed.to_vec();
+        corrupt[1000] ^= 0x10;
+        assert_eq!(decompress_parallel(&corrupt), Err(Error::Data));
+
+        // a wrong combined CRC
+        let mut corrupt = compressed.to_vec();
+        *corrupt.last_mut().unwrap() ^= 0x80;
+        assert_eq!(decompress_parallel(&corrupt), Err(Error::Data));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        let threads = NonZeroUsize::new(2).unwrap();
+        assert!(ParallelCompressor::new(0, 30, threads).is_err());
+        assert!(ParallelCompressor::new(10, 30, threads).is_err());
+        assert!(ParallelCompressor::new(9, 251, threads).is_err());
+    }
+}
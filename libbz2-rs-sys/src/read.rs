@@ -0,0 +1,146 @@
+//! Adapters that decompress data pulled from a [`Read`] implementation.
+
+use std::io::{self, BufReader, Read};
+
+use crate::bufread::MultiBzDecoder;
+use crate::bzlib::BZ_MAX_UNUSED_U32;
+use crate::{bz_decompress_limits, DecompressMode};
+
+/// Decompresses bzip2 data read from an underlying [`Read`] implementation.
+///
+/// Like the `bzip2` binary, the decoder accepts a concatenation of compressed streams, and yields
+/// the concatenation of their decompressed contents. Data after the last stream that does not
+/// begin with the bzip2 magic bytes is ignored, but only once at least one stream has been decoded
+/// successfully.
+///
+/// The compressed data is read in chunks of [`BZ_MAX_UNUSED`](crate::BZ_MAX_UNUSED) bytes, so the
+/// decoder may read past the end of the last stream. Use [`crate::bufread::BzDecoder`] when the
+/// compressed data is followed by other data.
+pub struct BzDecoder<R>(MultiBzDecoder<BufReader<R>>);
+
+impl<R: Read> BzDecoder<R> {
+    /// Creates a decoder that reads compressed data from `reader`, using [`DecompressMode::Fast`].
+    pub fn new(reader: R) -> Self {
+        Self::with_mode(reader, DecompressMode::Fast)
+    }
+
+    /// Creates a decoder that reads compressed data from `reader`, using the given `mode`.
+    pub fn with_mode(reader: R, mode: DecompressMode) -> Self {
+        Self::with_limits(reader, mode, bz_decompress_limits::default())
+    }
+
+    /// Creates a decoder that fails with [`Error::LimitExceeded`](crate::Error::LimitExceeded)
+    /// when decompression would cross one of the `limits`.
+    ///
+    /// The limits apply to all of the streams together, see [`MultiBzDecoder::with_limits`].
+    pub fn with_limits(reader: R, mode: DecompressMode, limits: bz_decompress_limits) -> Self {
+        let reader = BufReader::with_capacity(BZ_MAX_UNUSED_U32 as usize, reader);
+        Self(MultiBzDecoder::with_limits(reader, mode, limits))
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        self.0.get_ref().get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Reading from the underlying reader directly will corrupt the decompressed output.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.0.get_mut().get_mut()
+    }
+
+    /// Unwraps this decoder, returning the underlying reader.
+    ///
+    /// Compressed data that was read from the underlying reader but not yet decompressed is lost.
+    pub fn into_inner(self) -> R {
+        self.0.into_inner().into_inner()
+    }
+}
+
+impl<R: Read> Read for BzDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.0.read(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use crate::Error;
+
+    use crate::test_data::{SAMPLE1_BZ2, SAMPLE1_REF, SAMPLE2_BZ2, SAMPLE2_REF};
+
+    /// Hands out the input in chunks of at most `chunk_size` bytes.
+    struct Chunked<'a> {
+        input: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = Ord::min(Ord::min(buf.len(), self.chunk_size), self.input.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input = &self.input[n..];
+            Ok(n)
+        }
+    }
+
+    fn decode(input: &[u8], chunk_size: usize, mode: DecompressMode) -> io::Result<Vec<u8>> {
+        let reader = Chunked { input, chunk_size };
+        let mut output = Vec::new();
+        BzDecoder::with_mode(reader, mode).read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn single_stream() {
+        for mode in [DecompressMode::Fast, DecompressMode::Small] {
+            assert_eq!(decode(SAMPLE1_BZ2, usize::MAX, mode).unwrap(), SAMPLE1_REF);
+            assert_eq!(decode(SAMPLE1_BZ2, 13, mode).unwrap(), SAMPLE1_REF);
+        }
+    }
+
+    #[test]
+    fn concatenated_streams() {
+        let input = [SAMPLE1_BZ2, SAMPLE2_BZ2, SAMPLE1_BZ2].concat();
+        let expected = [SAMPLE1_REF, SAMPLE2_REF, SAMPLE1_REF].concat();
+
+        assert_eq!(
+            decode(&input, usize::MAX, DecompressMode::Fast).unwrap(),
+            expected
+        );
+        assert_eq!(decode(&input, 7, DecompressMode::Fast).unwrap(), expected);
+    }
+
+    #[test]
+    fn trailing_garbage_is_ignored() {
+        let input = [SAMPLE1_BZ2, b"trailing garbage"].concat();
+        assert_eq!(
+            decode(&input, usize::MAX, DecompressMode::Fast).unwrap(),
+            SAMPLE1_REF
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = decode(b"not bzip2", usize::MAX, DecompressMode::Fast).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Error::from(error), Error::DataMagic);
+
+        let error = decode(&[], usize::MAX, DecompressMode::Fast).unwrap_err();
+        assert_eq!(Error::from(error), Error::UnexpectedEof);
+
+        let truncated = &SAMPLE1_BZ2[..SAMPLE1_BZ2.len() - 10];
+        let error = decode(truncated, 100, DecompressMode::Fast).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut corrupt = SAMPLE1_BZ2.to_vec();
+        corrupt[100] ^= 0x10;
+        let error = decode(&corrupt, 100, DecompressMode::Fast).unwrap_err();
+        assert_eq!(Error::from(error), Error::Data);
+    }
+}
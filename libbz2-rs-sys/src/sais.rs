@@ -0,0 +1,398 @@
+#![forbid(unsafe_code)]
+
+//! Linear time block sorting with SA-IS, the induced sorting suffix array construction of Nong,
+//! Zhang and Chan.
+//!
+//! The block is sorted as a cyclic string. When the block is primitive, i.e. not a repetition of a
+//! shorter string, rotating it to its lexicographically least rotation gives a Lyndon word, and the
+//! order of the suffixes of a Lyndon word is the order of its rotations. All rotations are
+//! distinct, so the result is exactly the order that `mainSort` and `fallbackSort` produce.
+//!
+//! A block that is a repetition has rotations that compare equal, and the reference sorts order
+//! those in a way that depends on their internal passes. The position of rotation 0 among its
+//! equals ends up in `origPtr`, so such blocks are left to the reference sorts.
+
+use crate::bzlib::FTAB_LEN;
+
+/// Marks an unfilled slot in the suffix array.
+const EMPTY: u32 = u32::MAX;
+
+/// A string that ends in a unique smallest symbol, 0.
+trait Text {
+    fn len(&self) -> usize;
+    fn at(&self, i: usize) -> usize;
+}
+
+/// The block with a virtual sentinel appended, and all bytes moved up by one.
+struct Block<'a>(&'a [u8]);
+
+impl Text for Block<'_> {
+    fn len(&self) -> usize {
+        self.0.len() + 1
+    }
+
+    #[inline(always)]
+    fn at(&self, i: usize) -> usize {
+        match self.0.get(i) {
+            Some(b) => usize::from(*b) + 1,
+            None => 0,
+        }
+    }
+}
+
+/// The reduced string of a recursion level, which ends in the name of the sentinel.
+impl Text for [u32] {
+    fn len(&self) -> usize {
+        <[u32]>::len(self)
+    }
+
+    #[inline(always)]
+    fn at(&self, i: usize) -> usize {
+        self[i] as usize
+    }
+}
+
+/// One bit per position, set for S-type positions.
+struct Types<'a>(&'a mut [u32]);
+
+impl Types<'_> {
+    fn words(n: usize) -> usize {
+        n.div_ceil(32)
+    }
+
+    #[inline(always)]
+    fn is_s(&self, i: usize) -> bool {
+        self.0[i >> 5] & (1 << (i & 31)) != 0
+    }
+
+    #[inline(always)]
+    fn is_lms(&self, i: usize) -> bool {
+        i > 0 && self.is_s(i) && !self.is_s(i - 1)
+    }
+
+    fn classify<T: Text + ?Sized>(&mut self, s: &T) {
+        let n = s.len();
+        self.0[..Self::words(n)].fill(0);
+
+        // the sentinel is S-type, and the symbol before it is larger
+        let mut s_type = true;
+        self.0[(n - 1) >> 5] |= 1 << ((n - 1) & 31);
+        for i in (0..n - 1).rev() {
+            let (a, b) = (s.at(i), s.at(i + 1));
+            s_type = a < b || (a == b && s_type);
+            if s_type {
+                self.0[i >> 5] |= 1 << (i & 31);
+            }
+        }
+    }
+}
+
+/// Sets each bucket to its first slot, or to one past its last slot when `end` is true.
+fn bucket_bounds<T: Text + ?Sized>(s: &T, bkt: &mut [u32], end: bool) {
+    bkt.fill(0);
+    for i in 0..s.len() {
+        bkt[s.at(i)] += 1;
+    }
+
+    let mut sum = 0;
+    for b in bkt.iter_mut() {
+        let count = *b;
+        if end {
+            sum += count;
+            *b = sum;
+        } else {
+            *b = sum;
+            sum += count;
+        }
+    }
+}
+
+fn induce_l<T: Text + ?Sized>(s: &T, sa: &mut [u32], types: &Types, bkt: &mut [u32]) {
+    bucket_bounds(s, bkt, false);
+    for i in 0..sa.len() {
+        let j = sa[i];
+        if j != EMPTY && j > 0 && !types.is_s(j as usize - 1) {
+            let c = s.at(j as usize - 1);
+            sa[bkt[c] as usize] = j - 1;
+            bkt[c] += 1;
+        }
+    }
+}
+
+fn induce_s<T: Text + ?Sized>(s: &T, sa: &mut [u32], types: &Types, bkt: &mut [u32]) {
+    bucket_bounds(s, bkt, true);
+    for i in (0..sa.len()).rev() {
+        let j = sa[i];
+        if j != EMPTY && j > 0 && types.is_s(j as usize - 1) {
+            let c = s.at(j as usize - 1);
+            bkt[c] -= 1;
+            sa[bkt[c] as usize] = j - 1;
+        }
+    }
+}
+
+/// Whether the LMS substrings starting at `a` and `b` differ.
+fn lms_substrings_differ<T: Text + ?Sized>(s: &T, types: &Types, a: usize, b: usize) -> bool {
+    // the sentinel is unique, so this never runs past the end of the string
+    for d in 0.. {
+        if s.at(a + d) != s.at(b + d) || types.is_s(a + d) != types.is_s(b + d) {
+            return true;
+        }
+        if d > 0 && (types.is_lms(a + d) || types.is_lms(b + d)) {
+            return false;
+        }
+    }
+
+    unreachable!()
+}
+
+/// Builds the suffix array of `s`, with symbols in `0..k`, into `sa`.
+///
+/// `work` holds the S/L types, and the buckets when they don't fit in `ftab`. Both are reused by
+/// the recursion, so they are recomputed afterwards.
+fn sais<T: Text + ?Sized>(
+    s: &T,
+    sa: &mut [u32],
+    k: usize,
+    work: &mut [u32],
+    ftab: &mut [u32; FTAB_LEN],
+) {
+    let n = s.len();
+    debug_assert_eq!(sa.len(), n);
+
+    let (types, rest) = work.split_at_mut(Types::words(n));
+    let mut types = Types(types);
+    let bkt = if k <= FTAB_LEN {
+        &mut ftab[..k]
+    } else {
+        &mut rest[..k]
+    };
+
+    /*-- stage 1: sort the LMS substrings, and name them --*/
+    types.classify(s);
+
+    bucket_bounds(s, bkt, true);
+    sa.fill(EMPTY);
+    for i in 1..n {
+        if types.is_lms(i) {
+            let c = s.at(i);
+            bkt[c] -= 1;
+            sa[bkt[c] as usize] = i as u32;
+        }
+    }
+
+    induce_l(s, sa, &types, bkt);
+    induce_s(s, sa, &types, bkt);
+
+    let mut n1 = 0;
+    for i in 0..n {
+        let pos = sa[i];
+        if types.is_lms(pos as usize) {
+            sa[n1] = pos;
+            n1 += 1;
+        }
+    }
+
+    // LMS positions are at least 2 apart, so `n1 + pos / 2` stays within the array
+    sa[n1..].fill(EMPTY);
+    let mut names = 0;
+    let mut prev = None;
+    for i in 0..n1 {
+        let pos = sa[i] as usize;
+        let differs = match prev {
+            None => true,
+            Some(prev) => lms_substrings_differ(s, &types, pos, prev),
+        };
+        if differs {
+            names += 1;
+            prev = Some(pos);
+        }
+        sa[n1 + pos / 2] = names - 1;
+    }
+
+    let mut j = n;
+    for i in (n1..n).rev() {
+        if sa[i] != EMPTY {
+            j -= 1;
+            sa[j] = sa[i];
+        }
+    }
+
+    /*-- stage 2: sort the reduced string --*/
+    {
+        let (sa1, s1) = sa.split_at_mut(n - n1);
+        let sa1 = &mut sa1[..n1];
+        if (names as usize) < n1 {
+            sais(&*s1, sa1, names as usize, work, ftab);
+        } else {
+            for (i, name) in s1.iter().enumerate() {
+                sa1[*name as usize] = i as u32;
+            }
+        }
+    }
+
+    /*-- stage 3: induce the full order from the sorted LMS suffixes --*/
+    let (types, rest) = work.split_at_mut(Types::words(n));
+    let mut types = Types(types);
+    let bkt = if k <= FTAB_LEN {
+        &mut ftab[..k]
+    } else {
+        &mut rest[..k]
+    };
+
+    types.classify(s);
+
+    let mut j = n - n1;
+    for i in 1..n {
+        if types.is_lms(i) {
+            sa[j] = i as u32;
+            j += 1;
+        }
+    }
+    for i in 0..n1 {
+        sa[i] = sa[n - n1 + sa[i] as usize];
+    }
+    sa[n1..].fill(EMPTY);
+
+    bucket_bounds(s, bkt, true);
+    for i in (0..n1).rev() {
+        let pos = sa[i];
+        sa[i] = EMPTY;
+        let c = s.at(pos as usize);
+        bkt[c] -= 1;
+        sa[bkt[c] as usize] = pos;
+    }
+
+    induce_l(s, sa, &types, bkt);
+    induce_s(s, sa, &types, bkt);
+}
+
+/// Finds the start of the least rotation of `block`, and whether another rotation equals it.
+fn least_rotation(block: &[u8]) -> (usize, bool) {
+    let n = block.len();
+    let (mut i, mut j, mut k) = (0, 1, 0);
+
+    while i < n && j < n && k < n {
+        let a = block[(i + k) % n];
+        let b = block[(j + k) % n];
+        if a == b {
+            k += 1;
+            continue;
+        }
+
+        if a > b {
+            i += k + 1;
+        } else {
+            j += k + 1;
+        }
+        if i == j {
+            j += 1;
+        }
+        k = 0;
+    }
+
+    (Ord::min(i, j), n > 1 && k == n)
+}
+
+/// Sorts the rotations of `block` into `ptr`, using `work` and `ftab` as scratch space.
+///
+/// Returns `false`, without touching `ptr`, when the block is a repetition of a shorter string.
+/// The block is rotated while sorting, and restored afterwards.
+pub(crate) fn sort_rotations(
+    ptr: &mut [u32],
+    block: &mut [u8],
+    work: &mut [u32],
+    ftab: &mut [u32; FTAB_LEN],
+) -> bool {
+    let n = block.len();
+    debug_assert!(n > 0);
+
+    let (start, periodic) = least_rotation(block);
+    if periodic {
+        return false;
+    }
+
+    block.rotate_left(start);
+
+    // the first suffix is the empty one, at the sentinel
+    let sa = &mut ptr[..n + 1];
+    sais(&Block(block), sa, 257, work, ftab);
+    for i in 0..n {
+        let pos = sa[i + 1] as usize + start;
+        sa[i] = if pos >= n { pos - n } else { pos } as u32;
+    }
+
+    block.rotate_right(start);
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn sort(block: &[u8]) -> Option<Vec<u32>> {
+        let n = block.len();
+        let mut block = block.to_vec();
+        let mut ptr = vec![0; n + 1];
+        let mut work = vec![0; n + 64];
+        let mut ftab = Box::new([0; FTAB_LEN]);
+
+        let sorted = sort_rotations(&mut ptr, &mut block, &mut work, &mut ftab);
+        sorted.then(|| ptr[..n].to_vec())
+    }
+
+    fn naive(block: &[u8]) -> Vec<u32> {
+        let n = block.len();
+        let rotation = |i: usize| block[i..].iter().chain(&block[..i]);
+        let mut ptr: Vec<u32> = (0..n as u32).collect();
+        ptr.sort_by(|a, b| rotation(*a as usize).cmp(rotation(*b as usize)));
+        ptr
+    }
+
+    #[test]
+    fn matches_naive_sort() {
+        let mut seed = 0x2545_f491_u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+
+        for _ in 0..500 {
+            let len = 1 + next() as usize % 300;
+            let alphabet = 1 + next() % 4;
+            let block: Vec<u8> = (0..len).map(|_| (next() % alphabet) as u8).collect();
+
+            match sort(&block) {
+                Some(ptr) => assert_eq!(ptr, naive(&block), "{block:?}"),
+                None => {
+                    let n = block.len();
+                    assert!((1..n).any(|p| n % p == 0 && block[p..] == block[..n - p]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn repetitive() {
+        let mut block: Vec<u8> = b"abracadabra".repeat(200);
+        block.push(b'!');
+        assert_eq!(sort(&block).unwrap(), naive(&block));
+
+        let mut block = vec![0u8; 5000];
+        block[0] = 1;
+        assert_eq!(sort(&block).unwrap(), naive(&block));
+
+        assert_eq!(sort(&b"abab".repeat(100)), None);
+        assert_eq!(sort(&[42; 1000]), None);
+        assert_eq!(sort(b"x").unwrap(), [0]);
+    }
+}
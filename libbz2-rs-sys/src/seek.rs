@@ -0,0 +1,522 @@
+//! Random access to compressed data, using an index of its blocks.
+//!
+//! The blocks of a bzip2 stream are compressed independently, so decompression can start at any
+//! block, provided that its bit offset is known. A [`BlockIndex`] records the location of every
+//! block, together with the range of decompressed data that it contains. Building the index
+//! requires decompressing everything once, so the index can be stored next to the compressed data
+//! with [`BlockIndex::to_bytes`], and loaded again with [`BlockIndex::from_bytes`].
+
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::vec::Vec;
+
+use crate::block::{decode_block, max_block_bits, max_block_output};
+use crate::bzlib::BZ_MAX_UNUSED_U32;
+use crate::{DecompressMode, Decompressor, Error, Status};
+
+/// The magic bytes at the start of a serialized [`BlockIndex`], followed by a format version.
+const INDEX_MAGIC: [u8; 5] = *b"BZIX\x01";
+
+/// The serialized size of a [`BlockEntry`].
+const ENTRY_SIZE: usize = 4 * 8 + 1;
+
+/// The location of a single block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockEntry {
+    /// The bit offset of the block header in the compressed data.
+    pub bit_offset: u64,
+    /// The number of bits in the block, from the start of the block header up to the start of the
+    /// next block header or end-of-stream marker.
+    pub bit_len: u64,
+    /// The offset of the decompressed contents of the block in the decompressed data.
+    pub uncompressed_offset: u64,
+    /// The number of bytes that the block decompresses to.
+    pub uncompressed_len: u64,
+    /// The block size of the stream that contains the block, from 1 to 9.
+    pub block_size_100k: u8,
+}
+
+/// An index of the blocks in compressed data.
+///
+/// Like [`crate::read::BzDecoder`], the index covers a concatenation of compressed streams, and
+/// ignores data after the last stream that does not begin with the bzip2 magic bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockIndex {
+    blocks: Vec<BlockEntry>,
+}
+
+impl BlockIndex {
+    /// Builds an index by decompressing all data from `reader`.
+    ///
+    /// The bit offsets in the index are relative to the position of `reader` when this function
+    /// is called.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of the underlying reader, or with an [`Error`] when the compressed data
+    /// is invalid. Use [`Error::from`] to recover the [`Error`].
+    pub fn build<R: Read>(reader: R) -> io::Result<Self> {
+        let mut reader = BufReader::with_capacity(BZ_MAX_UNUSED_U32 as usize, reader);
+        let mut out = std::vec![0u8; 64 * 1024];
+
+        let mut blocks = Vec::new();
+        let mut compressed = 0u64;
+        let mut uncompressed = 0u64;
+        let mut streams = 0;
+
+        loop {
+            if streams > 0 && reader.fill_buf()?.is_empty() {
+                return Ok(Self { blocks });
+            }
+
+            let mut decompressor = Decompressor::new(DecompressMode::Fast)?;
+            decompressor.pause_at_block_boundaries();
+
+            let stream_start = compressed;
+            let mut current: Option<BlockEntry> = None;
+
+            loop {
+                let input = reader.fill_buf()?;
+                let input_len = input.len();
+
+                let progress = match decompressor.decompress(input, &mut out) {
+                    Ok(progress) => progress,
+                    Err(Error::DataMagic) if streams > 0 => return Ok(Self { blocks }),
+                    Err(error) => return Err(error.into()),
+                };
+                reader.consume(progress.consumed);
+                compressed += progress.consumed as u64;
+                uncompressed += progress.produced as u64;
+
+                let boundary = decompressor.block_boundary();
+                if let Some((bits, block_size_100k)) = boundary {
+                    let bit_offset = stream_start * 8 + bits;
+
+                    // a boundary ends the current block. The last boundary of a stream is the
+                    // end-of-stream marker, and the block it starts is discarded below.
+                    if let Some(mut block) = current.take() {
+                        block.bit_len = bit_offset - block.bit_offset;
+                        block.uncompressed_len = uncompressed - block.uncompressed_offset;
+                        blocks.push(block);
+                    }
+
+                    current = Some(BlockEntry {
+                        bit_offset,
+                        bit_len: 0,
+                        uncompressed_offset: uncompressed,
+                        uncompressed_len: 0,
+                        block_size_100k,
+                    });
+                }
+
+                match progress.status {
+                    Status::StreamEnd => break,
+                    _ if input_len == 0 && progress.produced == 0 && boundary.is_none() => {
+                        return Err(Error::UnexpectedEof.into());
+                    }
+                    _ => {}
+                }
+            }
+
+            streams += 1;
+        }
+    }
+
+    /// The blocks, in the order in which they occur in the compressed data.
+    pub fn blocks(&self) -> &[BlockEntry] {
+        &self.blocks
+    }
+
+    /// The total number of bytes that the indexed data decompresses to.
+    pub fn len(&self) -> u64 {
+        self.blocks.last().map_or(0, |block| {
+            block.uncompressed_offset + block.uncompressed_len
+        })
+    }
+
+    /// Returns whether the indexed data decompresses to nothing.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Finds the block whose decompressed contents include `offset`.
+    pub fn find(&self, offset: u64) -> Option<&BlockEntry> {
+        self.position(offset).map(|i| &self.blocks[i])
+    }
+
+    fn position(&self, offset: u64) -> Option<usize> {
+        let i = self
+            .blocks
+            .partition_point(|block| block.uncompressed_offset + block.uncompressed_len <= offset);
+        (i < self.blocks.len()).then_some(i)
+    }
+
+    /// Serializes the index.
+    ///
+    /// The format is the 4 bytes `BZIX` and a version byte, followed by the number of blocks and
+    /// then, for every block, its bit offset, bit length, uncompressed offset and uncompressed
+    /// length. All numbers are 64-bit little-endian. Every block ends with a byte for its block
+    /// size.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(INDEX_MAGIC.len() + 8 + self.blocks.len() * ENTRY_SIZE);
+        bytes.extend_from_slice(&INDEX_MAGIC);
+        bytes.extend_from_slice(&(self.blocks.len() as u64).to_le_bytes());
+
+        for block in &self.blocks {
+            bytes.extend_from_slice(&block.bit_offset.to_le_bytes());
+            bytes.extend_from_slice(&block.bit_len.to_le_bytes());
+            bytes.extend_from_slice(&block.uncompressed_offset.to_le_bytes());
+            bytes.extend_from_slice(&block.uncompressed_len.to_le_bytes());
+            bytes.push(block.block_size_100k);
+        }
+
+        bytes
+    }
+
+    /// Deserializes an index that was serialized with [`BlockIndex::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Data`] if `bytes` is not a valid index.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let rest = bytes.strip_prefix(&INDEX_MAGIC).ok_or(Error::Data)?;
+        let (count, rest) = rest.split_first_chunk::<8>().ok_or(Error::Data)?;
+        let count = u64::from_le_bytes(*count);
+
+        if count.checked_mul(ENTRY_SIZE as u64) != Some(rest.len() as u64) {
+            return Err(Error::Data);
+        }
+
+        let read_u64 = |chunk: &[u8]| u64::from_le_bytes(chunk.try_into().unwrap());
+
+        let mut blocks = Vec::with_capacity(count as usize);
+        let mut bit_end = 0u64;
+        let mut uncompressed_end = 0u64;
+        for entry in rest.chunks_exact(ENTRY_SIZE) {
+            let block = BlockEntry {
+                bit_offset: read_u64(&entry[0..8]),
+                bit_len: read_u64(&entry[8..16]),
+                uncompressed_offset: read_u64(&entry[16..24]),
+                uncompressed_len: read_u64(&entry[24..32]),
+                block_size_100k: entry[32],
+            };
+
+            // blocks are not empty, don't overlap, cover the decompressed data without gaps, and are
+            // no larger than their block size allows
+            let valid = block.bit_offset >= bit_end
+                && (1..=9).contains(&block.block_size_100k)
+                && (1..=max_block_bits(block.block_size_100k)).contains(&block.bit_len)
+                && block.uncompressed_offset == uncompressed_end
+                && (1..=max_block_output(block.block_size_100k)).contains(&block.uncompressed_len);
+            if !valid {
+                return Err(Error::Data);
+            }
+
+            bit_end = block
+                .bit_offset
+                .checked_add(block.bit_len)
+                .ok_or(Error::Data)?;
+            uncompressed_end = uncompressed_end
+                .checked_add(block.uncompressed_len)
+                .ok_or(Error::Data)?;
+            blocks.push(block);
+        }
+
+        Ok(Self { blocks })
+    }
+}
+
+/// Decompresses bzip2 data at arbitrary offsets, using a [`BlockIndex`].
+///
+/// Reading starts by decompressing the whole block that contains the current position. The most
+/// recently decompressed block is cached, so sequential reads decompress every block only once.
+pub struct SeekableDecoder<R> {
+    reader: R,
+    index: BlockIndex,
+    pos: u64,
+    /// The index and contents of the most recently decompressed block.
+    block: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> SeekableDecoder<R> {
+    /// Creates a decoder for the compressed data in `reader`, which must be described by `index`.
+    ///
+    /// The bit offsets of `index` are relative to the start of `reader`.
+    pub fn new(reader: R, index: BlockIndex) -> Self {
+        Self {
+            reader,
+            index,
+            pos: 0,
+            block: None,
+        }
+    }
+
+    /// Creates a decoder for the compressed data in `reader`, after building its index from the
+    /// start of `reader`.
+    pub fn build(mut reader: R) -> io::Result<Self> {
+        reader.rewind()?;
+        let index = BlockIndex::build(&mut reader)?;
+        Ok(Self::new(reader, index))
+    }
+
+    /// The index of the compressed data.
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Unwraps this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads and decompresses the block at position `i` of the index.
+    fn load(&mut self, i: usize) -> io::Result<Vec<u8>> {
+        let block = self.index.blocks[i];
+
+        let start = block.bit_offset % 8;
+        let mut bytes = std::vec![0u8; (start + block.bit_len).div_ceil(8) as usize];
+        self.reader.seek(SeekFrom::Start(block.bit_offset / 8))?;
+        self.reader.read_exact(&mut bytes)?;
+
+        let level = b'0' + block.block_size_100k;
+        let (data, _) = decode_block(&bytes, level, start, start + block.bit_len)?;
+        if data.len() as u64 != block.uncompressed_len {
+            return Err(Error::Data.into());
+        }
+
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let Some(i) = self.index.position(self.pos) else {
+            return Ok(0);
+        };
+
+        if !matches!(self.block, Some((cached, _)) if cached == i) {
+            self.block = None;
+            self.block = Some((i, self.load(i)?));
+        }
+
+        let Some((_, data)) = &self.block else {
+            unreachable!()
+        };
+        let start = (self.pos - self.index.blocks[i].uncompressed_offset) as usize;
+        let n = Ord::min(out.len(), data.len() - start);
+        out[..n].copy_from_slice(&data[start..][..n]);
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecoder<R> {
+    /// Moves to a position in the decompressed data.
+    ///
+    /// Seeking is cheap, the data is only decompressed when it is read. Seeking past the end is
+    /// allowed, and reads there return no data.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::End(offset) => (self.index.len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+
+        match base.checked_add_signed(offset) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::test_data::{SAMPLE1_BZ2, SAMPLE1_REF, SAMPLE2_BZ2, SAMPLE2_REF};
+
+    fn concatenated() -> (Vec<u8>, Vec<u8>) {
+        let compressed = [SAMPLE1_BZ2, SAMPLE2_BZ2, SAMPLE1_BZ2].concat();
+        let expected = [SAMPLE1_REF, SAMPLE2_REF, SAMPLE1_REF].concat();
+        (compressed, expected)
+    }
+
+    #[test]
+    fn index_blocks() {
+        let (compressed, expected) = concatenated();
+        let index = BlockIndex::build(compressed.as_slice()).unwrap();
+
+        // sample2 consists of two blocks
+        let blocks = index.blocks();
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(index.len(), expected.len() as u64);
+
+        // the first block starts right after the stream header
+        assert_eq!(blocks[0].bit_offset, 32);
+        assert_eq!(blocks[0].block_size_100k, 1);
+        assert_eq!(blocks[1].bit_offset, SAMPLE1_BZ2.len() as u64 * 8 + 32);
+        assert_eq!(blocks[1].block_size_100k, 2);
+
+        let mut end = 0;
+        for block in blocks {
+            assert_eq!(block.uncompressed_offset, end);
+            end += block.uncompressed_len;
+        }
+
+        assert_eq!(index.find(0), Some(&blocks[0]));
+        assert_eq!(index.find(SAMPLE1_REF.len() as u64), Some(&blocks[1]));
+        assert_eq!(index.find(index.len() - 1), Some(&blocks[3]));
+        assert_eq!(index.find(index.len()), None);
+    }
+
+    #[test]
+    fn index_ignores_trailing_garbage() {
+        let input = [SAMPLE1_BZ2, b"trailing garbage"].concat();
+        let index = BlockIndex::build(input.as_slice()).unwrap();
+        assert_eq!(index.len(), SAMPLE1_REF.len() as u64);
+    }
+
+    #[test]
+    fn index_errors() {
+        let error = BlockIndex::build(&b"not bzip2"[..]).unwrap_err();
+        assert_eq!(Error::from(error), Error::DataMagic);
+
+        let error = BlockIndex::build(&[][..]).unwrap_err();
+        assert_eq!(Error::from(error), Error::UnexpectedEof);
+
+        let truncated = &SAMPLE2_BZ2[..SAMPLE2_BZ2.len() / 2];
+        let error = BlockIndex::build(truncated).unwrap_err();
+        assert_eq!(Error::from(error), Error::UnexpectedEof);
+    }
+
+    #[test]
+    fn serialization() {
+        let (compressed, _) = concatenated();
+        let index = BlockIndex::build(compressed.as_slice()).unwrap();
+
+        let bytes = index.to_bytes();
+        assert_eq!(BlockIndex::from_bytes(&bytes), Ok(index));
+
+        let empty = BlockIndex::default();
+        assert_eq!(BlockIndex::from_bytes(&empty.to_bytes()), Ok(empty));
+
+        assert_eq!(
+            BlockIndex::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Error::Data)
+        );
+        assert_eq!(BlockIndex::from_bytes(b"BZIX"), Err(Error::Data));
+
+        // the blocks must cover the decompressed data without gaps
+        let mut gap = bytes.clone();
+        gap[INDEX_MAGIC.len() + 8 + ENTRY_SIZE + 16] ^= 1;
+        assert_eq!(BlockIndex::from_bytes(&gap), Err(Error::Data));
+
+        let mut block_size = bytes.clone();
+        block_size[INDEX_MAGIC.len() + 8 + ENTRY_SIZE - 1] = 0;
+        assert_eq!(BlockIndex::from_bytes(&block_size), Err(Error::Data));
+
+        // a block can't be longer than its block size allows, or decompress to more than that
+        let last = bytes.len() - ENTRY_SIZE;
+        let mut bit_len = bytes.clone();
+        bit_len[last + 8..last + 16].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(BlockIndex::from_bytes(&bit_len), Err(Error::Data));
+
+        let mut uncompressed_len = bytes;
+        uncompressed_len[last + 24..last + 32].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(BlockIndex::from_bytes(&uncompressed_len), Err(Error::Data));
+
+        // a block of long runs decompresses to much more than its block size
+        let mut compressor = crate::Compressor::new(1, 30).unwrap();
+        let mut compressed = std::vec![0u8; 1000];
+        let progress = compressor
+            .compress(&[7u8; 1_000_000], &mut compressed, crate::Action::Finish)
+            .unwrap();
+        compressed.truncate(progress.produced);
+        let index = BlockIndex::build(compressed.as_slice()).unwrap();
+        assert_eq!(index.blocks().len(), 1);
+        assert_eq!(index.blocks()[0].uncompressed_len, 1_000_000);
+        assert_eq!(BlockIndex::from_bytes(&index.to_bytes()), Ok(index));
+    }
+
+    #[test]
+    fn seek_and_read() {
+        let (compressed, expected) = concatenated();
+        let mut decoder = SeekableDecoder::build(Cursor::new(compressed)).unwrap();
+
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, expected);
+
+        // reads that start in one block and end in the next
+        let boundary = decoder.index().blocks()[2].uncompressed_offset;
+        for pos in [0, 1, 5000, boundary - 10, boundary, 300_000] {
+            let mut buf = [0u8; 100];
+            assert_eq!(decoder.seek(SeekFrom::Start(pos)).unwrap(), pos);
+            decoder.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, expected[pos as usize..][..100]);
+        }
+
+        let pos = decoder.seek(SeekFrom::End(-50)).unwrap();
+        assert_eq!(pos, expected.len() as u64 - 50);
+        output.clear();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, expected[expected.len() - 50..]);
+
+        decoder.seek(SeekFrom::Current(-20)).unwrap();
+        output.clear();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, expected[expected.len() - 20..]);
+
+        // past the end, or before the start
+        decoder.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(decoder.read(&mut [0; 10]).unwrap(), 0);
+        assert!(decoder.seek(SeekFrom::Current(-1_000_000)).is_err());
+    }
+
+    #[test]
+    fn stored_index() {
+        let (compressed, expected) = concatenated();
+        let bytes = BlockIndex::build(compressed.as_slice()).unwrap().to_bytes();
+
+        let index = BlockIndex::from_bytes(&bytes).unwrap();
+        let mut decoder = SeekableDecoder::new(Cursor::new(&compressed), index);
+
+        let pos = SAMPLE1_REF.len() as u64 + 150_000;
+        decoder.seek(SeekFrom::Start(pos)).unwrap();
+        let mut buf = [0u8; 1000];
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[pos as usize..][..1000]);
+
+        // a block that does not match the index is rejected
+        let mut corrupt = compressed.clone();
+        corrupt[SAMPLE1_BZ2.len() + 100] ^= 0x10;
+        let index = BlockIndex::from_bytes(&bytes).unwrap();
+        let mut decoder = SeekableDecoder::new(Cursor::new(corrupt), index);
+        decoder.seek(SeekFrom::End(-1000)).unwrap();
+        decoder.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[expected.len() - 1000..]);
+        decoder
+            .seek(SeekFrom::Start(SAMPLE1_REF.len() as u64))
+            .unwrap();
+        let error = decoder.read(&mut buf).unwrap_err();
+        assert_eq!(Error::from(error), Error::Data);
+    }
+}
@@ -0,0 +1,292 @@
+//! Adapters that compress data and push it into a writer.
+
+use crate::bzlib::BZ_MAX_UNUSED_U32;
+use crate::{Action, Compressor, Error, Status};
+
+/// A destination for compressed bytes.
+///
+/// With the `std` feature, this trait is implemented for every [`std::io::Write`]. On `no_std`
+/// targets it can be implemented for whatever writer abstraction is available.
+pub trait Sink {
+    /// The error returned when writing fails.
+    ///
+    /// Errors of the compressor itself are converted into this type too.
+    type Error: From<Error>;
+
+    /// Writes all of `buf`, or fails.
+    fn accept(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> Sink for W {
+    type Error = std::io::Error;
+
+    fn accept(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.write_all(buf)
+    }
+}
+
+/// Compresses data written to it, and writes the compressed data to an underlying [`Sink`].
+///
+/// The stream must be completed with [`BzEncoder::finish`] or [`BzEncoder::try_finish`]. When the
+/// encoder is dropped before that, it tries to finish the stream but ignores any errors.
+pub struct BzEncoder<W: Sink> {
+    writer: Option<W>,
+    compressor: Compressor,
+    buf: [u8; BZ_MAX_UNUSED_U32 as usize],
+    /// The number of bytes at the start of `buf` that still need to be written.
+    pending: usize,
+    finished: bool,
+}
+
+impl<W: Sink> BzEncoder<W> {
+    /// Creates an encoder that writes compressed data to `writer`.
+    ///
+    /// For the meaning of `block_size_100k` and `work_factor`, see [`Compressor::new`], which
+    /// also lists the possible errors.
+    pub fn new(writer: W, block_size_100k: u32, work_factor: u32) -> Result<Self, Error> {
+        Ok(Self {
+            writer: Some(writer),
+            compressor: Compressor::new(block_size_100k, work_factor)?,
+            buf: [0; BZ_MAX_UNUSED_U32 as usize],
+            pending: 0,
+            finished: false,
+        })
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.writer.as_ref().unwrap()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Writing to the underlying writer directly will corrupt the compressed output.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+
+    /// The total number of uncompressed bytes written to this encoder.
+    pub fn total_in(&self) -> u64 {
+        self.compressor.total_in()
+    }
+
+    /// The total number of compressed bytes produced by this encoder.
+    pub fn total_out(&self) -> u64 {
+        self.compressor.total_out()
+    }
+
+    /// Compresses all of `input`.
+    ///
+    /// Compressed data is written to the underlying writer as soon as the compressor produces it,
+    /// which usually happens once per block.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Sequence`] if the stream was already finished, and with the error of
+    /// the underlying writer if writing fails.
+    pub fn encode(&mut self, mut input: &[u8]) -> Result<(), W::Error> {
+        if self.finished {
+            return Err(Error::Sequence.into());
+        }
+
+        while !input.is_empty() {
+            self.dump()?;
+
+            let progress = self
+                .compressor
+                .compress(input, &mut self.buf, Action::Run)?;
+            self.pending = progress.produced;
+            input = &input[progress.consumed..];
+        }
+
+        self.dump()
+    }
+
+    /// Completes the compressed stream, and writes all remaining output to the underlying writer.
+    ///
+    /// After a successful call, no more data can be encoded. When writing fails this function can
+    /// be called again to retry.
+    pub fn try_finish(&mut self) -> Result<(), W::Error> {
+        loop {
+            self.dump()?;
+
+            if self.finished {
+                return Ok(());
+            }
+
+            let progress = self
+                .compressor
+                .compress(&[], &mut self.buf, Action::Finish)?;
+            self.pending = progress.produced;
+            self.finished = progress.status == Status::StreamEnd;
+        }
+    }
+
+    /// Completes the compressed stream, and returns the underlying writer together with the total
+    /// number of uncompressed and compressed bytes.
+    pub fn finish(mut self) -> Result<(W, u64, u64), W::Error> {
+        self.try_finish()?;
+
+        let writer = self.writer.take().unwrap();
+        Ok((writer, self.total_in(), self.total_out()))
+    }
+
+    /// Writes the pending output to the underlying writer.
+    fn dump(&mut self) -> Result<(), W::Error> {
+        if self.pending > 0 {
+            self.writer
+                .as_mut()
+                .unwrap()
+                .accept(&self.buf[..self.pending])?;
+            self.pending = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Sink> Drop for BzEncoder<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> std::io::Write for BzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.encode(buf)?;
+        Ok(buf.len())
+    }
+
+    /// Writes the output that was produced so far, and flushes the underlying writer.
+    ///
+    /// This does not end the current block, so data that is still being collected into a block is
+    /// not written.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.dump()?;
+        self.get_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+
+    use alloc::vec::Vec;
+
+    use crate::{DecompressMode, Decompressor};
+
+    /// A writer that is not a [`std::io::Write`].
+    #[derive(Default)]
+    struct Collect {
+        bytes: Vec<u8>,
+        fail: bool,
+    }
+
+    impl Sink for Collect {
+        type Error = Error;
+
+        fn accept(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            if self.fail {
+                return Err(Error::Io);
+            }
+            self.bytes.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn sample_input() -> Vec<u8> {
+        (0..300_000u64).map(|i| (i * i % 251) as u8).collect()
+    }
+
+    fn decompress(mut input: &[u8], expected_len: usize) -> Vec<u8> {
+        let mut decompressor = Decompressor::new(DecompressMode::Fast).unwrap();
+        let mut output = alloc::vec![0u8; expected_len + 1];
+        let mut produced = 0;
+        loop {
+            let progress = decompressor
+                .decompress(input, &mut output[produced..])
+                .unwrap();
+            input = &input[progress.consumed..];
+            produced += progress.produced;
+            if progress.status == Status::StreamEnd {
+                break;
+            }
+        }
+        assert!(input.is_empty());
+        output.truncate(produced);
+        output
+    }
+
+    #[test]
+    fn custom_sink() {
+        let input = sample_input();
+
+        let mut encoder = BzEncoder::new(Collect::default(), 1, 30).unwrap();
+        for chunk in input.chunks(7000) {
+            encoder.encode(chunk).unwrap();
+        }
+        let (collect, total_in, total_out) = encoder.finish().unwrap();
+
+        assert_eq!(total_in, input.len() as u64);
+        assert_eq!(total_out, collect.bytes.len() as u64);
+        assert_eq!(decompress(&collect.bytes, input.len()), input);
+    }
+
+    #[test]
+    fn sink_errors() {
+        let mut encoder = BzEncoder::new(Collect::default(), 1, 30).unwrap();
+        encoder.encode(b"abc").unwrap();
+
+        encoder.get_mut().fail = true;
+        assert_eq!(encoder.try_finish(), Err(Error::Io));
+
+        // the pending output is kept, so finishing can be retried
+        encoder.get_mut().fail = false;
+        let (collect, _, _) = encoder.finish().unwrap();
+        assert_eq!(decompress(&collect.bytes, 3), b"abc");
+    }
+
+    #[test]
+    fn encode_after_finish() {
+        let mut encoder = BzEncoder::new(Collect::default(), 1, 30).unwrap();
+        encoder.try_finish().unwrap();
+        assert_eq!(encoder.encode(b"abc"), Err(Error::Sequence));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn io_write() {
+        use std::io::{Read, Write};
+
+        let input = sample_input();
+
+        let mut encoder = BzEncoder::new(Vec::new(), 9, 0).unwrap();
+        encoder.write_all(&input).unwrap();
+        encoder.flush().unwrap();
+        let (compressed, _, _) = encoder.finish().unwrap();
+
+        let mut output = Vec::new();
+        crate::read::BzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn finish_on_drop() {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = BzEncoder::new(&mut compressed, 9, 0).unwrap();
+            encoder.encode(b"hello world").unwrap();
+        }
+
+        assert_eq!(decompress(&compressed, 11), b"hello world");
+    }
+}
@@ -1,4 +1,6 @@
-use test_libbz2_rs_sys::{compress_c_with_capacity, compress_rs_with_capacity};
+use test_libbz2_rs_sys::{
+    compress_c_with_capacity, compress_rs_with_block_sorter, compress_rs_with_capacity,
+};
 
 fn main() {
     let mut it = std::env::args();
@@ -42,6 +44,24 @@ fn main() {
 
             drop(dest_vec)
         }
-        other => panic!("invalid option '{other}', expected one of 'c' or 'rs'"),
+        "rs-sais" => {
+            let level: i32 = it.next().unwrap().parse().unwrap();
+
+            let path = it.next().unwrap();
+            let input = std::fs::read(&path).unwrap();
+
+            let source = input.as_ptr();
+            let source_len = input.len() as _;
+
+            let (err, dest_vec) =
+                unsafe { compress_rs_with_block_sorter(1 << 18, source, source_len, level, 1) };
+
+            if err != 0 {
+                panic!("error {err}");
+            }
+
+            drop(dest_vec)
+        }
+        other => panic!("invalid option '{other}', expected one of 'c', 'rs' or 'rs-sais'"),
     }
 }
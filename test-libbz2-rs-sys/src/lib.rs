@@ -234,6 +234,34 @@ fn miri_compress_sample3() {
     assert_eq_compress!("../../tests/input/quick/sample3.bz2");
 }
 
+#[test]
+fn parallel_compress_decompresses_with_c() {
+    let input = [
+        include_bytes!("../../tests/input/quick/sample1.ref").as_slice(),
+        include_bytes!("../../tests/input/quick/sample2.ref").as_slice(),
+        include_bytes!("../../tests/input/quick/sample3.ref").as_slice(),
+    ]
+    .concat();
+
+    let threads = std::num::NonZeroUsize::new(4).unwrap();
+    let mut compressor = libbz2_rs_sys::ParallelCompressor::new(1, 30, threads).unwrap();
+    let mut compressed = Vec::new();
+    for chunk in input.chunks(50_000) {
+        compressor.compress(chunk, &mut compressed).unwrap();
+    }
+    compressor.finish(&mut compressed).unwrap();
+
+    let (err, output) = unsafe {
+        decompress_c_with_capacity(
+            1 << 24,
+            compressed.as_ptr(),
+            compressed.len() as libc::c_uint,
+        )
+    };
+    assert_eq!(err, bzip2_sys::BZ_OK);
+    assert_eq!(output, input);
+}
+
 unsafe fn decompress_c(source: *const u8, source_len: libc::c_uint) -> (i32, Vec<u8>) {
     decompress_c_with_capacity(1024, source, source_len)
 }
@@ -495,6 +523,17 @@ pub unsafe fn compress_rs_with_capacity(
     source: *const u8,
     source_len: libc::c_uint,
     blockSize100k: i32,
+) -> (i32, Vec<u8>) {
+    compress_rs_with_block_sorter(capacity, source, source_len, blockSize100k, 0)
+}
+
+/// Like [`compress_rs_with_capacity`], but selects the block sorter first.
+pub unsafe fn compress_rs_with_block_sorter(
+    capacity: usize,
+    source: *const u8,
+    source_len: libc::c_uint,
+    blockSize100k: i32,
+    sorter: libc::c_int,
 ) -> (i32, Vec<u8>) {
     use libbz2_rs_sys::*;
 
@@ -523,6 +562,11 @@ pub unsafe fn compress_rs_with_capacity(
         if ret != 0 as libc::c_int {
             return (ret, vec![]);
         }
+        let ret = BZ2_bzCompressSetBlockSorter(&mut strm, sorter);
+        if ret != 0 as libc::c_int {
+            BZ2_bzCompressEnd(&mut strm);
+            return (ret, vec![]);
+        }
         strm.avail_in = source_len;
         strm.avail_out = dest.len() as _;
         strm.next_in = source as *mut libc::c_char;
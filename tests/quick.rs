@@ -1210,6 +1210,126 @@ mod test_command {
     }
 }
 
+mod list_command {
+    use super::*;
+
+    #[test]
+    fn stdout_and_list() {
+        let mut cmd = command();
+
+        expect_failure!(
+            cmd.args(["-c", "-l"]),
+            "bzip2: -c and -l cannot be used together.\n"
+        );
+    }
+
+    #[test]
+    fn files() {
+        let mut cmd = command();
+
+        let output = cmd
+            .arg("-l")
+            .arg("tests/input/quick/sample1.bz2")
+            .arg("tests/input/quick/sample2.bz2")
+            .output()
+            .unwrap();
+
+        expect_output_success!(output, "");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"),
+            concat!(
+                "Streams  Blocks     Compressed   Uncompressed    Ratio Block size  Filename\n",
+                "      1       1          32348          98696    3.051       100k  tests/input/quick/sample1.bz2\n",
+                "      1       2          73732         212340    2.880       200k  tests/input/quick/sample2.bz2\n",
+                "      2       3         106080         311036    2.932             (2 files)\n",
+            )
+        );
+    }
+
+    #[test]
+    fn robot() {
+        let mut cmd = command();
+
+        let output = cmd
+            .arg("--list")
+            .arg("--robot")
+            .arg("tests/input/quick/sample2.bz2")
+            .arg("tests/input/quick/sample3.bz2")
+            .output()
+            .unwrap();
+
+        expect_output_success!(output, "");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"),
+            concat!(
+                "file\t1\t2\t73732\t212340\t2.880\t2\ttests/input/quick/sample2.bz2\n",
+                "file\t1\t1\t235\t120244\t511.677\t3\ttests/input/quick/sample3.bz2\n",
+                "totals\t2\t3\t73967\t332584\t4.496\t2\n",
+            )
+        );
+    }
+
+    #[test]
+    #[cfg_attr(windows, ignore = "windows may be mangling stdin somehow")]
+    fn concatenated_stdin() {
+        use std::io::Write;
+
+        let compressed = [
+            &include_bytes!("input/quick/sample1.bz2")[..],
+            &include_bytes!("input/quick/sample3.bz2")[..],
+        ]
+        .concat();
+
+        let mut cmd = command();
+
+        let mut child = cmd
+            .arg("-l")
+            .arg("--robot")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to start child process");
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(&compressed)
+                .expect("Failed to write to stdin");
+        }
+
+        let output = child.wait_with_output().expect("Failed to read stdout");
+
+        expect_output_success!(output, "");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"),
+            "file\t2\t2\t32583\t218940\t6.719\t1,3\t(stdin)\n",
+        );
+    }
+
+    #[test]
+    fn input_file_is_not_bzip2_data() {
+        let mut cmd = command();
+
+        let output = cmd
+            .arg("-l")
+            .arg("--robot")
+            .arg("tests/input/quick/sample1.ref")
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(2));
+        assert!(output.stdout.is_empty());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr)
+                .replace(bzip2_binary(), "bzip2")
+                .replace("bzip2.exe", "bzip2")
+                .replace("\\", "/")
+                .replace("\r\n", "\n"),
+            "bzip2: tests/input/quick/sample1.ref: bad magic number (file not created by bzip2)\n",
+        );
+    }
+}
+
 mod compress_command {
     use super::*;
 
@@ -1369,6 +1489,113 @@ mod compress_command {
         }
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn recursive() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path().join("dir");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+
+        std::fs::copy("tests/input/quick/sample1.ref", dir.join("a")).unwrap();
+        std::fs::copy("tests/input/quick/sample2.ref", dir.join("sub/b")).unwrap();
+        std::fs::copy("tests/input/quick/sample1.bz2", dir.join("c.bz2")).unwrap();
+        std::os::unix::fs::symlink(dir.join("a"), dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("sub"), dir.join("sub_link")).unwrap();
+
+        let mut cmd = command();
+        expect_failure!(
+            cmd.arg("-r").arg(&dir),
+            format!(
+                concat!(
+                    "bzip2: Input file {dir}/link is not a normal file.\n",
+                    "bzip2: Input file {dir}/sub_link is not a normal file.\n",
+                    "bzip2: Input file {dir}/c.bz2 already has bz2 suffix.\n",
+                    "bzip2: skipped 3 files:\n",
+                    "       1  already compressed\n",
+                    "       2  is a symbolic link\n",
+                ),
+                dir = dir.display(),
+            ),
+        );
+
+        assert!(!dir.join("a").exists());
+        assert!(!dir.join("sub/b").exists());
+        assert!(dir.join("a.bz2").exists());
+        assert!(dir.join("sub/b.bz2").exists());
+
+        // the files without a suffix are left alone when decompressing
+        std::fs::remove_file(dir.join("c.bz2")).unwrap();
+        std::fs::write(dir.join("plain"), b"plain").unwrap();
+
+        let mut cmd = command();
+        expect_failure!(
+            cmd.arg("-d").arg("--recursive").arg(&dir),
+            format!(
+                concat!(
+                    "bzip2: Input file {dir}/link is not a normal file.\n",
+                    "bzip2: Input file {dir}/plain has no bzip2 suffix -- ignored.\n",
+                    "bzip2: Input file {dir}/sub_link is not a normal file.\n",
+                    "bzip2: skipped 3 files:\n",
+                    "       1  no bzip2 suffix\n",
+                    "       2  is a symbolic link\n",
+                ),
+                dir = dir.display(),
+            ),
+        );
+
+        assert_eq!(
+            std::fs::read(dir.join("a")).unwrap(),
+            std::fs::read("tests/input/quick/sample1.ref").unwrap()
+        );
+        assert_eq!(
+            std::fs::read(dir.join("sub/b")).unwrap(),
+            std::fs::read("tests/input/quick/sample2.ref").unwrap()
+        );
+    }
+
+    #[test]
+    fn threads_identical_output() {
+        for sample in ["sample1", "sample2", "sample3"] {
+            let input = format!("tests/input/quick/{sample}.ref");
+            let compressed = format!("tests/input/quick/{sample}.bz2");
+
+            // the reference files were compressed with -1, -2 and -3 respectively
+            let block_size = format!("-{}", &sample[sample.len() - 1..]);
+
+            for threads in ["-T1", "-T3", "--threads=0"] {
+                let mut cmd = command();
+                let output = cmd
+                    .args([block_size.as_str(), threads, "--stdout", input.as_str()])
+                    .output()
+                    .unwrap();
+
+                expect_output_success!(output, "");
+                assert_eq!(output.stdout, std::fs::read(&compressed).unwrap());
+
+                let mut cmd = command();
+                let output = cmd
+                    .args(["-d", threads, "--stdout", compressed.as_str()])
+                    .output()
+                    .unwrap();
+
+                expect_output_success!(output, "");
+                assert_eq!(output.stdout, std::fs::read(&input).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn threads_bad_value() {
+        for flag in ["-T", "-Tx", "--threads=", "--threads=-1"] {
+            let mut cmd = command();
+            let output = cmd.arg(flag).output().unwrap();
+
+            assert_eq!(output.status.code(), Some(1));
+            let stderr = String::from_utf8_lossy(&output.stderr).replace("bzip2.exe", "bzip2");
+            assert!(stderr.starts_with(&format!("bzip2: Bad flag `{flag}'")));
+        }
+    }
+
     #[test]
     fn compression_stderr_output() {
         let sample = Path::new("tests/input/quick/sample3.ref");
@@ -19,8 +19,8 @@ pub fn runner() -> Option<String> {
     })
 }
 
-fn run_bzip2recover(path: Option<&Path>) -> std::process::Output {
-    let mut cmd = match crate::runner() {
+fn command() -> Command {
+    match crate::runner() {
         Some(runner) if !runner.is_empty() => {
             let mut runner_args = runner.split(' ');
             let mut cmd = Command::new(runner_args.next().unwrap());
@@ -30,7 +30,11 @@ fn run_bzip2recover(path: Option<&Path>) -> std::process::Output {
             cmd
         }
         _ => Command::new(bzip2recover_binary()),
-    };
+    }
+}
+
+fn run_bzip2recover(path: Option<&Path>) -> std::process::Output {
+    let mut cmd = command();
 
     if let Some(path) = path {
         cmd.arg(path.as_os_str()).stdout(Stdio::piped());
@@ -79,8 +83,9 @@ fn basic_valid_file() {
             "   block 1 runs from 80 to 544887\n",
             "   block 2 runs from 544936 to 589771\n",
             "bzip2recover: splitting into blocks\n",
-            "   writing block 1 to `$TEMPDIR/rec00001sample1.bz2' ...\n",
-            "   writing block 2 to `$TEMPDIR/rec00002sample1.bz2' ...\n",
+            "   writing block 1 to `$TEMPDIR/rec00001sample1.bz2' ... ok\n",
+            "   writing block 2 to `$TEMPDIR/rec00002sample1.bz2' ... ok\n",
+            "bzip2recover: 2 of 2 blocks decode correctly\n",
             "bzip2recover: finished\n"
         )
     );
@@ -131,7 +136,8 @@ fn basic_invalid_file() {
             "   block 1 runs from 80 to 544887\n",
             "   block 2 runs from 544936 to 589056 (incomplete)\n",
             "bzip2recover: splitting into blocks\n",
-            "   writing block 1 to `$TEMPDIR/rec00001sample1.bz2' ...\n",
+            "   writing block 1 to `$TEMPDIR/rec00001sample1.bz2' ... ok\n",
+            "bzip2recover: 1 of 1 blocks decode correctly\n",
             "bzip2recover: finished\n",
         )
     );
@@ -159,7 +165,10 @@ fn no_input_file() {
         String::from_utf8_lossy(&output.stderr).replace(bzip2recover_binary(), "bzip2recover"),
         concat!(
             "bzip2recover 1.0.6: extracts blocks from damaged .bz2 files.\n",
-            "bzip2recover: usage is `bzip2recover damaged_file_name'.\n",
+            "bzip2recover: usage is `bzip2recover [-m] [-r] damaged_file_name'.\n",
+            "\t-m --merge: also write the good blocks to a single valid file\n",
+            "\t-r --repair: try to fix damaged blocks by flipping a single bit\n",
+            "\t--repair=2: also try flipping two bits close together\n",
             "\trestrictions on size of recovered file: None\n"
         )
     );
@@ -252,8 +261,9 @@ fn does_not_overwrite_recovered_files() {
             "   block 1 runs from 80 to 544887\n",
             "   block 2 runs from 544936 to 589771\n",
             "bzip2recover: splitting into blocks\n",
-            "   writing block 1 to `$TEMPDIR/rec00001sample1.bz2' ...\n",
-            "   writing block 2 to `$TEMPDIR/rec00002sample1.bz2' ...\n",
+            "   writing block 1 to `$TEMPDIR/rec00001sample1.bz2' ... ok\n",
+            "   writing block 2 to `$TEMPDIR/rec00002sample1.bz2' ... ok\n",
+            "bzip2recover: 2 of 2 blocks decode correctly\n",
             "bzip2recover: finished\n"
         )
     );
@@ -307,3 +317,181 @@ fn very_long_file_name() {
         )
     );
 }
+
+fn decompress(path: &Path) -> Vec<u8> {
+    use std::io::Read;
+
+    let mut decoder = libbz2_rs_sys::read::BzDecoder::new(File::open(path).unwrap());
+    let mut output = Vec::new();
+    decoder.read_to_end(&mut output).unwrap();
+    output
+}
+
+#[test]
+fn merge_valid_file() {
+    let tmp = tempfile::tempdir().unwrap();
+
+    let file_path = tmp.path().join("sample2.bz2");
+    std::fs::write(&file_path, include_bytes!("input/quick/sample2.bz2")).unwrap();
+
+    let mut cmd = command();
+    let output = cmd.arg("--merge").arg(&file_path).output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // only the block size in the header differs from the original
+    let merged = std::fs::read(tmp.path().join("recovered_sample2.bz2")).unwrap();
+    let original = include_bytes!("input/quick/sample2.bz2");
+    assert_eq!(&merged[..4], b"BZh9");
+    assert_eq!(&merged[4..], &original[4..]);
+}
+
+#[test]
+fn damaged_block() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tmp_path_str = tmp.path().display().to_string();
+
+    // damage the first of the two blocks
+    let mut input = include_bytes!("input/quick/sample2.bz2").to_vec();
+    input[1000] ^= 0x10;
+
+    let file_path = tmp.path().join("sample2.bz2");
+    std::fs::write(&file_path, &input).unwrap();
+
+    let mut cmd = command();
+    let output = cmd.arg("-m").arg(&file_path).output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr)
+            .replace(&tmp_path_str, "$TEMPDIR")
+            .replace(bzip2recover_binary(), "bzip2recover")
+            .replace("\\", "/"),
+        concat!(
+            "bzip2recover 1.0.6: extracts blocks from damaged .bz2 files.\n",
+            "bzip2recover: searching for block boundaries ...\n",
+            "   block 1 runs from 80 to 544887\n",
+            "   block 2 runs from 544936 to 589771\n",
+            "bzip2recover: splitting into blocks\n",
+            "bzip2recover: merging the good blocks into `$TEMPDIR/recovered_sample2.bz2'\n",
+            "   writing block 1 to `$TEMPDIR/rec00001sample2.bz2' ... damaged\n",
+            "   writing block 2 to `$TEMPDIR/rec00002sample2.bz2' ... ok\n",
+            "bzip2recover: 1 of 2 blocks decode correctly\n",
+            "bzip2recover: finished\n"
+        )
+    );
+
+    // the merged file is valid, and holds the data of the second block
+    let expected = include_bytes!("input/quick/sample2.ref");
+    let block2 = decompress(&tmp.path().join("rec00002sample2.bz2"));
+    assert!(expected.ends_with(&block2));
+    assert_eq!(
+        decompress(&tmp.path().join("recovered_sample2.bz2")),
+        block2
+    );
+}
+
+/// A small single-block file, so that trying every bit flip is quick.
+fn small_stream() -> (Vec<u8>, Vec<u8>) {
+    let data: Vec<u8> = (0..400)
+        .flat_map(|i: u32| format!("line {}\n", i * 7 % 113).into_bytes())
+        .collect();
+
+    let mut encoder = libbz2_rs_sys::write::BzEncoder::new(Vec::new(), 1, 30).unwrap();
+    encoder.encode(&data).unwrap();
+    let (compressed, _, _) = encoder.finish().unwrap();
+
+    (data, compressed)
+}
+
+fn run_repair(flag: &str, input: &[u8]) -> (String, tempfile::TempDir) {
+    let tmp = tempfile::tempdir().unwrap();
+    let tmp_path_str = tmp.path().display().to_string();
+
+    let file_path = tmp.path().join("small.bz2");
+    std::fs::write(&file_path, input).unwrap();
+
+    let mut cmd = command();
+    let output = cmd.arg(flag).arg("-m").arg(&file_path).output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stderr = String::from_utf8_lossy(&output.stderr)
+        .replace(&tmp_path_str, "$TEMPDIR")
+        .replace(bzip2recover_binary(), "bzip2recover")
+        .replace("\\", "/");
+
+    (stderr, tmp)
+}
+
+#[test]
+fn repair_single_bit() {
+    let (data, mut input) = small_stream();
+    input[100] ^= 0x04;
+
+    let (stderr, tmp) = run_repair("--repair", &input);
+
+    assert!(
+        stderr.contains(concat!(
+            "   writing block 1 to `$TEMPDIR/rec00001small.bz2' ...",
+            " repaired by flipping bit 805 (byte 100, mask 0x04)\n",
+        )),
+        "{stderr}"
+    );
+    assert!(stderr.contains("bzip2recover: 1 of 1 blocks decode correctly\n"));
+    assert!(stderr.contains("bzip2recover: 1 of 1 damaged blocks repaired\n"));
+
+    assert_eq!(decompress(&tmp.path().join("rec00001small.bz2")), data);
+    assert_eq!(decompress(&tmp.path().join("recovered_small.bz2")), data);
+}
+
+#[test]
+fn repair_stored_crc() {
+    let (data, mut input) = small_stream();
+    // the block CRC follows the stream header and the block magic
+    input[12] ^= 0x40;
+
+    let (stderr, tmp) = run_repair("-r", &input);
+
+    assert!(
+        stderr.contains(" repaired by flipping bit 97 (byte 12, mask 0x40)\n"),
+        "{stderr}"
+    );
+
+    // the stream trailer of the merged file is fixed as well
+    assert_eq!(decompress(&tmp.path().join("recovered_small.bz2")), data);
+}
+
+#[test]
+fn repair_two_bits() {
+    let (data, mut input) = small_stream();
+    input[20] ^= 0x81;
+
+    let (stderr, _tmp) = run_repair("-r", &input);
+    assert!(stderr.contains(" damaged, no repair found\n"), "{stderr}");
+    assert!(stderr.contains("bzip2recover: 0 of 1 damaged blocks repaired\n"));
+
+    let (stderr, tmp) = run_repair("--repair=2", &input);
+
+    assert!(
+        stderr.contains(concat!(
+            " repaired by flipping bit 160 (byte 20, mask 0x80)",
+            " and bit 167 (byte 20, mask 0x01)\n",
+        )),
+        "{stderr}"
+    );
+    assert_eq!(decompress(&tmp.path().join("recovered_small.bz2")), data);
+}